@@ -0,0 +1,132 @@
+use std::{fs, io::Read, path::{Path, PathBuf}};
+
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
+use enchat_core::{chat::{self, ChatState, ReceiverBundle}, files::{self, FileInfo}, keybundle::{generate_identity_key, public_key_from_base58, to_base58, IdentityKey, ManagedKey, StoredKey}, message::{self, Message, Received}, profile::Profile, store::DataDir};
+use serde::Serialize;
+
+// Messages, bundles and file infos are read and printed as the same JSON the app passes around
+#[derive(Parser)]
+#[command(name = "enchat-cli", about = "Headless enchat client")]
+struct Cli {
+    /// Account the keys and sessions belong to
+    #[arg(long, env = "ENCHAT_USER")]
+    user: String,
+    /// Where profiles are kept, next to the binary in portable mode and in the local app data otherwise
+    #[arg(long, env = "ENCHAT_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Defaults to the account's profile database
+    #[arg(long, env = "ENCHAT_DATABASE")]
+    database: Option<PathBuf>,
+    /// Database key in hex, taken from the profile's keyring entry if not set
+    #[arg(long, env = "ENCHAT_DATABASE_KEY", hide_env_values = true)]
+    database_key: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates the identity key if there is none and prints it
+    Identity,
+    /// Prints a key bundle for a peer to start a session with
+    Bundle,
+    /// Starts a session from the peer's bundle file
+    Start { chat_id: String, bundle: PathBuf },
+    /// Encrypts a message and prints it
+    Send { chat_id: String, text: String },
+    /// Decrypts a message read from the file, or from stdin without it
+    Receive {
+        chat_id: String,
+        message: Option<PathBuf>,
+        /// Identity key of the sender, needed when the message starts a new session
+        #[arg(long)]
+        sender: Option<String>,
+    },
+    /// Encrypts a file attached to the message we sent, prints its info
+    EncryptFile { chat_id: String, message_id: u32, input: PathBuf, output: PathBuf },
+    /// Decrypts a file attached to a message
+    DecryptFile {
+        chat_id: String,
+        message_id: u32,
+        info: PathBuf,
+        input: PathBuf,
+        output: PathBuf,
+        /// The message was sent by us
+        #[arg(long)]
+        sent: bool,
+    },
+    /// Lists chats with a stored session
+    Chats,
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: Option<&Path>) -> anyhow::Result<T> {
+    let mut content = String::new();
+    match path {
+        Some(path) => { content = fs::read_to_string(path).with_context(|| format!("Can't read {}", path.display()))?; }
+        None => { std::io::stdin().read_to_string(&mut content)?; }
+    }
+    Ok(serde_json::from_str(&content)?)
+}
+
+fn print_json<T: Serialize>(value: &T) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    let cli = Cli::parse();
+    let data_dir = DataDir::resolve(cli.data_dir)?;
+    let profile = Profile::new(&data_dir, &cli.user);
+    let database = profile.open_at(cli.database, cli.database_key)?;
+    let conn = database.get_connection();
+    let user = profile.user();
+    match cli.command {
+        Command::Identity => {
+            let identity = match IdentityKey::fetch(None, conn, &user) {
+                Ok(identity) => identity,
+                Err(_) => generate_identity_key(conn, &user)?
+            };
+            println!("{}", to_base58(identity.get_public_key().as_bytes()));
+        }
+        Command::Bundle => print_json(&ReceiverBundle::publish(conn, &user).context("Create the identity first")?)?,
+        Command::Start { chat_id, bundle } => {
+            let bundle: ReceiverBundle = read_json(Some(&bundle))?;
+            chat::enter_session(&chat_id, None, None, Some(bundle), &user, conn)?;
+        }
+        Command::Send { chat_id, text } => {
+            let mut chat = ChatState::load(&user, conn, &chat_id).context("No active session in this chat, start one first")?;
+            let message = message::send(chat_id, text, &mut chat, &user, conn).ok_or_else(|| anyhow!("Encryption failed"))?;
+            print_json(&message)?;
+        }
+        Command::Receive { chat_id, message, sender } => {
+            let message: Message = read_json(message.as_deref())?;
+            let mut chat = ChatState::load(&user, conn, &chat_id).ok();
+            let mut received = message::receive(chat_id.clone(), message.clone(), &mut chat, &user, conn);
+            if matches!(received, Some(Received::NewSession)) {
+                let sender = sender.context("Message starts a new session, pass the identity key of the sender")?;
+                let sender = public_key_from_base58(&sender).ok_or_else(|| anyhow!("Invalid identity key"))?;
+                chat = chat::enter_session(&chat_id, Some(sender), Some(message.clone()), None, &user, conn)?;
+                received = message::receive(chat_id, message, &mut chat, &user, conn);
+            }
+            match received {
+                Some(received) => print_json(&received)?,
+                None => bail!("Message could not be decrypted")
+            }
+        }
+        Command::EncryptFile { chat_id, message_id, input, output } => {
+            print_json(&files::encrypt_attachment(&input, &output, message_id, &chat_id, &user, conn)?)?;
+        }
+        Command::DecryptFile { chat_id, message_id, info, input, output, sent } => {
+            let info: FileInfo = read_json(Some(&info))?;
+            files::decrypt_attachment(&info, !sent, message_id, &input, &output, &chat_id, &user, conn)?;
+        }
+        Command::Chats => {
+            for chat_id in ChatState::list_chats(&user, conn)? {
+                println!("{}", chat_id);
+            }
+        }
+    }
+    Ok(())
+}
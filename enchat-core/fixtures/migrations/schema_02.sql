@@ -0,0 +1,14 @@
+-- Schema after the first 2 migrations, with two rows in every table
+CREATE TABLE identity(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );
+CREATE TABLE signed(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );
+INSERT INTO identity(id, key) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO identity(id, key) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO signed(id, key) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO signed(id, key) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202');
+PRAGMA user_version = 2;
@@ -0,0 +1,22 @@
+-- Schema after the first 3 migrations, with two rows in every table
+CREATE TABLE identity(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );
+CREATE TABLE signed(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );
+CREATE TABLE onetime(
+            id INTEGER NOT NULL,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (id, user_id)
+        );
+INSERT INTO identity(id, key) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO identity(id, key) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO signed(id, key) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO signed(id, key) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO onetime(id, user_id, key) VALUES (1, 'alice', X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO onetime(id, user_id, key) VALUES (2, 'bob', X'0202020202020202020202020202020202020202020202020202020202020202');
+PRAGMA user_version = 3;
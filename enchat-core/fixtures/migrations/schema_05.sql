@@ -0,0 +1,31 @@
+-- Schema after the first 5 migrations, with two rows in every table
+CREATE TABLE identity(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        , user_id TEXT NOT NULL);
+CREATE TABLE signed(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );
+CREATE TABLE onetime(
+            id INTEGER NOT NULL,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (id, user_id)
+        );
+CREATE TABLE message_key(
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            chat_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            received BOOL DEFAULT FALSE NOT NULL
+        );
+CREATE INDEX identity_user ON identity(user_id);
+INSERT INTO identity(id, key, user_id) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101', 'alice');
+INSERT INTO identity(id, key, user_id) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202', 'bob');
+INSERT INTO signed(id, key) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO signed(id, key) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO onetime(id, user_id, key) VALUES (1, 'alice', X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO onetime(id, user_id, key) VALUES (2, 'bob', X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO message_key(id, chat_id, key, received) VALUES (1, 'chat_id-1', X'0101010101010101010101010101010101010101010101010101010101010101', 0);
+INSERT INTO message_key(id, chat_id, key, received) VALUES (2, 'chat_id-2', X'0202020202020202020202020202020202020202020202020202020202020202', 0);
+PRAGMA user_version = 5;
@@ -0,0 +1,33 @@
+-- Schema after the first 7 migrations, with two rows in every table
+CREATE TABLE identity(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        , user_id TEXT NOT NULL);
+CREATE TABLE signed(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        , user_id TEXT NOT NULL);
+CREATE TABLE onetime(
+            id INTEGER NOT NULL,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (id, user_id)
+        );
+CREATE TABLE message_key(
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            chat_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            received BOOL DEFAULT FALSE NOT NULL
+        , user_id TEXT NOT NULL);
+CREATE INDEX identity_user ON identity(user_id);
+CREATE INDEX signed_user ON signed(user_id);
+CREATE INDEX message_key_user ON message_key(user_id);
+INSERT INTO identity(id, key, user_id) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101', 'alice');
+INSERT INTO identity(id, key, user_id) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202', 'bob');
+INSERT INTO signed(id, key, user_id) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101', 'alice');
+INSERT INTO signed(id, key, user_id) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202', 'bob');
+INSERT INTO onetime(id, user_id, key) VALUES (1, 'alice', X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO onetime(id, user_id, key) VALUES (2, 'bob', X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO message_key(id, chat_id, key, received, user_id) VALUES (1, 'chat_id-1', X'0101010101010101010101010101010101010101010101010101010101010101', 0, 'alice');
+INSERT INTO message_key(id, chat_id, key, received, user_id) VALUES (2, 'chat_id-2', X'0202020202020202020202020202020202020202020202020202020202020202', 0, 'bob');
+PRAGMA user_version = 7;
@@ -0,0 +1,50 @@
+-- Schema after the first 10 migrations, with two rows in every table
+CREATE TABLE identity(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        , user_id TEXT NOT NULL);
+CREATE TABLE signed(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        , user_id TEXT NOT NULL);
+CREATE TABLE onetime(
+            id INTEGER NOT NULL,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (id, user_id)
+        );
+CREATE TABLE message_key(
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            chat_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            received BOOL DEFAULT FALSE NOT NULL
+        , user_id TEXT NOT NULL, local_id INTEGER NOT NULL);
+CREATE INDEX identity_user ON identity(user_id);
+CREATE INDEX signed_user ON signed(user_id);
+CREATE INDEX message_key_user ON message_key(user_id);
+CREATE INDEX message_key_local_id ON message_key(local_id);
+CREATE INDEX message_key_chat_index ON message_key(chat_id);
+CREATE TABLE rachet_state(
+            chat_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            diffie_public_bytes BLOB NOT NULL,
+            diffie_private_bytes BLOB NOT NULL,
+            root_input_bytes BLOB NOT NULL,
+            root_id INTEGER NOT NULL,
+            sender_input_bytes BLOB NOT NULL,
+            sender_id INTEGER NOT NULL,
+            receiver_input_bytes BLOB NOT NULL,
+            receiver_id INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, user_id)
+        );
+INSERT INTO identity(id, key, user_id) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101', 'alice');
+INSERT INTO identity(id, key, user_id) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202', 'bob');
+INSERT INTO signed(id, key, user_id) VALUES (1, X'0101010101010101010101010101010101010101010101010101010101010101', 'alice');
+INSERT INTO signed(id, key, user_id) VALUES (2, X'0202020202020202020202020202020202020202020202020202020202020202', 'bob');
+INSERT INTO onetime(id, user_id, key) VALUES (1, 'alice', X'0101010101010101010101010101010101010101010101010101010101010101');
+INSERT INTO onetime(id, user_id, key) VALUES (2, 'bob', X'0202020202020202020202020202020202020202020202020202020202020202');
+INSERT INTO message_key(id, chat_id, key, received, user_id, local_id) VALUES (1, 'chat_id-1', X'0101010101010101010101010101010101010101010101010101010101010101', 0, 'alice', 1);
+INSERT INTO message_key(id, chat_id, key, received, user_id, local_id) VALUES (2, 'chat_id-2', X'0202020202020202020202020202020202020202020202020202020202020202', 0, 'bob', 2);
+INSERT INTO rachet_state(chat_id, user_id, diffie_public_bytes, diffie_private_bytes, root_input_bytes, root_id, sender_input_bytes, sender_id, receiver_input_bytes, receiver_id) VALUES ('chat_id-1', 'alice', X'0101010101010101010101010101010101010101010101010101010101010101', X'0101010101010101010101010101010101010101010101010101010101010101', X'0101010101010101010101010101010101010101010101010101010101010101', 1, X'0101010101010101010101010101010101010101010101010101010101010101', 1, X'0101010101010101010101010101010101010101010101010101010101010101', 1);
+INSERT INTO rachet_state(chat_id, user_id, diffie_public_bytes, diffie_private_bytes, root_input_bytes, root_id, sender_input_bytes, sender_id, receiver_input_bytes, receiver_id) VALUES ('chat_id-2', 'bob', X'0202020202020202020202020202020202020202020202020202020202020202', X'0202020202020202020202020202020202020202020202020202020202020202', X'0202020202020202020202020202020202020202020202020202020202020202', 2, X'0202020202020202020202020202020202020202020202020202020202020202', 2, X'0202020202020202020202020202020202020202020202020202020202020202', 2);
+PRAGMA user_version = 10;
@@ -0,0 +1,504 @@
+use rand::RngCore;
+use rusqlite::{Connection, named_params, params, Row};
+use serde::{Deserialize, Serialize};
+use x25519_dalek::{PublicKey, SharedSecret};
+use zeroize::Zeroize;
+
+#[cfg(test)] mod tests;
+
+use crate::{encryption::{generate_ephemeral, get_rng, Key, RootKey, Otherkey, ProtocolVersion}, message::{self, InitialData, Message, MessageHeader, end_session}, helpers::unix_millis, keybundle::{IdentityKey, StoredKey, ManagedKey, SignedKey, Onetime, KemPrekey, KemPrekeyBundle, Signature, verify_signature, encapsulate}, errors::{CommandError, CommandResult}, passphrase::{self, PassphraseKey}, transport::KeyBundle, user::User};
+
+fn row_to_chain(row: &Row, chain_name: &str) -> rusqlite::Result<Chain> {
+    let input_key: Vec<u8> = row.get(format!("{}_input_bytes", &chain_name).as_ref())?;
+    let chain = Chain {
+        input_key: input_key.try_into().unwrap(),
+        id: row.get(format!("{}_id", &chain_name).as_ref())?
+    };
+    Ok(chain)
+} 
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Default)]
+pub struct Chain {
+    id: u32,
+    input_key: RootKey,
+}
+
+
+#[derive(Clone)]
+pub struct DHRachet {
+    our_keypair: Key,
+    their_public: PublicKey
+}
+
+impl DHRachet {
+    pub fn new(their_public: PublicKey) -> Self {
+        Self {
+            our_keypair: generate_ephemeral(),
+            their_public
+        }
+    }
+    pub fn calculate_dh(&self) -> SharedSecret {
+        self.our_keypair.diffie_hellman(&self.their_public)
+    }
+    pub fn step(&mut self, new_public_key: PublicKey) -> SharedSecret {
+        self.their_public = new_public_key;
+        let dh = self.calculate_dh();
+        self.our_keypair = generate_ephemeral();
+        dh
+    }
+}
+
+
+impl Chain {
+    fn step(&mut self, dh_input: Option<&SharedSecret>, version: ProtocolVersion) -> Otherkey {
+        let output = if let Some(dh) = dh_input {
+            version.root(&self.input_key, dh.as_bytes())
+        } else {
+            version.chain(&self.input_key)
+        };
+        self.input_key = output.0;
+        self.id+=1;
+        output.1
+    }
+    fn set_key(&mut self, key: RootKey) {
+        self.input_key = key;
+    }
+}
+
+// Identity keys from the session setup, bound into associated data of every message as X3DH suggests
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SessionIdentities {
+    pub initiator: PublicKey,
+    pub responder: PublicKey,
+}
+
+// Older sessions of the chat, kept for late messages or until the peer uses one of them again
+const MAX_ARCHIVED_SESSIONS: u32 = 10;
+
+#[derive(Clone)]
+pub struct ChatState {
+    // Random, so sessions of one chat can be told apart in the database
+    pub(crate) session_id: i64,
+    // Keys used for X3DH, sent with every message until the peer replies so it can set up the session from any of them
+    pub receiver_used_keys: Option<InitialData>,
+    pub(crate) root_chain: Chain,
+    pub(crate) sender_chain: Chain,
+    pub(crate) receiver_chain: Chain,
+    rachet: DHRachet,
+    pub last_previous_sender_id: u32,
+    pub(crate) version: ProtocolVersion,
+    // None for sessions created before identities were stored
+    pub(crate) identities: Option<SessionIdentities>,
+    // Messages in a row that no session could decrypt
+    pub(crate) decryption_failures: u32,
+    // End of session was sent or received, or the session lost simultaneous initiation.
+    // Archived session isn't promoted anymore
+    pub(crate) ended: bool,
+}
+
+// Chain keys are plain arrays, cleared when the session is dropped on logout or after saving.
+// The ratchet's own secret clears itself
+impl Drop for ChatState {
+    fn drop(&mut self) {
+        self.root_chain.input_key.zeroize();
+        self.sender_chain.input_key.zeroize();
+        self.receiver_chain.input_key.zeroize();
+    }
+}
+
+impl ChatState {
+    pub fn dh_receiver(sender_ephemeral: &PublicKey, identity_key: &Key, sender_identity: &PublicKey, prekey: &Key, onetime_key: Option<Onetime> ) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(32 * 4);
+        vec.extend_from_slice(prekey.diffie_hellman(sender_identity).as_bytes());
+        vec.extend_from_slice(identity_key.diffie_hellman(sender_ephemeral).as_bytes());
+        vec.extend_from_slice(prekey.diffie_hellman(sender_ephemeral).as_bytes());
+        if let Some(onetime) = onetime_key {
+            vec.extend_from_slice(onetime.get_keypair().diffie_hellman(sender_ephemeral).as_bytes());
+        }
+        vec
+    }
+    pub fn dh_sender(ephemeral: &Key, identity_key: &Key, receiver_identity: &PublicKey, receiver_prekey: &PublicKey, receiver_onetime: Option<PublicKey>) -> Vec<u8> {
+        let mut vec = Vec::with_capacity(32 * 4);
+        vec.extend_from_slice(identity_key.diffie_hellman(receiver_prekey).as_bytes());
+        vec.extend_from_slice(ephemeral.diffie_hellman(receiver_identity).as_bytes());
+        vec.extend_from_slice(ephemeral.diffie_hellman(receiver_prekey).as_bytes());
+        if let Some(onetime) = receiver_onetime {
+            vec.extend_from_slice(ephemeral.diffie_hellman(&onetime).as_bytes());
+        };
+        vec
+    }
+    pub fn new(initial_rachet_key: &PublicKey, initial_dh: Vec<u8>, version: ProtocolVersion) -> Self {
+        let output = version.initial(initial_dh);
+        Self {
+            session_id: (get_rng().next_u64() >> 1) as i64,
+            root_chain: Chain { id: 0, input_key: output.0 },
+            rachet: DHRachet::new(*initial_rachet_key),
+            sender_chain: Default::default(),
+            receiver_chain: Default::default(),
+            receiver_used_keys: None,
+            last_previous_sender_id: 0,
+            version,
+            identities: None,
+            decryption_failures: 0,
+            ended: false
+        }
+    }
+    pub fn new_sender(initial_rachet_key: &PublicKey, initial_dh: Vec<u8>, version: ProtocolVersion) -> Self {
+        let mut new_self = Self::new(initial_rachet_key, initial_dh, version);
+        new_self.sender_chain.set_key(new_self.root_chain.step(Some(&new_self.rachet.calculate_dh()), version));
+        new_self
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn initial_sender(our_identity: IdentityKey, ephemeral: IdentityKey, receiver_identity: &PublicKey, receiver_prekey: &PublicKey, receiver_onetime: Option<PublicKey>, prekey_id: u32, onetime_key_id: Option<u32>, receiver_kem: Option<&KemPrekeyBundle>, passphrase: Option<&PassphraseKey>) -> CommandResult<Self> {
+        let mut vec = Self::dh_sender(ephemeral.get_keypair(), our_identity.get_keypair(), receiver_identity, receiver_prekey, receiver_onetime);
+        // Receivers without post-quantum prekey fall back to plain X3DH
+        let kem_ciphertext = match receiver_kem {
+            Some(kem) => {
+                let (ciphertext, secret) = encapsulate(&kem.key).ok_or(CommandError::InvalidKemPrekey)?;
+                vec.extend_from_slice(&secret);
+                Some(ciphertext)
+            }
+            None => None
+        };
+        let passphrase_confirmation = passphrase.map(|passphrase| {
+            vec.extend_from_slice(passphrase);
+            passphrase::confirmation(&vec)
+        });
+        let version = ProtocolVersion::CURRENT;
+        let rachet_key = if version.prekey_rachet() { receiver_prekey } else { receiver_identity };
+        let mut new_self = Self::new_sender(rachet_key, vec, version);
+        new_self.receiver_used_keys = Some(InitialData {
+            onetime_key_id,
+            ephemeral: ephemeral.get_public_key(),
+            prekey_id,
+            version: Some(version.id()),
+            kem_ciphertext,
+            kem_prekey_id: receiver_kem.map(|kem| kem.id),
+            passphrase_confirmation
+        });
+        new_self.identities = Some(SessionIdentities { initiator: our_identity.get_public_key(), responder: *receiver_identity });
+        Ok(new_self)
+    }
+    pub fn new_receiver(our_identity: Key, initial_rachet_key: &PublicKey, initial_dh: Vec<u8>, version: ProtocolVersion) -> Self {
+        let mut new_self = Self::new(initial_rachet_key, initial_dh, version);
+        new_self.rachet.our_keypair = our_identity;
+        new_self
+    }
+    #[allow(clippy::too_many_arguments)]
+    pub fn initial_receiver(our_identity: IdentityKey, our_prekey: SignedKey, initial_data: &InitialData, rachet: &PublicKey, conn: &Connection, sender_identity: PublicKey, user: &User, passphrase: Option<&PassphraseKey>) -> CommandResult<Self> {
+        // TODO: Handle, may be a common case?
+        let onetime_key = Onetime::fetch(initial_data.onetime_key_id, conn, user).ok();
+        let sender_ephemeral = initial_data.ephemeral;
+
+        // Senders from before the versioning don't send it
+        let version = initial_data.version.and_then(ProtocolVersion::from_id).unwrap_or(ProtocolVersion::V1);
+        let mut vec = Self::dh_receiver(&sender_ephemeral, our_identity.get_keypair(), &sender_identity, our_prekey.get_keypair(), onetime_key);
+        if let Some(ciphertext) = &initial_data.kem_ciphertext {
+            let kem_prekey = KemPrekey::fetch(initial_data.kem_prekey_id, conn, user)?;
+            vec.extend_from_slice(&kem_prekey.decapsulate(ciphertext).ok_or_else(|| anyhow::anyhow!("Invalid post-quantum ciphertext"))?);
+        }
+        if let Some(passphrase) = passphrase {
+            vec.extend_from_slice(passphrase);
+        }
+        passphrase::check(initial_data.passphrase_confirmation.as_ref(), passphrase.map(|_| passphrase::confirmation(&vec)))?;
+        // Sessions from before V3 started the rachet with the identity key
+        let rachet_keypair = if version.prekey_rachet() { our_prekey.get_keypair() } else { our_identity.get_keypair() };
+        let mut new_self = Self::new_receiver(rachet_keypair.clone(), rachet, vec, version);
+        new_self.identities = Some(SessionIdentities { initiator: sender_identity, responder: our_identity.get_public_key() });
+        Ok(new_self)
+    }
+    pub fn move_sender(&mut self) -> (PublicKey, Otherkey, u32) {
+        let id = self.sender_chain.id;
+        let key = self.sender_chain.step(None, self.version);
+        (PublicKey::from(&self.rachet.our_keypair), key, id)
+    }
+    pub fn move_receiver(&mut self, new_public_key: Option<PublicKey>) -> Otherkey {
+        if let Some(new_public_key) = new_public_key {
+            if new_public_key != self.rachet.their_public || self.root_chain.id == 0 {
+                let receiver_dh = self.rachet.step(new_public_key);
+                let receiver_key = self.root_chain.step(Some(&receiver_dh), self.version);
+                self.receiver_chain.set_key(receiver_key);
+                let sender_key = self.root_chain.step(Some(&self.rachet.calculate_dh()), self.version);
+                self.sender_chain.set_key(sender_key);
+                self.last_previous_sender_id = self.sender_chain.id;
+            }
+        }
+        self.receiver_chain.step(None, self.version)
+    }
+    // Session we started that didn't get any reply yet
+    fn is_unanswered_initiation(&self, our_identity: &PublicKey) -> bool {
+        self.receiver_chain.id == 0 && self.identities.map_or(false, |identities| identities.initiator == *our_identity)
+    }
+    // Identity key of the other party, if the session has them stored
+    fn peer(&self, our_identity: &PublicKey) -> Option<PublicKey> {
+        self.identities.map(|identities| if identities.initiator == *our_identity { identities.responder } else { identities.initiator })
+    }
+    // Both parties may start a session at once, each then receives the other's initial message.
+    // Both keep the session started by the lower identity key, so they settle on the same one
+    fn wins_over(&self, incoming: &ChatState, our_identity: &PublicKey) -> bool {
+        match (self.identities, incoming.identities) {
+            (Some(ours), Some(theirs)) => self.is_unanswered_initiation(our_identity) && ours.initiator.as_bytes() < theirs.initiator.as_bytes(),
+            _ => false
+        }
+    }
+    pub fn associated_data(&self, header: &MessageHeader) -> Vec<u8> {
+        let mut ad = Vec::with_capacity(64);
+        if let Some(identities) = &self.identities {
+            ad.extend_from_slice(identities.initiator.as_bytes());
+            ad.extend_from_slice(identities.responder.as_bytes());
+        }
+        ad.extend(bincode::serialize(header).unwrap());
+        ad
+    }
+    pub fn save(&self, user: &User, connection: &Connection, chat_id: &str) -> rusqlite::Result<usize> {
+        self.upsert(None, user, connection, chat_id)
+    }
+    // Archive time is only used for a new row, stored sessions keep theirs
+    fn upsert(&self, archived_at: Option<i64>, user: &User, connection: &Connection, chat_id: &str) -> rusqlite::Result<usize> {
+        let dh_public = self.rachet.their_public.as_bytes();
+        let dh_private = self.rachet.our_keypair.to_bytes();
+        let root_input = &self.root_chain.input_key;
+        let sender_input = &self.sender_chain.input_key;
+        let receiver_input = &self.receiver_chain.input_key;
+        let initiator_identity = self.identities.map(|identities| identities.initiator.to_bytes());
+        let responder_identity = self.identities.map(|identities| identities.responder.to_bytes());
+        // JSON, as bincode can't read back the fields skipped for older peers
+        let pending_initial = self.receiver_used_keys.as_ref().map(|initial| serde_json::to_string(initial).unwrap());
+        connection.execute("INSERT INTO
+            rachet_state(session_id, chat_id, user_id, diffie_public_bytes, diffie_private_bytes,
+            root_input_bytes, sender_input_bytes, receiver_input_bytes, root_id, sender_id, receiver_id, last_previous_sender_id, protocol_version, initiator_identity, responder_identity, decryption_failures, ended, pending_initial, archived_at)
+            VALUES (:session_id, :chat_id, :user_id, :dh_public, :dh_private, :root_input, :sender_input, :receiver_input, :root_id, :sender_id, :receiver_id, :last_previous_sender_id, :protocol_version, :initiator_identity, :responder_identity, :decryption_failures, :ended, :pending_initial, :archived_at)
+            ON CONFLICT(session_id) DO UPDATE SET
+                diffie_public_bytes = :dh_public,
+                diffie_private_bytes = :dh_private,
+                root_input_bytes = :root_input,
+                sender_input_bytes = :sender_input,
+                receiver_input_bytes = :receiver_input,
+                root_id = :root_id,
+                sender_id = :sender_id,
+                receiver_id = :receiver_id,
+                last_previous_sender_id = :last_previous_sender_id,
+                protocol_version = :protocol_version,
+                initiator_identity = :initiator_identity,
+                responder_identity = :responder_identity,
+                decryption_failures = :decryption_failures,
+                ended = :ended,
+                pending_initial = :pending_initial",
+        named_params! {
+            ":session_id": self.session_id,
+            ":dh_public": dh_public,
+            ":dh_private": dh_private,
+            ":chat_id": chat_id,
+            ":user_id": user.user_id,
+            ":root_input": root_input,
+            ":sender_input": sender_input,
+            ":receiver_input": receiver_input,
+            ":root_id": self.root_chain.id,
+            ":sender_id": self.sender_chain.id,
+            ":receiver_id": self.receiver_chain.id,
+            ":last_previous_sender_id": self.last_previous_sender_id,
+            ":protocol_version": self.version.id(),
+            ":initiator_identity": initiator_identity,
+            ":responder_identity": responder_identity,
+            ":decryption_failures": self.decryption_failures,
+            ":ended": self.ended,
+            ":pending_initial": pending_initial,
+            ":archived_at": archived_at
+        })
+    }
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let dh_public: Vec<u8> = row.get("diffie_public_bytes")?;
+        let dh_private: Vec<u8> = row.get("diffie_private_bytes")?;
+        let dh_public_bytes: [u8; 32] = dh_public.try_into().unwrap();
+        let dh_private_bytes: [u8; 32] = dh_private.try_into().unwrap();
+        let version_id: u8 = row.get("protocol_version")?;
+        let initiator: Option<[u8; 32]> = row.get("initiator_identity")?;
+        let responder: Option<[u8; 32]> = row.get("responder_identity")?;
+        let identities = initiator.zip(responder).map(|(initiator, responder)| SessionIdentities {
+            initiator: PublicKey::from(initiator),
+            responder: PublicKey::from(responder)
+        });
+        let pending_initial: Option<String> = row.get("pending_initial")?;
+        let receiver_used_keys = pending_initial.map(|initial| serde_json::from_str(&initial)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(err)))).transpose()?;
+        let rachet = DHRachet {
+            their_public: PublicKey::from(dh_public_bytes),
+            our_keypair: Key::from(dh_private_bytes)
+        };
+        Ok(Self {
+            session_id: row.get("session_id")?,
+            rachet,
+            receiver_chain: row_to_chain(row, "receiver")?,
+            receiver_used_keys,
+            sender_chain: row_to_chain(row, "sender")?,
+            root_chain: row_to_chain(row, "root")?,
+            last_previous_sender_id: row.get("last_previous_sender_id")?,
+            version: ProtocolVersion::from_id(version_id).ok_or(rusqlite::Error::IntegralValueOutOfRange(0, version_id.into()))?,
+            identities,
+            decryption_failures: row.get("decryption_failures")?,
+            ended: row.get("ended")?
+        })
+    }
+    // Loads the active session of the chat
+    pub fn load(user: &User, connection: &Connection, chat_id: &str) -> rusqlite::Result<Self> {
+        connection.query_row("SELECT * FROM rachet_state WHERE chat_id = ? AND user_id = ? AND archived_at IS NULL LIMIT 1", params![chat_id, user.user_id], Self::from_row)
+    }
+    // Chats with a stored session of the user, active or archived
+    pub fn list_chats(user: &User, connection: &Connection) -> rusqlite::Result<Vec<String>> {
+        let mut statement = connection.prepare("SELECT DISTINCT chat_id FROM rachet_state WHERE user_id = ? ORDER BY chat_id")?;
+        let chats = statement.query_map(params![user.user_id], |row| row.get(0))?;
+        chats.collect()
+    }
+    // Newest first
+    pub fn load_archived(user: &User, connection: &Connection, chat_id: &str) -> rusqlite::Result<Vec<Self>> {
+        let mut statement = connection.prepare("SELECT * FROM rachet_state WHERE chat_id = ? AND user_id = ? AND archived_at IS NOT NULL ORDER BY archived_at DESC, session_id DESC")?;
+        let sessions = statement.query_map(params![chat_id, user.user_id], Self::from_row)?;
+        sessions.collect()
+    }
+    // Session stays readable for late messages, the oldest ones over the limit are dropped
+    pub fn archive(&self, user: &User, connection: &Connection, chat_id: &str) -> rusqlite::Result<usize> {
+        // Kept increasing within one millisecond too, as it orders the archive
+        let archived_at: i64 = connection.query_row("SELECT MAX(?1, IFNULL(MAX(archived_at) + 1, 0)) FROM rachet_state WHERE chat_id = ?2 AND user_id = ?3",
+            params![unix_millis(), chat_id, user.user_id], |row| row.get(0))?;
+        // Session may not be stored yet, and it must not become the active one even for a moment
+        self.upsert(Some(archived_at), user, connection, chat_id)?;
+        connection.execute("UPDATE rachet_state SET archived_at = ? WHERE session_id = ?", params![archived_at, self.session_id])?;
+        connection.execute("DELETE FROM rachet_state WHERE chat_id = ?1 AND user_id = ?2 AND archived_at IS NOT NULL AND session_id NOT IN (
+            SELECT session_id FROM rachet_state WHERE chat_id = ?1 AND user_id = ?2 AND archived_at IS NOT NULL ORDER BY archived_at DESC, session_id DESC LIMIT ?3
+        )", params![chat_id, user.user_id, MAX_ARCHIVED_SESSIONS])
+    }
+    // Makes archived session the active one again, the current active session is archived in its place
+    pub fn promote(&self, active: Option<&ChatState>, user: &User, connection: &Connection, chat_id: &str) -> rusqlite::Result<usize> {
+        if let Some(active) = active {
+            active.archive(user, connection, chat_id)?;
+        }
+        connection.execute("UPDATE rachet_state SET archived_at = NULL WHERE session_id = ?", params![self.session_id])?;
+        // Archiving the active one may have pruned this session if it was the oldest
+        self.save(user, connection, chat_id)
+    }
+    #[inline]
+    pub fn get_last_received_id(&self) -> u32 {
+        self.receiver_chain.id
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct ReceiverBundle {
+    pub receiver_identity: PublicKey,
+    pub receiver_prekey: PublicKey,
+    pub receiver_onetime: Option<PublicKey>,
+    pub receiver_onetime_id: Option<u32>,
+    pub receiver_prekey_id: u32,
+    #[serde(default)]
+    pub receiver_kem_prekey: Option<KemPrekeyBundle>,
+    // Verified if present, bundles fetched by the UI don't carry it
+    #[serde(default)]
+    pub receiver_prekey_signature: Option<Vec<u8>>,
+}
+
+impl ReceiverBundle {
+    // Our own keys for a peer to start a session with, takes a new onetime key
+    pub fn publish(conn: &Connection, user: &User) -> rusqlite::Result<Self> {
+        Ok(KeyBundle::generate(1, conn, user)?.take_receiver_bundle())
+    }
+    pub fn verify_prekey(&self) -> bool {
+        let Some(signature) = &self.receiver_prekey_signature else {
+            return true;
+        };
+        match Signature::try_from(signature.as_slice()) {
+            Ok(signature) => verify_signature(self.receiver_identity.as_bytes(), self.receiver_prekey.as_bytes(), &signature),
+            Err(_) => false
+        }
+    }
+}
+
+// Stores session started by the peer and returns the one to use from now on.
+// Anyone can send a message that claims to start a session, so nothing is replaced before its first message decrypts.
+// A chat stays with the peer it was started with, someone else reusing its id can't take it over
+pub fn accept_session(mut incoming: ChatState, initial_message: &Message, our_identity: &PublicKey, user: &User, conn: &Connection, chat_id: &str) -> CommandResult<ChatState> {
+    if !message::authenticates(initial_message, &incoming) {
+        return Err(CommandError::UnauthenticatedSession);
+    }
+    let current = ChatState::load(user, conn, chat_id);
+    if let Ok(current) = &current {
+        if current.peer(our_identity).map_or(false, |peer| incoming.peer(our_identity) != Some(peer)) {
+            return Err(CommandError::PeerMismatch(chat_id.to_owned()));
+        }
+    }
+    match current {
+        Ok(current) if current.wins_over(&incoming, our_identity) => {
+            debug!("Keeping our session of chat {}, the peer will switch to it", chat_id);
+            // Kept only to read what the peer sent before switching
+            incoming.ended = true;
+            incoming.archive(user, conn, chat_id)?;
+            Ok(current)
+        }
+        Ok(mut current) => {
+            // Peer started a new session, the current one is kept for messages still in flight
+            current.ended = current.is_unanswered_initiation(our_identity);
+            current.archive(user, conn, chat_id)?;
+            incoming.save(user, conn, chat_id)?;
+            Ok(incoming)
+        }
+        Err(_) => {
+            incoming.save(user, conn, chat_id)?;
+            Ok(incoming)
+        }
+    }
+}
+
+fn start_session(receiver_keys: &ReceiverBundle, chat_id: &str, conn: &Connection, user: &User) -> CommandResult<ChatState> {
+    if !receiver_keys.verify_prekey() {
+        return Err(anyhow::anyhow!("Prekey signature doesn't match the identity key").into());
+    }
+    if let Some(kem) = &receiver_keys.receiver_kem_prekey {
+        if !kem.verify(&receiver_keys.receiver_identity) {
+            return Err(anyhow::anyhow!("Post-quantum prekey signature doesn't match the identity key").into());
+        }
+    }
+    let identity_key = IdentityKey::fetch(None, conn, user)?;
+    let ephemeral = IdentityKey::generate();
+    let passphrase = passphrase::fetch(conn, user, chat_id)?;
+    ChatState::initial_sender(identity_key, ephemeral, &receiver_keys.receiver_identity,&receiver_keys.receiver_prekey, receiver_keys.receiver_onetime, receiver_keys.receiver_prekey_id, receiver_keys.receiver_onetime_id, receiver_keys.receiver_kem_prekey.as_ref(), passphrase.as_ref())
+}
+
+// Session to use from now on, set up from the peer's first message or started from their bundle
+pub fn enter_session(chat_id: &str, sender_identity: Option<PublicKey>, received_message: Option<Message>, receiver_keys: Option<ReceiverBundle>, user: &User, conn: &Connection) -> CommandResult<Option<ChatState>> {
+    if let Some(message) = received_message {
+        let initial_data = message.header.initial.as_ref().ok_or_else(|| anyhow::anyhow!("Message doesn't start a session"))?;
+        let sender_identity = sender_identity.ok_or_else(|| anyhow::anyhow!("Identity key of the sender is required"))?;
+        let identity_key = IdentityKey::fetch(None, conn, user)?;
+        let prekey = SignedKey::fetch(None, conn, user)?;
+        let our_identity = identity_key.get_public_key();
+        let passphrase = passphrase::fetch(conn, user, chat_id)?;
+        let incoming = ChatState::initial_receiver(identity_key, prekey, initial_data, &message.header.rachet_key, conn, sender_identity, user, passphrase.as_ref())?;
+        Ok(Some(accept_session(incoming, &message, &our_identity, user, conn, chat_id)?))
+    } else if let Some(receiver_keys) = receiver_keys {
+        let new_chat = start_session(&receiver_keys, chat_id, conn, user)?;
+        if let Ok(previous) = ChatState::load(user, conn, chat_id) {
+            previous.archive(user, conn, chat_id)?;
+        }
+        new_chat.save(user, conn, chat_id)?;
+        Ok(Some(new_chat))
+    } else {
+        Ok(None)
+    }
+}
+
+// Replaces a lost or broken session with a new one built from the peer's current bundle.
+// Returns the new session and end of session message for the peer, if the old session could still be used
+pub fn reset_session(chat_id: &str, receiver_keys: &ReceiverBundle, user: &User, conn: &Connection) -> CommandResult<(ChatState, Option<Message>)> {
+    let new_chat = start_session(receiver_keys, chat_id, conn, user)?;
+    let end_message = match ChatState::load(user, conn, chat_id) {
+        Ok(mut previous) => {
+            let end_message = end_session(chat_id, &mut previous, user, conn);
+            previous.ended = true;
+            previous.archive(user, conn, chat_id)?;
+            end_message
+        }
+        Err(_) => None
+    };
+    new_chat.save(user, conn, chat_id)?;
+    Ok((new_chat, end_message))
+}
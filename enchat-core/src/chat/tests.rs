@@ -0,0 +1,175 @@
+use crate::{keybundle::{IdentityKey, StoredKey, Onetime, ManagedKey, SignedKey, KemPrekey, KemPrekeyBundle}, encryption::{PublicKey, Otherkey, ProtocolVersion}, errors::CommandError, helpers::prepare_database, user::User};
+
+use super::{ChatState};
+
+#[test]
+fn bob_alice_should_share_same_initial_key() {
+    let alice_id = IdentityKey::generate();
+    let alice_ephemeral = IdentityKey::generate();
+    let bob_onetime = Onetime::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let alice_dh = ChatState::dh_sender(alice_ephemeral.get_keypair(), alice_id.get_keypair(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), Some(bob_onetime.get_public_key()));
+    let bob_dh = ChatState::dh_receiver(&alice_ephemeral.get_public_key(), bob_id.get_keypair(), &alice_id.get_public_key(), bob_prekey.get_keypair(), Some(bob_onetime));
+    assert_eq!(alice_dh, bob_dh);
+}
+
+#[test]
+fn bob_should_be_able_to_receive_alice() {
+    let psk = vec![0x02u8; 32];
+    let bob_keypair = IdentityKey::generate();
+    let mut initial_alice_chat = ChatState::new_sender(&bob_keypair.get_public_key(), psk.clone(), ProtocolVersion::CURRENT);
+    let (alice_rachet_key, alice_message_key, _) = initial_alice_chat.move_sender();
+    let mut initial_bob_chat = ChatState::new_receiver(bob_keypair.get_keypair().clone(), &alice_rachet_key, psk, ProtocolVersion::CURRENT);
+    let bob_message_key = initial_bob_chat.move_receiver(Some(alice_rachet_key));
+    assert_eq!(alice_message_key, bob_message_key);
+}
+#[test]
+fn bob_should_be_able_to_receive_alice_multiple() {
+    let psk = vec![0x02u8; 32];
+    let bob_keypair = IdentityKey::generate();
+    let mut initial_alice_chat = ChatState::new_sender(&bob_keypair.get_public_key(), psk.clone(), ProtocolVersion::CURRENT);
+    let mut vec: Vec<(PublicKey, Otherkey)> = Vec::new();
+    for _ in 0..4 {
+        let (alice_rachet_key, alice_message_key, _) = initial_alice_chat.move_sender();
+        vec.push((alice_rachet_key, alice_message_key));
+    }
+    let mut initial_bob_chat = ChatState::new_receiver(bob_keypair.get_keypair().clone(), &vec[0].0, psk.clone(), ProtocolVersion::CURRENT);
+    for message in vec {
+        let bob_message_key = initial_bob_chat.move_receiver(Some(message.0));
+        assert_eq!(message.1, bob_message_key);
+        debug!("Valid");
+    }
+}
+#[test]
+fn bob_alice_should_pingpong_multiple_times() {
+    let psk = vec![0x02u8; 32];
+    let bob_keypair = IdentityKey::generate();
+    let mut initial_alice_chat = ChatState::new_sender(&bob_keypair.get_public_key(), psk.clone(), ProtocolVersion::CURRENT);
+    let mut initial_bob_chat: Option<ChatState> = None;
+    for x in 0..5 {
+        let (alice_rachet_key, alice_message_key, _) = initial_alice_chat.move_sender();
+        if x == 0 {
+            initial_bob_chat = Some(ChatState::new_receiver(bob_keypair.get_keypair().clone(), &alice_rachet_key, psk.clone(), ProtocolVersion::CURRENT));
+        }
+        assert_eq!(initial_bob_chat.as_mut().unwrap().move_receiver(Some(alice_rachet_key)), alice_message_key);
+        let (bob_rachet_key, bob_message_key, _) = initial_bob_chat.as_mut().unwrap().move_sender();
+        assert_eq!(initial_alice_chat.move_receiver(Some(bob_rachet_key)), bob_message_key);
+    }
+}
+#[test]
+fn legacy_version_sessions_should_keep_working() {
+    let psk = vec![0x02u8; 32];
+    let bob_keypair = IdentityKey::generate();
+    let mut alice_chat = ChatState::new_sender(&bob_keypair.get_public_key(), psk.clone(), ProtocolVersion::V1);
+    let (alice_rachet_key, alice_message_key, _) = alice_chat.move_sender();
+    let mut bob_chat = ChatState::new_receiver(bob_keypair.get_keypair().clone(), &alice_rachet_key, psk.clone(), ProtocolVersion::V1);
+    assert_eq!(bob_chat.move_receiver(Some(alice_rachet_key)), alice_message_key);
+    let (bob_rachet_key, bob_message_key, _) = bob_chat.move_sender();
+    assert_eq!(alice_chat.move_receiver(Some(bob_rachet_key)), bob_message_key);
+    let mut current_bob_chat = ChatState::new_receiver(bob_keypair.get_keypair().clone(), &alice_rachet_key, psk, ProtocolVersion::CURRENT);
+    assert_ne!(current_bob_chat.move_receiver(Some(alice_rachet_key)), alice_message_key);
+}
+#[test]
+fn protocol_version_should_be_restored() {
+    let db = prepare_database();
+    let user = User { user_id: Some("1".to_owned()) };
+    let bob_keypair = IdentityKey::generate();
+    let chat = ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x02u8; 32], ProtocolVersion::V1);
+    chat.save(&user, &db, "1").unwrap();
+    assert_eq!(ChatState::load(&user, &db, "1").unwrap().version, ProtocolVersion::V1);
+    let chat = ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x02u8; 32], ProtocolVersion::V2);
+    chat.save(&user, &db, "2").unwrap();
+    assert_eq!(ChatState::load(&user, &db, "2").unwrap().version, ProtocolVersion::V2);
+}
+#[test]
+fn archived_session_should_not_be_active() {
+    let db = prepare_database();
+    let user = User { user_id: Some("1".to_owned()) };
+    let bob_keypair = IdentityKey::generate();
+    let first = ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x02u8; 32], ProtocolVersion::CURRENT);
+    first.save(&user, &db, "1").unwrap();
+    let second = ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x03u8; 32], ProtocolVersion::CURRENT);
+    assert!(second.save(&user, &db, "1").is_err());
+    first.archive(&user, &db, "1").unwrap();
+    assert!(ChatState::load(&user, &db, "1").is_err());
+    second.save(&user, &db, "1").unwrap();
+    assert_eq!(ChatState::load(&user, &db, "1").unwrap().session_id, second.session_id);
+    second.archive(&user, &db, "1").unwrap();
+    let archived = ChatState::load_archived(&user, &db, "1").unwrap();
+    assert_eq!(archived.len(), 2);
+    assert_eq!(archived[0].session_id, second.session_id);
+    assert_eq!(archived[1].session_id, first.session_id);
+}
+#[test]
+fn archive_should_drop_oldest_sessions() {
+    let db = prepare_database();
+    let user = User { user_id: Some("1".to_owned()) };
+    let bob_keypair = IdentityKey::generate();
+    let sessions: Vec<ChatState> = (0..12).map(|_| ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x02u8; 32], ProtocolVersion::CURRENT)).collect();
+    for session in &sessions {
+        session.archive(&user, &db, "1").unwrap();
+    }
+    let archived: Vec<i64> = ChatState::load_archived(&user, &db, "1").unwrap().iter().map(|session| session.session_id).collect();
+    let expected: Vec<i64> = sessions.iter().rev().take(10).map(|session| session.session_id).collect();
+    assert_eq!(archived, expected);
+}
+#[test]
+fn promoted_session_should_replace_active() {
+    let db = prepare_database();
+    let user = User { user_id: Some("1".to_owned()) };
+    let bob_keypair = IdentityKey::generate();
+    let old = ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x02u8; 32], ProtocolVersion::CURRENT);
+    old.archive(&user, &db, "1").unwrap();
+    let active = ChatState::new_sender(&bob_keypair.get_public_key(), vec![0x03u8; 32], ProtocolVersion::CURRENT);
+    active.save(&user, &db, "1").unwrap();
+    old.promote(Some(&active), &user, &db, "1").unwrap();
+    assert_eq!(ChatState::load(&user, &db, "1").unwrap().session_id, old.session_id);
+    let archived = ChatState::load_archived(&user, &db, "1").unwrap();
+    assert_eq!(archived.len(), 1);
+    assert_eq!(archived[0].session_id, active.session_id);
+}
+#[test]
+fn pending_initial_data_should_be_restored() {
+    let db = prepare_database();
+    let user = User { user_id: Some("1".to_owned()) };
+    let bob_id = IdentityKey::generate();
+    let kem = KemPrekey::generate();
+    let bundle = KemPrekeyBundle { key: kem.get_public_key(), signature: Vec::new(), id: 7 };
+    let chat = ChatState::initial_sender(IdentityKey::generate(), IdentityKey::generate(), &bob_id.get_public_key(), &SignedKey::generate().get_public_key(), None, 3, Some(5), Some(&bundle), None).unwrap();
+    chat.save(&user, &db, "1").unwrap();
+    let restored = ChatState::load(&user, &db, "1").unwrap().receiver_used_keys.clone().unwrap();
+    let initial = chat.receiver_used_keys.clone().unwrap();
+    assert_eq!(restored.ephemeral, initial.ephemeral);
+    assert_eq!(restored.prekey_id, 3);
+    assert_eq!(restored.onetime_key_id, Some(5));
+    assert_eq!(restored.kem_prekey_id, Some(7));
+    assert_eq!(restored.kem_ciphertext, initial.kem_ciphertext);
+    assert_eq!(restored.version, initial.version);
+}
+#[test]
+fn malformed_kem_prekey_should_be_an_error() {
+    let bundle = KemPrekeyBundle { key: vec![1u8; 5], signature: Vec::new(), id: 7 };
+    let chat = ChatState::initial_sender(IdentityKey::generate(), IdentityKey::generate(), &IdentityKey::generate().get_public_key(), &SignedKey::generate().get_public_key(), None, 1, None, Some(&bundle), None);
+    assert!(matches!(chat, Err(CommandError::InvalidKemPrekey)));
+}
+#[test]
+fn prekey_should_be_first_rachet_key() {
+    let db = prepare_database();
+    let user = User { user_id: Some("2".to_owned()) };
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let bob_prekey_public = bob_prekey.get_public_key();
+    let mut alice = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey_public, None, 1, None, None, None).unwrap();
+    assert_eq!(alice.rachet.their_public, bob_prekey_public);
+    let (alice_rachet_key, alice_message_key, _) = alice.move_sender();
+    let mut bob = ChatState::initial_receiver(bob_id.clone(), bob_prekey, alice.receiver_used_keys.as_ref().unwrap(), &alice_rachet_key, &db, alice_id.get_public_key(), &user, None).unwrap();
+    assert_eq!(PublicKey::from(&bob.rachet.our_keypair), bob_prekey_public);
+    assert_eq!(bob.version, ProtocolVersion::V3);
+    assert_eq!(bob.move_receiver(Some(alice_rachet_key)), alice_message_key);
+    // Identity key takes no part in the rachet
+    assert_ne!(PublicKey::from(&bob.rachet.our_keypair), bob_id.get_public_key());
+    let (bob_rachet_key, bob_message_key, _) = bob.move_sender();
+    assert_eq!(alice.move_receiver(Some(bob_rachet_key)), bob_message_key);
+}
@@ -6,6 +6,11 @@ use hkdf::Hkdf;
 use rand::rngs::OsRng;
 use sha2::Sha256;
 use x25519_dalek::{StaticSecret};
+use zeroize::Zeroize;
+
+mod kdf;
+
+pub use self::kdf::{ProtocolVersion, derive, DATABASE_INFO};
 
 pub type Key = StaticSecret;
 pub type PublicKey = x25519_dalek::PublicKey;
@@ -24,6 +29,14 @@ pub fn generate_ephemeral() -> StaticSecret {
 
 pub struct KdfOutput(pub RootKey, pub Otherkey);
 
+// Callers copy out the key they keep, the rest is cleared when the output goes away
+impl Drop for KdfOutput {
+    fn drop(&mut self) {
+        self.0.zeroize();
+        self.1.zeroize();
+    }
+}
+
 pub fn kdf(secrets: Vec<u8>) -> KdfOutput {
     let mut message: Vec<u8> = vec![0xFF];
     message.extend(secrets);
@@ -34,11 +47,11 @@ pub fn kdf(secrets: Vec<u8>) -> KdfOutput {
     KdfOutput(root_key.try_into().expect("Invalid size"), other_key.try_into().expect("Invalid size"))
 }
 
-pub fn encrypt(key: &Otherkey, message: &[u8], ad: &[u8]) -> Vec<u8> {
-    let output = kdf(key.to_vec());
+pub fn encrypt(key: &Otherkey, message: &[u8], ad: &[u8], version: ProtocolVersion) -> Vec<u8> {
+    let output = version.message_key(key);
     let (iv, _) = output.1.split_at(12);
     let aes_key = Aes256GcmSiv::new_from_slice(output.0.as_ref()).unwrap();
-    let nonce = Nonce::from_slice(&iv);
+    let nonce = Nonce::from_slice(iv);
     let payload = Payload {
         msg: message,
         aad: ad
@@ -46,11 +59,11 @@ pub fn encrypt(key: &Otherkey, message: &[u8], ad: &[u8]) -> Vec<u8> {
     aes_key.encrypt(nonce, payload).expect("Failed to encrypt???")
 }
 
-pub fn decrypt(key: &Otherkey, message: &[u8], ad: &[u8]) -> Result<Vec<u8>, aes_gcm_siv::Error> {
-    let output = kdf(key.to_vec());
+pub fn decrypt(key: &Otherkey, message: &[u8], ad: &[u8], version: ProtocolVersion) -> Result<Vec<u8>, aes_gcm_siv::Error> {
+    let output = version.message_key(key);
     let (iv, _) = output.1.split_at(12);
     let aes_key = Aes256GcmSiv::new_from_slice(output.0.as_ref()).unwrap();
-    let nonce = Nonce::from_slice(&iv);
+    let nonce = Nonce::from_slice(iv);
     let payload = Payload {
         msg: message,
         aad: ad
@@ -60,13 +73,15 @@ pub fn decrypt(key: &Otherkey, message: &[u8], ad: &[u8]) -> Result<Vec<u8>, aes
 
 #[cfg(test)]
 mod tests {
+    use super::ProtocolVersion;
+
     #[test]
     pub fn should_encrypt_with_ad() {
         let key = [0x20u8; 32];
         let message = "Hello World".as_bytes();
         let ad = "AD".as_bytes();
-        let encrypted = super::encrypt(&key, message, ad);
-        let decrypted = super::decrypt(&key, &encrypted, ad).expect("Failed to decrypt");
+        let encrypted = super::encrypt(&key, message, ad, ProtocolVersion::CURRENT);
+        let decrypted = super::decrypt(&key, &encrypted, ad, ProtocolVersion::CURRENT).expect("Failed to decrypt");
         assert_eq!(message, &decrypted[..]);
     }
     #[test]
@@ -74,9 +89,9 @@ mod tests {
         let key = [0x20u8; 32];
         let message = "Hello World".as_bytes();
         let ad = "AD".as_bytes();
-        let encrypted = super::encrypt(&key, message, ad);
+        let encrypted = super::encrypt(&key, message, ad, ProtocolVersion::CURRENT);
         let ad = "ADE".as_bytes();
-        let decrypted = super::decrypt(&key, &encrypted, ad);
+        let decrypted = super::decrypt(&key, &encrypted, ad, ProtocolVersion::CURRENT);
         assert!(decrypted.is_err());
     }
     #[test]
@@ -84,12 +99,21 @@ mod tests {
         let key = [0x20u8; 32];
         let message = "Hello World".as_bytes();
         let ad = "AD".as_bytes();
-        let encrypted = super::encrypt(&key, message, ad);
+        let encrypted = super::encrypt(&key, message, ad, ProtocolVersion::CURRENT);
         let key = [0x21u8; 32];
-        let decrypted = super::decrypt(&key, &encrypted, ad);
+        let decrypted = super::decrypt(&key, &encrypted, ad, ProtocolVersion::CURRENT);
         assert!(decrypted.is_err());
     }
     #[test]
+    pub fn decryption_should_fail_with_other_version() {
+        let key = [0x20u8; 32];
+        let message = "Hello World".as_bytes();
+        let ad = "AD".as_bytes();
+        let encrypted = super::encrypt(&key, message, ad, ProtocolVersion::V1);
+        assert!(super::decrypt(&key, &encrypted, ad, ProtocolVersion::V1).is_ok());
+        assert!(super::decrypt(&key, &encrypted, ad, ProtocolVersion::V2).is_err());
+    }
+    #[test]
     pub fn kdf_should_be_deterministic() {
         let key = [0x20u8; 32];
         let output = super::kdf(key.to_vec());
@@ -0,0 +1,160 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::{kdf, KdfOutput, RootKey};
+
+const INITIAL_INFO: &[u8] = b"enchat x3dh";
+const ROOT_INFO: &[u8] = b"enchat root chain";
+const MESSAGE_INFO: &[u8] = b"enchat message key";
+pub const DATABASE_INFO: &[u8] = b"enchat database key";
+
+// Inputs for HMAC chain step, as in the Double Rachet spec
+const MESSAGE_KEY_CONSTANT: u8 = 0x01;
+const CHAIN_KEY_CONSTANT: u8 = 0x02;
+
+// Version of the key derivation used by a session. It's stored with the rachet, so sessions
+// created before an upgrade keep deriving the same keys
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolVersion {
+    // Single HKDF with 0xFF prefix and one info string for every purpose
+    V1,
+    // Separate labels per purpose, salted root chain and HMAC message chains
+    V2,
+    // Key derivation of V2, the signed prekey is the receiver's first rachet key instead of the identity key
+    V3,
+}
+
+impl ProtocolVersion {
+    pub const CURRENT: Self = ProtocolVersion::V3;
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(ProtocolVersion::V1),
+            2 => Some(ProtocolVersion::V2),
+            3 => Some(ProtocolVersion::V3),
+            _ => None,
+        }
+    }
+    pub fn id(&self) -> u8 {
+        match self {
+            ProtocolVersion::V1 => 1,
+            ProtocolVersion::V2 => 2,
+            ProtocolVersion::V3 => 3,
+        }
+    }
+    // Long-term identity key is used only in X3DH, never in rachet DH
+    pub fn prekey_rachet(&self) -> bool {
+        matches!(self, ProtocolVersion::V3)
+    }
+    // Turns X3DH output into the first root key
+    pub fn initial(&self, secrets: Vec<u8>) -> KdfOutput {
+        match self {
+            ProtocolVersion::V1 => kdf(secrets),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => {
+                let mut input = vec![0xFFu8; 32];
+                input.extend(secrets);
+                derive(None, &input, INITIAL_INFO)
+            }
+        }
+    }
+    // Returns new root key and chain key
+    pub fn root(&self, root_key: &RootKey, dh: &[u8]) -> KdfOutput {
+        match self {
+            ProtocolVersion::V1 => {
+                let mut vec = Vec::with_capacity(64);
+                vec.extend_from_slice(dh);
+                vec.extend_from_slice(root_key);
+                kdf(vec)
+            }
+            ProtocolVersion::V2 | ProtocolVersion::V3 => derive(Some(root_key), dh, ROOT_INFO),
+        }
+    }
+    // Returns next chain key and message key
+    pub fn chain(&self, chain_key: &RootKey) -> KdfOutput {
+        match self {
+            ProtocolVersion::V1 => kdf(chain_key.to_vec()),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => KdfOutput(hmac(chain_key, CHAIN_KEY_CONSTANT), hmac(chain_key, MESSAGE_KEY_CONSTANT)),
+        }
+    }
+    // Returns cipher key, the nonce is taken from the beginning of the second part
+    pub fn message_key(&self, message_key: &RootKey) -> KdfOutput {
+        match self {
+            ProtocolVersion::V1 => kdf(message_key.to_vec()),
+            ProtocolVersion::V2 | ProtocolVersion::V3 => derive(None, message_key, MESSAGE_INFO),
+        }
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion::CURRENT
+    }
+}
+
+pub fn derive(salt: Option<&[u8]>, input: &[u8], info: &[u8]) -> KdfOutput {
+    let hk = Hkdf::<Sha256>::new(salt, input);
+    let mut output = [0u8; 64];
+    hk.expand(info, &mut output).expect("HKDF Failed");
+    let (first, second) = output.split_at(32);
+    KdfOutput(first.try_into().expect("Invalid size"), second.try_into().expect("Invalid size"))
+}
+
+fn hmac(key: &RootKey, constant: u8) -> RootKey {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key size");
+    mac.update(&[constant]);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtocolVersion;
+
+    #[test]
+    fn v1_should_match_legacy_kdf() {
+        let key = [0x20u8; 32];
+        let legacy = crate::encryption::kdf(key.to_vec());
+        let output = ProtocolVersion::V1.chain(&key);
+        assert_eq!(legacy.0, output.0);
+        assert_eq!(legacy.1, output.1);
+    }
+    #[test]
+    fn labels_should_separate_outputs() {
+        let key = [0x20u8; 32];
+        let version = ProtocolVersion::V2;
+        let outputs = [
+            version.initial(key.to_vec()).0,
+            version.root(&key, &key).0,
+            version.chain(&key).0,
+            version.chain(&key).1,
+            version.message_key(&key).0,
+        ];
+        for (i, a) in outputs.iter().enumerate() {
+            for b in outputs.iter().skip(i + 1) {
+                assert_ne!(a, b);
+            }
+        }
+    }
+    #[test]
+    fn root_step_should_depend_on_salt() {
+        let dh = [0x20u8; 32];
+        let first = ProtocolVersion::V2.root(&[0x01u8; 32], &dh);
+        let second = ProtocolVersion::V2.root(&[0x02u8; 32], &dh);
+        assert_ne!(first.0, second.0);
+        assert_ne!(first.1, second.1);
+    }
+    #[test]
+    fn prekey_rachet_should_keep_v2_derivation() {
+        let key = [0x20u8; 32];
+        assert!(ProtocolVersion::V3.prekey_rachet());
+        assert!(!ProtocolVersion::V2.prekey_rachet());
+        assert_eq!(ProtocolVersion::V2.chain(&key).1, ProtocolVersion::V3.chain(&key).1);
+        assert_eq!(ProtocolVersion::from_id(ProtocolVersion::V3.id()), Some(ProtocolVersion::V3));
+    }
+    #[test]
+    fn versions_should_derive_different_keys() {
+        let key = [0x20u8; 32];
+        assert_ne!(ProtocolVersion::V1.chain(&key).1, ProtocolVersion::V2.chain(&key).1);
+        assert_ne!(ProtocolVersion::V1.root(&key, &key).0, ProtocolVersion::V2.root(&key, &key).0);
+    }
+}
@@ -0,0 +1,65 @@
+use std::{io, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::transport::TransportError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("Database error: {0}")]
+    DatabaseError(#[from] rusqlite::Error),
+    #[error("IO error: {0}")]
+    IOError(#[from] io::Error),
+    #[error("Error parsing binary structure: {0}")]
+    BinarySerializationError(#[from] bincode::Error),
+    #[error("Error encrypting or decrypting message")]
+    MessageCipherError(#[from] aead::Error),
+    #[error("Chat passphrase doesn't match, {0}")]
+    PassphraseMismatch(&'static str),
+    #[error("Transport error: {0}")]
+    TransportError(#[from] TransportError),
+    #[error("Keyring error: {0}")]
+    KeyringError(#[from] keyring::Error),
+    #[error("Database migration failed: {0}")]
+    MigrationError(#[from] rusqlite_migration::Error),
+    #[error("Database is from a newer version of enchat (schema {0}, this one knows up to {1}), update the app to open it")]
+    DatabaseTooNew(usize, usize),
+    #[error("Database is damaged or its key doesn't fit: {0}")]
+    DatabaseDamaged(String),
+    #[error("Can't use {0:?} as the data directory: {1}")]
+    DataDir(PathBuf, io::Error),
+    #[error("No data directory found, set one with --data-dir or ENCHAT_DATA_DIR")]
+    NoDataDir,
+    #[error("No session in this chat, start a new one")]
+    NoSession,
+    #[error("Post-quantum prekey of the peer is malformed")]
+    InvalidKemPrekey,
+    #[error("First message of the new session can't be decrypted, the session is ignored")]
+    UnauthenticatedSession,
+    #[error("Chat {0} already has a session with another peer, the new session is ignored")]
+    PeerMismatch(String),
+    #[error("No profile is open, log in first")]
+    NoProfile,
+    #[error("App is locked")]
+    Locked,
+    #[error("Wrong PIN or passphrase")]
+    WrongPin,
+    #[error("Too many failed attempts, try again in {0} seconds")]
+    UnlockBackoff(u64),
+    #[error("Set a PIN or passphrase first")]
+    NoPin,
+    #[error("Duress passphrase has to differ from the PIN")]
+    DuressMatchesPin,
+    #[error("Unexpected error")]
+    Other(#[from] anyhow::Error)
+}
+
+impl Serialize for CommandError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+pub type CommandResult<T, E = CommandError> = anyhow::Result<T, E>;
\ No newline at end of file
@@ -0,0 +1,256 @@
+use std::{path::Path, fs::{self, File}, io::{Read, Write}};
+
+
+use aead::stream;
+use anyhow::Context;
+use chacha20poly1305::{XChaCha20Poly1305, KeyInit};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use rusqlite::{Connection, params};
+
+use crate::{user::User, keybundle::{MessageKeyType, read_message_key, read_message_keys}, errors::CommandResult, encryption::{get_rng, Otherkey}, helpers::shred};
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct FileInfo {
+    pub filename: String,
+    pub size: u64,
+    pub nonce: Vec<u8>
+}
+
+const BUFFER_LEN_ENCRYPT: usize = 500;
+const BUFFER_LEN_DECRYPT: usize = 516;
+
+#[inline]
+fn get_nonce(message_id: u32) -> Vec<u8> {
+    let mut nonce = vec![0u8; 15];
+    get_rng().fill_bytes(&mut nonce[0..15]);
+    nonce.extend_from_slice(&message_id.to_be_bytes());
+    nonce
+}
+
+fn decrypt_file(nonce: &[u8], source: &mut File, output: &mut File, message_key: &[u8]) -> anyhow::Result<()> {
+    // Based on https://kerkour.com/rust-file-encryption
+    let cipher = XChaCha20Poly1305::new(message_key.into());
+    let mut decryptor = stream::DecryptorBE32::from_aead(cipher, nonce.into());
+    let mut buffer = [0u8; BUFFER_LEN_DECRYPT];
+    loop {
+        let read_count = source.read(&mut buffer)?;
+        if read_count == BUFFER_LEN_DECRYPT {
+            output.write_all(
+            &decryptor.decrypt_next(buffer.as_slice())
+                    .map_err(|_| anyhow::anyhow!("Decryption failed"))?
+            )?;
+        } else if read_count == 0 {
+            break;
+        } else {
+            output.write_all(
+                &decryptor.decrypt_last(&buffer[..read_count])
+                    .map_err(|_| anyhow::anyhow!("Decryption failed"))?
+            )?;
+            break;
+        }
+    }
+    Ok(())
+}
+fn encrypt(nonce: &[u8], source: &mut File, output: &mut File, message_key: &[u8]) -> anyhow::Result<()> {
+    let cipher = XChaCha20Poly1305::new(message_key.into());
+    let mut encryptor = stream::EncryptorBE32::from_aead(cipher, nonce.into());
+    let mut buffer = [0u8; BUFFER_LEN_ENCRYPT];
+
+    loop {
+        let read_count = source.read(&mut buffer)?;
+        if read_count == BUFFER_LEN_ENCRYPT {
+            output.write_all(
+            &encryptor.encrypt_next(buffer.as_slice())
+                    .map_err(|_| anyhow::anyhow!("Encryption failed"))?
+            )?;
+        } else if read_count == 0 {
+            break;
+        } else {
+            output.write_all(
+            &encryptor.encrypt_last(&buffer[..read_count])
+                    .map_err(|_| anyhow::anyhow!("Encryption of last segment failed"))?
+            )?;
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn decrypt_attachment(info: &FileInfo, receiving: bool, message_id: u32, input_path: &Path, output_path: &Path, chat_id: &str, user: &User, conn: &Connection) -> CommandResult<()> {
+    let message_keys = prepare_decryption(receiving, message_id, output_path, chat_id, user, conn)?;
+    decrypt_with_keys(info, input_path, output_path, &message_keys)
+}
+
+// The part of decrypt_attachment that needs the database, decrypt_with_keys doesn't.
+// Archived sessions of the chat may have keys for the same message id, so all of them are returned
+pub fn prepare_decryption(receiving: bool, message_id: u32, output_path: &Path, chat_id: &str, user: &User, conn: &Connection) -> CommandResult<Vec<Otherkey>> {
+    let message_keys = read_message_keys(MessageKeyType::from_receiving(receiving), message_id, chat_id, user, conn);
+    if message_keys.is_empty() {
+        return Err(anyhow::anyhow!("Message key for the file not found, have you received the message?").into());
+    }
+    File::create(output_path)?;
+    // Tracked before writing, so a partly decrypted file is removed as well
+    track_decrypted_file(output_path, user, conn)?;
+    Ok(message_keys)
+}
+
+// Tries the keys newest first, the stream fails on its first block with a key of another session
+pub fn decrypt_with_keys(info: &FileInfo, input_path: &Path, output_path: &Path, message_keys: &[Otherkey]) -> CommandResult<()> {
+    let mut result = Err(anyhow::anyhow!("Message key for the file not found"));
+    for message_key in message_keys {
+        let mut output = File::create(output_path)?;
+        let mut source = File::open(input_path)?;
+        result = decrypt_file(&info.nonce, &mut source, &mut output, message_key);
+        if result.is_ok() {
+            break;
+        }
+    }
+    Ok(result?)
+}
+
+fn track_decrypted_file(path: &Path, user: &User, conn: &Connection) -> CommandResult<()> {
+    let path = fs::canonicalize(path)?;
+    conn.execute("INSERT OR IGNORE INTO attachment_file(path, user_id) VALUES (?, ?)", params![path.to_string_lossy(), user.user_id])?;
+    Ok(())
+}
+
+// Shreds every attachment decrypted by the user that is still on disk
+pub fn remove_decrypted_files(user: &User, conn: &Connection) -> CommandResult<()> {
+    let mut statement = conn.prepare("SELECT path FROM attachment_file WHERE user_id = ?")?;
+    let paths = statement.query_map(params![user.user_id], |row| row.get::<_, String>(0))?.collect::<rusqlite::Result<Vec<_>>>()?;
+    for path in paths {
+        let path = Path::new(&path);
+        if path.is_file() {
+            shred(path)?;
+        }
+        conn.execute("DELETE FROM attachment_file WHERE path = ? AND user_id = ?", params![path.to_string_lossy(), user.user_id])?;
+    }
+    Ok(())
+}
+
+// File is encrypted with the key of the message it's attached to
+pub fn encrypt_attachment(input_path: &Path, output_path: &Path, message_id: u32, chat_id: &str, user: &User, conn: &Connection) -> CommandResult<FileInfo> {
+    encrypt_with_key(input_path, output_path, message_id, &sending_key(message_id, chat_id, user, conn)?)
+}
+
+// Attachments are encrypted right after their message is sent, so the newest key is the one of the current session
+pub fn sending_key(message_id: u32, chat_id: &str, user: &User, conn: &Connection) -> CommandResult<Otherkey> {
+    Ok(read_message_key(MessageKeyType::Sending, message_id, chat_id, user, conn).context("Message key for the file not found, have you sent the message?")?)
+}
+
+pub fn encrypt_with_key(input_path: &Path, output_path: &Path, message_id: u32, message_key: &Otherkey) -> CommandResult<FileInfo> {
+    let mut source = File::open(input_path)?;
+    // Is nonce reuse a problem with the double rachet?
+    let mut output = File::create(output_path)?;
+    let nonce = get_nonce(message_id);
+    encrypt(&nonce, &mut source, &mut output, message_key)?;
+    Ok(FileInfo {
+        filename: input_path.file_name().and_then(|name| name.to_str()).context("Invalid file name")?.to_string(),
+        size: source.metadata()?.len(),
+        nonce,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{self, File}, io::{Write, Read}, path::Path};
+
+    use rand::RngCore;
+
+    use crate::{encryption::get_rng, files::{get_nonce, track_decrypted_file, remove_decrypted_files, encrypt_attachment, encrypt_with_key, decrypt_attachment, prepare_decryption, decrypt_with_keys}, helpers::prepare_database, keybundle::{save_message_key, MessageKeyType}, user::User};
+
+
+    #[test]
+    fn can_encrypt_and_decrypt_file() {
+        let content = [79u8; 5124];
+        let mut key: [u8;32] = [0u8; 32];
+        get_rng().fill_bytes(&mut key);
+        // Drop ownership as soon as possible so we can create new instance for write or read later
+        // We could open with rw options, but then we need to seek to the beginning of the file
+        // This is cleaner and more explicit
+        {
+            let mut to_be_encrypted = File::create("/tmp/enchat-test.txt").unwrap();
+            to_be_encrypted.write_all(&content).unwrap();
+        }
+        let nonce = get_nonce(2);
+        {
+            let mut to_be_encrypted = File::open("/tmp/enchat-test.txt").unwrap();
+            let mut encrypted = File::create("/tmp/enchat-test-encrypted").unwrap();
+            super::encrypt( &nonce, &mut to_be_encrypted, &mut encrypted, &key).unwrap();
+        }
+        {
+            let mut encrypted = File::open("/tmp/enchat-test-encrypted").unwrap();
+            let mut decrypted = File::create("/tmp/enchat-test-decrypted").unwrap();
+            super::decrypt_file( &nonce,  &mut encrypted, &mut decrypted, &key).unwrap();
+        }
+        let mut decrypted = File::open("/tmp/enchat-test-decrypted").unwrap();
+        let mut read = [0u8; 5124];
+        decrypted.read_exact(&mut read).unwrap();
+        assert_eq!(content, read);
+    }
+    #[test]
+    fn decrypted_files_should_be_removed_per_user() {
+        let conn = prepare_database();
+        let alice = User { user_id: Some("alice".to_owned()) };
+        let bob = User { user_id: Some("bob".to_owned()) };
+        let alice_file = Path::new("/tmp/enchat-test-alice-attachment");
+        let bob_file = Path::new("/tmp/enchat-test-bob-attachment");
+        fs::write(alice_file, [1u8; 10000]).unwrap();
+        fs::write(bob_file, [2u8; 10]).unwrap();
+        track_decrypted_file(alice_file, &alice, &conn).unwrap();
+        track_decrypted_file(bob_file, &bob, &conn).unwrap();
+        remove_decrypted_files(&alice, &conn).unwrap();
+        assert!(!alice_file.exists());
+        assert!(bob_file.exists());
+        // Already removed files are skipped
+        fs::remove_file(bob_file).unwrap();
+        remove_decrypted_files(&bob, &conn).unwrap();
+    }
+
+    #[test]
+    fn attachment_should_decrypt_after_the_database_step() {
+        let conn = prepare_database();
+        let alice = User { user_id: Some("alice".to_owned()) };
+        let mut key = [0u8; 32];
+        get_rng().fill_bytes(&mut key);
+        save_message_key(MessageKeyType::Sending, 7, &key, &alice, "chat", &conn);
+        let input = Path::new("/tmp/enchat-test-split-input");
+        let encrypted = Path::new("/tmp/enchat-test-split-encrypted");
+        let output = Path::new("/tmp/enchat-test-split-output");
+        fs::write(input, [5u8; 2000]).unwrap();
+        let info = encrypt_attachment(input, encrypted, 7, "chat", &alice, &conn).unwrap();
+
+        let message_keys = prepare_decryption(false, 7, output, "chat", &alice, &conn).unwrap();
+        drop(conn);
+        decrypt_with_keys(&info, encrypted, output, &message_keys).unwrap();
+        assert_eq!(fs::read(output).unwrap(), fs::read(input).unwrap());
+        for path in [input, encrypted, output] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+    #[test]
+    fn attachment_should_decrypt_with_the_key_of_an_archived_session() {
+        let conn = prepare_database();
+        let bob = User { user_id: Some("bob".to_owned()) };
+        let mut archived = [0u8; 32];
+        let mut current = [0u8; 32];
+        get_rng().fill_bytes(&mut archived);
+        get_rng().fill_bytes(&mut current);
+        // Both sessions of the chat received a message 3, the attachment belongs to the older one
+        save_message_key(MessageKeyType::Receiving, 3, &archived, &bob, "chat", &conn);
+        save_message_key(MessageKeyType::Receiving, 3, &current, &bob, "chat", &conn);
+        let input = Path::new("/tmp/enchat-test-archived-input");
+        let encrypted = Path::new("/tmp/enchat-test-archived-encrypted");
+        let output = Path::new("/tmp/enchat-test-archived-output");
+        fs::write(input, [9u8; 1500]).unwrap();
+        let info = encrypt_with_key(input, encrypted, 3, &archived).unwrap();
+
+        decrypt_attachment(&info, true, 3, encrypted, output, "chat", &bob, &conn).unwrap();
+        assert_eq!(fs::read(output).unwrap(), fs::read(input).unwrap());
+        for path in [input, encrypted, output] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
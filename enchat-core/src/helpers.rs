@@ -0,0 +1,58 @@
+use std::{fs::{self, OpenOptions}, io::{self, Write}, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+#[cfg(test)]
+use crate::chat::ChatState;
+#[cfg(test)]
+use crate::encryption::{PublicKey, ProtocolVersion};
+#[cfg(test)]
+use crate::keybundle::{IdentityKey, ManagedKey};
+#[cfg(test)]
+use crate::user::User;
+
+// Milliseconds since unix epoch, zero if the clock is before it
+pub fn unix_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Overwrites the file before removing it. Only best effort, journaling file systems and SSDs may keep the old blocks
+pub fn shred(path: &Path) -> io::Result<()> {
+    let zeros = [0u8; 8192];
+    let mut remaining = fs::metadata(path)?.len();
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    while remaining > 0 {
+        let chunk = remaining.min(zeros.len() as u64) as usize;
+        file.write_all(&zeros[..chunk])?;
+        remaining -= chunk as u64;
+    }
+    file.sync_all()?;
+    drop(file);
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+pub fn prepare_database() -> rusqlite::Connection {
+    use rusqlite::Connection;
+
+    use crate::store::make_migrations;
+
+    let mut connection = Connection::open_in_memory().unwrap();
+    make_migrations(&mut connection).unwrap();
+    connection
+}
+
+#[cfg(test)]
+pub fn mock_alice_state(bob_keypair: PublicKey) -> (ChatState, User) {
+    let psk = vec![0x02u8; 32];
+    let initial_alice_chat = ChatState::new_sender(&bob_keypair, psk, ProtocolVersion::CURRENT);
+    (initial_alice_chat, User {user_id: Some("1".to_owned())})
+}
+
+#[cfg(test)]
+pub fn mock_bob_state(bob_keypair: IdentityKey, alice_public: &PublicKey) -> (ChatState, User) {
+
+    let psk = vec![0x02u8; 32];
+    let initial_alice_chat = ChatState::new_receiver(bob_keypair.get_keypair().clone(), alice_public, psk, ProtocolVersion::CURRENT);
+    (initial_alice_chat, User {user_id: Some("2".to_owned())})
+}
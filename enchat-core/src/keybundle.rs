@@ -0,0 +1,77 @@
+
+use serde::{Serialize, ser::SerializeStruct};
+use rusqlite::Connection;
+
+mod keys;
+mod signature;
+mod kem;
+
+use crate::{encryption::PublicKey, user::User};
+
+pub use self::{keys::{IdentityKey, StoredKey, SignedKey, ManagedKey, Onetime, save_message_key, read_message_key, read_message_keys, MessageKeyType}, signature::{Signature, calculate_signature, verify_signature}, kem::{KemPrekey, KemPrekeyBundle, SignedKemPrekey, encapsulate}};
+pub struct Prekey(pub SignedKey, pub Signature);
+impl Serialize for Prekey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+            let mut s = serializer.serialize_struct("Prekey", 2)?;
+            s.serialize_field("prekey", &self.0)?;
+            s.serialize_field("signature",&to_base58(self.1))?;
+            s.end()
+    }
+}
+
+pub fn to_base58<K: AsRef<[u8]>>(bytes: K) -> String {
+    bs58::encode(bytes).into_string()
+}
+
+// Public keys are published in base58
+pub fn public_key_from_base58(encoded: &str) -> Option<PublicKey> {
+    let bytes: [u8; 32] = bs58::decode(encoded).into_vec().ok()?.try_into().ok()?;
+    Some(PublicKey::from(bytes))
+}
+
+
+// Onetime keys numbered after the last uploaded one
+pub fn generate_onetime_keys(keys: usize, last_key: usize, conn: &Connection, user: &User) -> rusqlite::Result<Vec<Onetime>> {
+    let mut onetime_keys: Vec<Onetime> = Vec::with_capacity(keys);
+    for i in 0..keys {
+        let onetime = Onetime::generate_id(last_key + 1 + i);
+        onetime.store(conn, user)?;
+        onetime_keys.push(onetime);
+    }
+    Ok(onetime_keys)
+}
+
+pub fn generate_identity_key(conn: &Connection, user: &User) -> rusqlite::Result<IdentityKey> {
+    let id = IdentityKey::generate();
+    id.store(conn, user)?;
+    Ok(id)
+}
+
+pub fn generate_prekey(conn: &Connection, user: &User) -> rusqlite::Result<Prekey> {
+    let identity = IdentityKey::fetch(None, conn, user)?;
+    let prekey = SignedKey::generate();
+    prekey.store(conn, user)?;
+    let signature = prekey.signature(identity.get_keypair());
+    Ok(Prekey(prekey, signature))
+}
+
+pub fn generate_kem_prekey(conn: &Connection, user: &User) -> rusqlite::Result<SignedKemPrekey> {
+    let identity = IdentityKey::fetch(None, conn, user)?;
+    let mut prekey = KemPrekey::generate();
+    prekey.store(conn, user)?;
+    let signature = prekey.signature(identity.get_keypair());
+    Ok(SignedKemPrekey(prekey, signature))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_test;
+    use super::*;
+    #[test]
+    fn convert_to_base58() {
+        let bytes: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
+        assert_eq!(to_base58(bytes), "6h8cQN");
+    }
+}
\ No newline at end of file
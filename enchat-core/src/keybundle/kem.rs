@@ -0,0 +1,141 @@
+use ml_kem::{kem::{Decapsulate, Encapsulate}, Ciphertext, Encoded, EncodedSizeUser, KemCore, MlKem768};
+use rusqlite::{Connection, params, types::Type};
+use serde::{Deserialize, Serialize, ser::SerializeStruct};
+use x25519_dalek::StaticSecret;
+
+use crate::{encryption::{get_rng, PublicKey}, user::User};
+
+use super::{to_base58, signature::{calculate_signature, verify_signature, Signature}};
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+pub type KemSecret = [u8; 32];
+
+// ML-KEM signed prekey, mixed into the initial key agreement next to the X25519 prekey (PQXDH)
+pub struct KemPrekey {
+    key: DecapsulationKey,
+    public: EncapsulationKey,
+    pub id: u32
+}
+
+impl KemPrekey {
+    pub fn generate() -> Self {
+        let (key, public) = MlKem768::generate(&mut get_rng());
+        Self { key, public, id: 0 }
+    }
+    pub fn get_public_key(&self) -> Vec<u8> {
+        self.public.as_bytes().to_vec()
+    }
+    pub fn signature(&self, secret: &StaticSecret) -> Signature {
+        let mut rng = get_rng();
+        calculate_signature(secret, &mut rng, &self.get_public_key())
+    }
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Option<KemSecret> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext).ok()?;
+        let shared = self.key.decapsulate(&ciphertext).ok()?;
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&shared);
+        Some(secret)
+    }
+    // Without id, the latest key is used
+    pub fn fetch(id: Option<u32>, connection: &Connection, user: &User) -> rusqlite::Result<Self> {
+        connection.query_row("SELECT key, public, id FROM kem_prekey WHERE (?1 IS NULL OR id = ?1) AND user_id = ?2 ORDER BY id DESC LIMIT 1", params![id, user.user_id], |row| {
+            let key: Vec<u8> = row.get(0)?;
+            let public: Vec<u8> = row.get(1)?;
+            let key = Encoded::<DecapsulationKey>::try_from(key.as_slice()).map_err(|_| rusqlite::Error::InvalidColumnType(0, "key".to_owned(), Type::Blob))?;
+            let public = Encoded::<EncapsulationKey>::try_from(public.as_slice()).map_err(|_| rusqlite::Error::InvalidColumnType(1, "public".to_owned(), Type::Blob))?;
+            Ok(Self {
+                key: DecapsulationKey::from_bytes(&key),
+                public: EncapsulationKey::from_bytes(&public),
+                id: row.get(2)?
+            })
+        })
+    }
+    pub fn store(&mut self, connection: &Connection, user: &User) -> rusqlite::Result<usize> {
+        let inserted = connection.execute("INSERT INTO kem_prekey(key, public, user_id) VALUES (?, ?, ?)", params![
+            self.key.as_bytes().to_vec(), self.get_public_key(), user.user_id.as_ref().unwrap()
+        ])?;
+        self.id = connection.last_insert_rowid() as u32;
+        Ok(inserted)
+    }
+}
+
+// Returns ciphertext for the receiver and the shared secret, None if the public key is malformed
+pub fn encapsulate(public_key: &[u8]) -> Option<(Vec<u8>, KemSecret)> {
+    let encoded = Encoded::<EncapsulationKey>::try_from(public_key).ok()?;
+    let public = EncapsulationKey::from_bytes(&encoded);
+    let (ciphertext, shared) = public.encapsulate(&mut get_rng()).ok()?;
+    let mut secret = [0u8; 32];
+    secret.copy_from_slice(&shared);
+    Some((ciphertext.to_vec(), secret))
+}
+
+// Post-quantum part of the receiver bundle, missing if the receiver didn't publish one
+#[derive(Deserialize, Serialize, Clone)]
+pub struct KemPrekeyBundle {
+    pub key: Vec<u8>,
+    pub signature: Vec<u8>,
+    pub id: u32,
+}
+
+impl KemPrekeyBundle {
+    pub fn verify(&self, identity: &PublicKey) -> bool {
+        match Signature::try_from(self.signature.as_slice()) {
+            Ok(signature) => verify_signature(identity.as_bytes(), &self.key, &signature),
+            Err(_) => false
+        }
+    }
+}
+
+pub struct SignedKemPrekey(pub KemPrekey, pub Signature);
+
+impl Serialize for SignedKemPrekey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer {
+            let mut s = serializer.serialize_struct("KemPrekey", 3)?;
+            s.serialize_field("prekey", &to_base58(self.0.get_public_key()))?;
+            s.serialize_field("signature", &to_base58(self.1))?;
+            s.serialize_field("id", &self.0.id)?;
+            s.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{helpers::prepare_database, keybundle::{IdentityKey, StoredKey, ManagedKey}, user::User};
+
+    use super::{KemPrekey, KemPrekeyBundle, encapsulate};
+
+    #[test]
+    fn encapsulated_secret_should_match() {
+        let prekey = KemPrekey::generate();
+        let (ciphertext, secret) = encapsulate(&prekey.get_public_key()).unwrap();
+        assert_eq!(prekey.decapsulate(&ciphertext).unwrap(), secret);
+        assert!(encapsulate(&[0u8; 16]).is_none());
+    }
+    #[test]
+    fn kem_prekey_should_be_stored_and_fetched() {
+        let connection = prepare_database();
+        let user = User { user_id: Some("AAAA-GGGG".to_owned()) };
+        let mut first = KemPrekey::generate();
+        first.store(&connection, &user).unwrap();
+        let mut second = KemPrekey::generate();
+        second.store(&connection, &user).unwrap();
+        assert_ne!(first.id, second.id);
+        let latest = KemPrekey::fetch(None, &connection, &user).unwrap();
+        assert_eq!(latest.get_public_key(), second.get_public_key());
+        let fetched = KemPrekey::fetch(Some(first.id), &connection, &user).unwrap();
+        let (ciphertext, secret) = encapsulate(&first.get_public_key()).unwrap();
+        assert_eq!(fetched.decapsulate(&ciphertext).unwrap(), secret);
+    }
+    #[test]
+    fn kem_prekey_signature_should_be_verified() {
+        let identity = IdentityKey::generate();
+        let prekey = KemPrekey::generate();
+        let bundle = KemPrekeyBundle { key: prekey.get_public_key(), signature: prekey.signature(identity.get_keypair()).to_vec(), id: 1 };
+        assert!(bundle.verify(&identity.get_public_key()));
+        assert!(!bundle.verify(&IdentityKey::generate().get_public_key()));
+    }
+}
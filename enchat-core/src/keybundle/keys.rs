@@ -106,6 +106,10 @@ impl Onetime {
         s.id = id;
         s
     }
+    // Zero if no onetime key was generated yet
+    pub fn last_id(connection: &Connection, user: &User) -> rusqlite::Result<usize> {
+        connection.query_row("SELECT IFNULL(MAX(id), 0) FROM onetime WHERE user_id = ?", params![user.user_id], |row| row.get(0))
+    }
 }
 
 impl <'a> StoredKey<'a> for Onetime {
@@ -151,7 +155,7 @@ impl Serialize for IdentityKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
-            serializer.serialize_str(&to_base58(&self.get_public_key().as_bytes()))
+            serializer.serialize_str(&to_base58(self.get_public_key().as_bytes()))
     }
 }
 
@@ -159,7 +163,7 @@ impl Serialize for SignedKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer {
-            serializer.serialize_str(&to_base58(&self.get_public_key().as_bytes()))
+            serializer.serialize_str(&to_base58(self.get_public_key().as_bytes()))
     }
 }
 
@@ -168,7 +172,7 @@ impl Serialize for Onetime {
     where
         S: serde::Serializer {
         let mut s = serializer.serialize_struct("onetime", 2)?;
-        s.serialize_field("key", &to_base58(&self.get_public_key().as_bytes()))?;
+        s.serialize_field("key", &to_base58(self.get_public_key().as_bytes()))?;
         s.serialize_field("id", &self.id)?;
         s.end()
     }
@@ -201,7 +205,7 @@ pub fn save_message_key(message_key_type: MessageKeyType, message_id: u32, key:
 
 pub fn read_message_key(message_key_type: MessageKeyType, message_id: u32, chat_id: &str, user: &User, connection: &Connection) -> Option<Otherkey> {
     let received = matches!(message_key_type, MessageKeyType::Receiving);
-    connection.query_row("SELECT key FROM message_key WHERE received = ? AND local_id = ? AND user_id = ? AND chat_id = ? LIMIT 1", params![
+    connection.query_row("SELECT key FROM message_key WHERE received = ? AND local_id = ? AND user_id = ? AND chat_id = ? ORDER BY id DESC LIMIT 1", params![
         received, message_id, user.user_id, chat_id
     ], |row| {
         let key_vec: Vec<u8> = row.get(0)?;
@@ -209,6 +213,17 @@ pub fn read_message_key(message_key_type: MessageKeyType, message_id: u32, chat_
     }).ok()
 }
 
+// Every session of the chat counts messages from zero, so one id may have several keys. Newest first
+pub fn read_message_keys(message_key_type: MessageKeyType, message_id: u32, chat_id: &str, user: &User, connection: &Connection) -> Vec<Otherkey> {
+    let received = matches!(message_key_type, MessageKeyType::Receiving);
+    let mut statement = connection.prepare("SELECT key FROM message_key WHERE received = ? AND local_id = ? AND user_id = ? AND chat_id = ? ORDER BY id DESC").unwrap();
+    let keys = statement.query_map(params![received, message_id, user.user_id, chat_id], |row| {
+        let key_vec: Vec<u8> = row.get(0)?;
+        Ok(key_vec.try_into().unwrap())
+    });
+    keys.map(|keys| keys.filter_map(Result::ok).collect()).unwrap_or_default()
+}
+
 #[cfg(test)]
 mod test {
 
@@ -223,38 +238,38 @@ mod test {
 
     #[test]
     fn identity_key_should_be_stored_and_fetched() {
-        let mut connection = prepare_database();
+        let connection = prepare_database();
         let user = test_user();
         let key = super::IdentityKey::generate();
-        key.store(&mut connection, &user).unwrap();
-        let key2 = super::IdentityKey::fetch(None, &mut connection, &user).unwrap();
+        key.store(&connection, &user).unwrap();
+        let key2 = super::IdentityKey::fetch(None, &connection, &user).unwrap();
         assert_eq!(key.0.to_bytes(), key2.0.to_bytes());
     }
     #[test]
     fn signed_key_should_be_stored_and_fetch() {
-        let mut connection = prepare_database();
+        let connection = prepare_database();
         let user = test_user();
         let key = super::SignedKey::generate();
-        key.store(&mut connection, &user).unwrap();
-        let key2 = super::SignedKey::fetch(None, &mut connection, &user).unwrap();
+        key.store(&connection, &user).unwrap();
+        let key2 = super::SignedKey::fetch(None, &connection, &user).unwrap();
         assert_eq!(key.0.to_bytes(), key2.0.to_bytes());
     }
     #[test]
     fn onetime_key_should_be_stored_and_fetch() {
-        let mut connection = prepare_database();
+        let connection = prepare_database();
         let user = test_user();
         let key = super::Onetime::generate();
-        key.store(&mut connection, &user).unwrap();
-        let key2 = super::Onetime::fetch(Some(0), &mut connection, &user).unwrap();
+        key.store(&connection, &user).unwrap();
+        let key2 = super::Onetime::fetch(Some(0), &connection, &user).unwrap();
         assert_eq!(key.key.to_bytes(), key2.key.to_bytes());
     }
     #[test]
     fn message_key_should_be_stored_and_fetch() {
-        let mut connection = prepare_database();
+        let connection = prepare_database();
         let user = test_user();
         let key = [0x02u8; 32];
-        super::save_message_key(super::MessageKeyType::Receiving, 0, &key, &user, "AAAA", &mut connection);
-        let key2 = super::read_message_key(super::MessageKeyType::Receiving, 0, "AAAA", &user, &mut connection).unwrap();
+        super::save_message_key(super::MessageKeyType::Receiving, 0, &key, &user, "AAAA", &connection);
+        let key2 = super::read_message_key(super::MessageKeyType::Receiving, 0, "AAAA", &user, &connection).unwrap();
         assert_eq!(key, key2);
     }
 }
\ No newline at end of file
@@ -101,7 +101,7 @@ mod tests {
         let mut rng = get_rng();
         let message = "Hello, world!".as_bytes();
         let signature = super::calculate_signature(&secret_id, &mut rng, message);
-        assert_eq!(super::verify_signature(PublicKey::from(&secret_id).as_bytes(), message, &signature), true);
+        assert!(super::verify_signature(PublicKey::from(&secret_id).as_bytes(), message, &signature));
     }
     #[test]
     fn signature_mismatch_should_throw_error() {
@@ -109,6 +109,6 @@ mod tests {
         let mut rng = get_rng();
         let message = "Hello, world!".as_bytes();
         let signature = super::calculate_signature(&secret_id, &mut rng, message);
-        assert_eq!(super::verify_signature(PublicKey::from(&secret_id).as_bytes(), b"Hello, world", &signature), false);
+        assert!(!super::verify_signature(PublicKey::from(&secret_id).as_bytes(), b"Hello, world", &signature));
     }
 }
\ No newline at end of file
@@ -0,0 +1,18 @@
+// Sessions, key store and file encryption of enchat, the Tauri app only wraps these into commands
+
+pub mod encryption;
+pub mod keybundle;
+pub mod chat;
+pub mod message;
+pub mod store;
+pub mod user;
+pub mod files;
+pub mod errors;
+pub mod padding;
+pub mod passphrase;
+pub mod profile;
+pub mod lock;
+pub mod transport;
+pub mod helpers;
+
+#[macro_use] extern crate log;
@@ -0,0 +1,248 @@
+use std::time::{Duration, Instant};
+
+use argon2::Argon2;
+use rand::RngCore;
+use rusqlite::{Connection, params, OptionalExtension};
+use subtle::ConstantTimeEq;
+
+use crate::{encryption::get_rng, errors::{CommandError, CommandResult}, user::User};
+
+const SALT_LENGTH: usize = 16;
+// A few typos are free, after that every failed attempt doubles the wait
+const FREE_ATTEMPTS: u32 = 3;
+const FIRST_DELAY_MILLIS: u64 = 5_000;
+const MAX_DELAY_MILLIS: u64 = 15 * 60 * 1000;
+
+// Wait before the next attempt is allowed, after the given number of failed ones in a row
+pub fn backoff_millis(failed_attempts: u32) -> u64 {
+    if failed_attempts < FREE_ATTEMPTS {
+        return 0;
+    }
+    let doublings = (failed_attempts - FREE_ATTEMPTS).min(16);
+    (FIRST_DELAY_MILLIS << doublings).min(MAX_DELAY_MILLIS)
+}
+
+fn hash_pin(pin: &str, salt: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    Argon2::default().hash_password_into(pin.as_bytes(), salt, &mut hash).expect("Argon2 failed");
+    hash
+}
+
+// Unlocks the app as usual, or destroys the profile first when the duress passphrase was entered
+#[derive(Debug, PartialEq)]
+pub enum Unlock {
+    Normal,
+    Duress,
+}
+
+fn random_salt() -> [u8; SALT_LENGTH] {
+    let mut salt = [0u8; SALT_LENGTH];
+    get_rng().fill_bytes(&mut salt);
+    salt
+}
+
+// Without a stored hash one is still computed, so the timing doesn't tell whether a duress passphrase is set
+fn matches(pin: &str, salt: Option<&[u8]>, hash: Option<&[u8]>) -> bool {
+    let computed = hash_pin(pin, salt.unwrap_or(&[0u8; SALT_LENGTH]));
+    hash.map_or(false, |hash| computed.ct_eq(hash).into())
+}
+
+// None removes the PIN along with the duress passphrase and the idle timeout, the app can't lock without one
+pub fn set_pin(pin: Option<&str>, connection: &Connection, user: &User) -> CommandResult<()> {
+    let (hash, salt) = match pin {
+        Some(pin) => {
+            let (duress_hash, duress_salt) = fetch_hash(connection, user, "duress")?;
+            if matches(pin, duress_salt.as_deref(), duress_hash.as_deref()) {
+                return Err(CommandError::DuressMatchesPin);
+            }
+            let salt = random_salt();
+            (Some(hash_pin(pin, &salt).to_vec()), Some(salt.to_vec()))
+        }
+        None => (None, None),
+    };
+    connection.execute("INSERT INTO app_lock(user_id, pin_hash, pin_salt) VALUES (?, ?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET pin_hash = excluded.pin_hash, pin_salt = excluded.pin_salt, failed_attempts = 0, retry_after = 0",
+        params![user.user_id, hash, salt])?;
+    if pin.is_none() {
+        set_duress_pin(None, connection, user)?;
+        set_idle_timeout(None, connection, user)?;
+    }
+    Ok(())
+}
+
+// Hash and salt, None while not set
+type StoredHash = (Option<Vec<u8>>, Option<Vec<u8>>);
+
+fn fetch_hash(connection: &Connection, user: &User, prefix: &str) -> rusqlite::Result<StoredHash> {
+    let stored = connection.query_row(&format!("SELECT {0}_hash, {0}_salt FROM app_lock WHERE user_id = ?", prefix), params![user.user_id],
+        |row| Ok((row.get(0)?, row.get(1)?))).optional()?;
+    Ok(stored.unwrap_or((None, None)))
+}
+
+// Only works next to a PIN, and has to differ from it
+pub fn set_duress_pin(duress: Option<&str>, connection: &Connection, user: &User) -> CommandResult<()> {
+    let (hash, salt) = match duress {
+        Some(duress) => {
+            let (Some(pin_hash), pin_salt) = fetch_hash(connection, user, "pin")? else {
+                return Err(CommandError::NoPin);
+            };
+            if matches(duress, pin_salt.as_deref(), Some(&pin_hash)) {
+                return Err(CommandError::DuressMatchesPin);
+            }
+            let salt = random_salt();
+            (Some(hash_pin(duress, &salt).to_vec()), Some(salt.to_vec()))
+        }
+        None => (None, None),
+    };
+    connection.execute("UPDATE app_lock SET duress_hash = ?, duress_salt = ? WHERE user_id = ?", params![hash, salt, user.user_id])?;
+    Ok(())
+}
+
+pub fn has_pin(connection: &Connection, user: &User) -> rusqlite::Result<bool> {
+    Ok(fetch_hash(connection, user, "pin")?.0.is_some())
+}
+
+// Attempts are counted in the profile database, so restarting the app doesn't reset the back-off.
+// The duress passphrase is let through even then, whoever forces the unlock shouldn't have to wait
+pub fn verify_pin(pin: &str, now_millis: u64, connection: &Connection, user: &User) -> CommandResult<Unlock> {
+    let (Some(hash), salt) = fetch_hash(connection, user, "pin")? else {
+        return Err(CommandError::NoPin);
+    };
+    let (duress_hash, duress_salt) = fetch_hash(connection, user, "duress")?;
+    let (failed_attempts, retry_after): (u32, u64) = connection.query_row("SELECT failed_attempts, retry_after FROM app_lock WHERE user_id = ?",
+        params![user.user_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    let normal = matches(pin, salt.as_deref(), Some(&hash));
+    if matches(pin, duress_salt.as_deref(), duress_hash.as_deref()) {
+        return Ok(Unlock::Duress);
+    }
+    if now_millis < retry_after {
+        return Err(CommandError::UnlockBackoff((retry_after - now_millis + 999) / 1000));
+    }
+    if normal {
+        connection.execute("UPDATE app_lock SET failed_attempts = 0, retry_after = 0 WHERE user_id = ?", params![user.user_id])?;
+        return Ok(Unlock::Normal);
+    }
+    let failed_attempts = failed_attempts + 1;
+    connection.execute("UPDATE app_lock SET failed_attempts = ?, retry_after = ? WHERE user_id = ?",
+        params![failed_attempts, now_millis + backoff_millis(failed_attempts), user.user_id])?;
+    Err(CommandError::WrongPin)
+}
+
+pub fn idle_timeout(connection: &Connection, user: &User) -> rusqlite::Result<Option<Duration>> {
+    let seconds: Option<Option<u64>> = connection.query_row("SELECT idle_timeout FROM app_lock WHERE user_id = ?", params![user.user_id], |row| row.get(0)).optional()?;
+    Ok(seconds.flatten().map(Duration::from_secs))
+}
+
+pub fn set_idle_timeout(timeout: Option<Duration>, connection: &Connection, user: &User) -> rusqlite::Result<()> {
+    connection.execute("INSERT INTO app_lock(user_id, idle_timeout) VALUES (?, ?)
+        ON CONFLICT(user_id) DO UPDATE SET idle_timeout = excluded.idle_timeout", params![user.user_id, timeout.map(|timeout| timeout.as_secs())])?;
+    Ok(())
+}
+
+// Time since the user last did something, the app locks once it passes the timeout
+pub struct IdleTimer {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+}
+
+impl Default for IdleTimer {
+    fn default() -> Self {
+        Self { timeout: None, last_activity: Instant::now() }
+    }
+}
+
+impl IdleTimer {
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.activity();
+    }
+    pub fn activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+    pub fn expired(&self, now: Instant) -> bool {
+        self.timeout.map_or(false, |timeout| now.saturating_duration_since(self.last_activity) >= timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::{helpers::prepare_database, user::User, errors::CommandError};
+
+    use super::{backoff_millis, has_pin, set_pin, set_duress_pin, verify_pin, idle_timeout, set_idle_timeout, IdleTimer, Unlock};
+
+    #[test]
+    fn backoff_should_grow_and_stay_capped() {
+        assert_eq!((0..3).map(backoff_millis).collect::<Vec<_>>(), vec![0, 0, 0]);
+        assert_eq!(backoff_millis(3), 5_000);
+        assert_eq!(backoff_millis(4), 10_000);
+        assert_eq!(backoff_millis(100), 15 * 60 * 1000);
+    }
+
+    #[test]
+    fn wrong_pins_should_back_off() {
+        let connection = prepare_database();
+        let user = User { user_id: Some("alice".to_owned()) };
+        assert!(matches!(verify_pin("1234", 0, &connection, &user), Err(CommandError::NoPin)));
+        set_pin(Some("1234"), &connection, &user).unwrap();
+        assert!(has_pin(&connection, &user).unwrap());
+        assert_eq!(verify_pin("1234", 0, &connection, &user).unwrap(), Unlock::Normal);
+
+        for _ in 0..3 {
+            assert!(matches!(verify_pin("0000", 1_000, &connection, &user), Err(CommandError::WrongPin)));
+        }
+        // Even the right PIN is refused until the wait is over
+        assert!(matches!(verify_pin("1234", 2_000, &connection, &user), Err(CommandError::UnlockBackoff(4))));
+        assert!(matches!(verify_pin("0000", 6_000, &connection, &user), Err(CommandError::WrongPin)));
+        assert!(matches!(verify_pin("1234", 15_000, &connection, &user), Err(CommandError::UnlockBackoff(1))));
+        verify_pin("1234", 16_000, &connection, &user).unwrap();
+        assert!(matches!(verify_pin("0000", 16_000, &connection, &user), Err(CommandError::WrongPin)));
+        verify_pin("1234", 16_000, &connection, &user).unwrap();
+
+        set_pin(None, &connection, &user).unwrap();
+        assert!(!has_pin(&connection, &user).unwrap());
+    }
+
+    #[test]
+    fn duress_pin_should_be_told_apart() {
+        let connection = prepare_database();
+        let user = User { user_id: Some("alice".to_owned()) };
+        assert!(matches!(set_duress_pin(Some("9999"), &connection, &user), Err(CommandError::NoPin)));
+        set_pin(Some("1234"), &connection, &user).unwrap();
+        assert!(matches!(set_duress_pin(Some("1234"), &connection, &user), Err(CommandError::DuressMatchesPin)));
+        set_duress_pin(Some("9999"), &connection, &user).unwrap();
+        assert!(matches!(set_pin(Some("9999"), &connection, &user), Err(CommandError::DuressMatchesPin)));
+        assert_eq!(verify_pin("1234", 0, &connection, &user).unwrap(), Unlock::Normal);
+        assert_eq!(verify_pin("9999", 0, &connection, &user).unwrap(), Unlock::Duress);
+
+        for _ in 0..3 {
+            assert!(matches!(verify_pin("0000", 0, &connection, &user), Err(CommandError::WrongPin)));
+        }
+        assert!(matches!(verify_pin("1234", 0, &connection, &user), Err(CommandError::UnlockBackoff(_))));
+        assert_eq!(verify_pin("9999", 0, &connection, &user).unwrap(), Unlock::Duress);
+
+        set_pin(None, &connection, &user).unwrap();
+        set_pin(Some("1234"), &connection, &user).unwrap();
+        assert!(matches!(verify_pin("9999", 0, &connection, &user), Err(CommandError::WrongPin)));
+    }
+
+    #[test]
+    fn idle_timeout_should_be_kept_with_pin() {
+        let connection = prepare_database();
+        let user = User { user_id: Some("alice".to_owned()) };
+        assert_eq!(idle_timeout(&connection, &user).unwrap(), None);
+        set_idle_timeout(Some(Duration::from_secs(300)), &connection, &user).unwrap();
+        set_pin(Some("correct horse"), &connection, &user).unwrap();
+        assert_eq!(idle_timeout(&connection, &user).unwrap(), Some(Duration::from_secs(300)));
+
+        let mut timer = IdleTimer::default();
+        assert!(!timer.expired(Instant::now() + Duration::from_secs(3600)));
+        timer.set_timeout(Some(Duration::from_secs(300)));
+        assert!(!timer.expired(Instant::now()));
+        assert!(timer.expired(Instant::now() + Duration::from_secs(300)));
+
+        set_pin(None, &connection, &user).unwrap();
+        assert_eq!(idle_timeout(&connection, &user).unwrap(), None);
+    }
+}
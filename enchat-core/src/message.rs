@@ -0,0 +1,271 @@
+use rand::RngCore;
+use rusqlite::{Connection, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey};
+
+use crate::{chat::ChatState, encryption::{encrypt, decrypt, get_rng, Otherkey}, user::User, keybundle::{save_message_key, MessageKeyType, read_message_keys}, padding::{Padding, unpad}, helpers::unix_millis};
+
+#[cfg(test)] mod tests;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct InitialData {
+    pub onetime_key_id: Option<u32>,
+    pub ephemeral: PublicKey,
+    pub prekey_id: u32,
+    // Missing for senders from before versioning, skipped so the associated data stays the same
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u8>,
+    // ML-KEM ciphertext, only if the receiver published a post-quantum prekey
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kem_ciphertext: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kem_prekey_id: Option<u32>,
+    // Only if the sender set a chat passphrase, lets the receiver tell a wrong passphrase apart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase_confirmation: Option<[u8; 32]>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Message {
+    // id for receiver chain, so its local to sender
+    pub header: MessageHeader,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+
+pub struct MessageHeader {
+    pub id: u32,
+    pub rachet_key: PublicKey,
+    pub initial: Option<InitialData>,
+    pub previous_receiver_length: u32,
+}
+
+pub type MessageId = [u8; 16];
+
+// Session management messages, they aren't shown to the user
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum ControlMessage {
+    // Sender has archived the session and will start a new one
+    EndSession,
+}
+
+// Chat can't recover without a new session after this many undecryptable messages in a row
+const MAX_DECRYPTION_FAILURES: u32 = 3;
+
+// Encrypted part of the message, header id is only a chain counter so the payload carries its own identity
+#[derive(Deserialize, Serialize, Clone)]
+pub struct MessagePayload {
+    pub id: MessageId,
+    // Sender's clock, milliseconds since unix epoch
+    pub timestamp: u64,
+    pub content: String,
+    pub control: Option<ControlMessage>,
+}
+
+impl MessagePayload {
+    pub fn new(content: String) -> Self {
+        let mut id = [0u8; 16];
+        get_rng().fill_bytes(&mut id);
+        Self { id, timestamp: unix_millis(), content, control: None }
+    }
+    pub fn control(control: ControlMessage) -> Self {
+        Self { control: Some(control), ..Self::new(String::new()) }
+    }
+}
+
+#[derive(Serialize)]
+pub enum Received {
+    Message(MessagePayload),
+    // Message was already processed, chat state is left untouched
+    Duplicate,
+    // Peer started a new session, it has to be entered with this message first
+    NewSession,
+    // Session is missing or out of sync, a new one has to be started
+    ResetRequired,
+}
+
+impl Received {
+    pub fn message(self) -> Option<MessagePayload> {
+        match self {
+            Received::Message(payload) => Some(payload),
+            _ => None
+        }
+    }
+    fn is_end_session(&self) -> bool {
+        matches!(self, Received::Message(MessagePayload { control: Some(ControlMessage::EndSession), .. }))
+    }
+}
+
+fn was_received(header: &MessageHeader, user: &User, chat_id: &str, connection: &Connection) -> bool {
+    connection.query_row("SELECT 1 FROM received_message WHERE rachet_key = ? AND counter = ? AND chat_id = ? AND user_id = ? LIMIT 1", params![
+        header.rachet_key.as_bytes(), header.id, chat_id, user.user_id
+    ], |_row| Ok(())).is_ok()
+}
+
+// Returns false if the message with given id was already received in this chat
+fn mark_received(id: &MessageId, header: &MessageHeader, user: &User, chat_id: &str, connection: &Connection) -> rusqlite::Result<bool> {
+    let inserted = connection.execute("INSERT OR IGNORE INTO received_message(message_id, chat_id, user_id, rachet_key, counter) VALUES (?, ?, ?, ?, ?)", params![
+        id, chat_id, user.user_id, header.rachet_key.as_bytes(), header.id
+    ])?;
+    Ok(inserted == 1)
+}
+
+// Messages from before payloads and padding carry only the text. Their id is taken from the ciphertext,
+// so duplicates are still caught, and the sender's time isn't known
+fn legacy_payload(decoded: &[u8], message: &Message) -> Option<MessagePayload> {
+    // Padded payloads end in zeros, text typed by the user doesn't contain any
+    if decoded.contains(&0) {
+        return None;
+    }
+    let content = String::from_utf8(decoded.to_vec()).ok()?;
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&Sha256::digest(&message.ciphertext)[..16]);
+    Some(MessagePayload { id, timestamp: 0, content, control: None })
+}
+
+fn decrypt_payload(message_key: &Otherkey, message: &Message, chat: &ChatState) -> Option<MessagePayload> {
+    let ad = chat.associated_data(&message.header);
+    let decoded = decrypt(message_key, &message.ciphertext, &ad, chat.version).ok()?;
+    unpad(&decoded).and_then(|payload| bincode::deserialize(payload).ok())
+        .or_else(|| legacy_payload(&decoded, message))
+}
+
+// Moves the receiving chain up to the message, the last key is the message's own
+fn move_to(header: &MessageHeader, trial: &mut ChatState) -> Vec<(u32, Otherkey)> {
+    let mut new_keys = Vec::new();
+    // TODO: Check the math here
+    // Genere decryption keys for out of order messages, they are saved for later usage once the message is verified
+    if header.id > 0 {
+        for x in trial.get_last_received_id()..(header.previous_receiver_length) {
+            new_keys.push((x, trial.move_receiver(None)));
+        }
+        for x in trial.get_last_received_id()..header.id {
+            new_keys.push((x, trial.move_receiver(Some(header.rachet_key))));
+        }
+    }
+    new_keys.push((header.id, trial.move_receiver(Some(header.rachet_key))));
+    new_keys
+}
+
+// Whether the message decrypts on the session, which is left as it is
+pub(crate) fn authenticates(message: &Message, chat: &ChatState) -> bool {
+    let mut trial = chat.clone();
+    let new_keys = move_to(&message.header, &mut trial);
+    new_keys.last().map_or(false, |(_, message_key)| decrypt_payload(message_key, message, &trial).is_some())
+}
+
+fn send_payload(chat_id: &str, payload: MessagePayload, chat: &mut ChatState, user: &User, conn: &Connection) -> Option<Message> {
+    let (rachet_key, message_key, id) = chat.move_sender();
+    chat.save(user, conn, chat_id).expect("Failed to save double rachet state");
+    save_message_key(MessageKeyType::Sending, id, &message_key, user, chat_id, conn);
+    let message_header = MessageHeader {
+        id,
+        rachet_key,
+        initial: chat.receiver_used_keys.clone(),
+        previous_receiver_length: chat.last_previous_sender_id
+    };
+    let ad = chat.associated_data(&message_header);
+    let payload = bincode::serialize(&payload).unwrap();
+    let padding = Padding::fetch(conn, user).unwrap_or_default();
+    let ciphertext = encrypt(&message_key, &padding.pad(&payload), &ad, chat.version);
+    Some(Message {
+        header: message_header,
+        ciphertext
+    })
+}
+
+pub fn send(chat_id: String, message: String, chat: &mut ChatState, user: &User, conn: &Connection) -> Option<Message> {
+    send_payload(&chat_id, MessagePayload::new(message), chat, user, conn)
+}
+
+// Last message of the session, tells the peer to archive it
+pub fn end_session(chat_id: &str, chat: &mut ChatState, user: &User, conn: &Connection) -> Option<Message> {
+    send_payload(chat_id, MessagePayload::control(ControlMessage::EndSession), chat, user, conn)
+}
+
+fn receive_inner(chat_id: String, message: Message, chat: &mut ChatState, user: &User, conn: &Connection) -> Option<Received> {
+    // Relay may deliver the same message more than once, so check before the chain moves
+    if was_received(&message.header, user, &chat_id, conn) {
+        debug!("Dropping duplicate message");
+        return Some(Received::Duplicate);
+    }
+    // Chains are moved on a copy, so a message from another session or a forged one leaves the state untouched
+    let mut trial = chat.clone();
+    let mut new_keys = Vec::new();
+    let candidates = if message.header.id < trial.get_last_received_id() {
+        // Chain already moved past this message, its key was stored as skipped
+        read_message_keys(MessageKeyType::Receiving, message.header.id, &chat_id, user, conn)
+    } else {
+        new_keys = move_to(&message.header, &mut trial);
+        new_keys.last().map(|(_, message_key)| *message_key).into_iter().collect()
+    };
+    let payload = candidates.iter().find_map(|message_key| decrypt_payload(message_key, &message, &trial))?;
+    for (id, message_key) in new_keys {
+        save_message_key(MessageKeyType::Receiving, id, &message_key, user, &chat_id, conn);
+    }
+    trial.decryption_failures = 0;
+    // Peer has the session once we can read its reply, so the X3DH keys don't have to be sent anymore
+    trial.receiver_used_keys = None;
+    *chat = trial;
+    chat.save(user, conn, &chat_id).expect("Failed to save double rachet state");
+    if !mark_received(&payload.id, &message.header, user, &chat_id, conn).expect("Failed to save received message id") {
+        debug!("Dropping duplicate message");
+        return Some(Received::Duplicate);
+    }
+    Some(Received::Message(payload))
+}
+
+// Tries the active session and then the archived ones, counts the failures of the active session
+pub fn receive(chat_id: String, message: Message, chat: &mut Option<ChatState>, user: &User, conn: &Connection) -> Option<Received> {
+    if let Some(active) = chat {
+        if let Some(received) = receive_inner(chat_id.clone(), message.clone(), active, user, conn) {
+            if received.is_end_session() {
+                // Old session stays readable for the messages still in flight
+                active.ended = true;
+                active.archive(user, conn, &chat_id).expect("Failed to archive double rachet state");
+                *chat = None;
+            }
+            return Some(received);
+        }
+    }
+    for mut archived in ChatState::load_archived(user, conn, &chat_id).unwrap_or_default() {
+        let last_received_id = archived.get_last_received_id();
+        if let Some(received) = receive_inner(chat_id.clone(), message.clone(), &mut archived, user, conn) {
+            if received.is_end_session() {
+                archived.ended = true;
+                archived.save(user, conn, &chat_id).expect("Failed to save double rachet state");
+            } else if !archived.ended && archived.get_last_received_id() > last_received_id {
+                // Peer still sends new messages with this session, so we switch to it as well
+                debug!("Promoting archived session of chat {}", chat_id);
+                archived.promote(chat.as_ref(), user, conn, &chat_id).expect("Failed to promote double rachet state");
+                *chat = Some(archived);
+            }
+            return Some(received);
+        }
+    }
+    if message.header.initial.is_some() {
+        return Some(Received::NewSession);
+    }
+    let Some(active) = chat else {
+        return Some(Received::ResetRequired);
+    };
+    active.decryption_failures += 1;
+    active.save(user, conn, &chat_id).expect("Failed to save double rachet state");
+    if active.decryption_failures >= MAX_DECRYPTION_FAILURES {
+        warn!("Session of chat {} is out of sync", chat_id);
+        Some(Received::ResetRequired)
+    } else {
+        None
+    }
+}
+
+// Decrypts a message again from its stored key, without moving any chain
+pub fn try_decrypt(chat_id: &str, received: bool, message: &Message, chat: Option<&ChatState>, user: &User, conn: &Connection) -> Option<MessagePayload> {
+    let message_keys = read_message_keys(MessageKeyType::from_receiving(received), message.header.id, chat_id, user, conn);
+    let archived = ChatState::load_archived(user, conn, chat_id).unwrap_or_default();
+    chat.into_iter().chain(archived.iter()).find_map(|session| {
+        message_keys.iter().find_map(|message_key| decrypt_payload(message_key, message, session))
+    })
+}
@@ -0,0 +1,577 @@
+use crate::{chat::{ChatState, SessionIdentities, ReceiverBundle, accept_session, enter_session}, keybundle::{IdentityKey, StoredKey, ManagedKey, SignedKey, KemPrekey, KemPrekeyBundle}, message::{send, receive_inner, InitialData, receive, end_session, mark_received, ControlMessage, Message, MessageHeader, Received}, encryption::{encrypt, ProtocolVersion}, user::User, helpers::prepare_database, passphrase::stretch, errors::CommandError};
+use crate::helpers::{mock_alice_state, mock_bob_state};
+use crate::padding::Padding;
+
+
+#[test]
+fn bob_should_decrypt_alice_sent_message() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db); 
+    assert!(message.is_some());
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &message.as_ref().unwrap().header.rachet_key);
+    let decrypted = receive_inner("1".to_owned(), message.unwrap(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert!(decrypted.is_some());
+    assert_eq!(decrypted.unwrap().content, "test");
+}
+#[test]
+fn bob_should_decrypt_alice_sent_multiple_message() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let messages: Vec<Message> = (0..10).map(|_| {
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap()
+    }).collect();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &messages[0].header.rachet_key);
+    for message in messages {
+        let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+        assert!(decrypted.is_some());
+        assert_eq!(decrypted.unwrap().content, "test");
+    }
+}
+
+#[test]
+fn bob_should_decrypt_alice_sent_messages_out_of_order() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let messages: Vec<Message> = (0..80).map(|_| {
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap()
+    }).collect();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &messages[0].header.rachet_key);
+    let decrypted = receive_inner("1".to_owned(), messages[6].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert!(decrypted.is_some());
+    assert_eq!(decrypted.unwrap().content, "test");
+    let decrypted = receive_inner("1".to_owned(), messages[8].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert!(decrypted.is_some());
+    assert_eq!(decrypted.unwrap().content, "test");
+    let decrypted = receive_inner("1".to_owned(), messages[11].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert!(decrypted.is_some());
+    assert_eq!(decrypted.unwrap().content, "test");
+    let decrypted = receive_inner("1".to_owned(), messages[50].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert!(decrypted.is_some());
+    assert_eq!(decrypted.unwrap().content, "test");
+    let decrypted = receive_inner("1".to_owned(), messages[79].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert!(decrypted.is_some());
+    assert_eq!(decrypted.unwrap().content, "test");
+}
+#[test]
+pub fn alice_should_pingpong_bob_out_of_order() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let initial_messaege = 
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &initial_messaege.header.rachet_key);
+    receive_inner("1".to_owned(), initial_messaege.clone(), &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    for _ in 0..100 {
+        let messages: Vec<Message> = (0..120).map(|_| {
+            send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap()
+        }).collect();
+        let decrypted = receive_inner("1".to_owned(), messages[29].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+        assert!(decrypted.is_some());
+        assert_eq!(decrypted.unwrap().content, "test");
+        let bob_messages: Vec<Message> = (0..120).map(|_| {
+            send("1".to_owned(), "test".to_owned(), &mut bob_state, &bob_user, &db).unwrap()
+        }).collect();
+        let decrypted = receive_inner("1".to_owned(), bob_messages[29].clone(), &mut alice_state, &alice_user, &db).and_then(Received::message);
+        assert!(decrypted.is_some());
+        assert_eq!(decrypted.unwrap().content, "test");
+        println!("Pass")
+    }
+}
+#[test]
+fn bob_should_receive_alice_message_sent_after_alice_chat_reopen() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let initial_message =
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &initial_message.header.rachet_key);
+    receive_inner("1".to_owned(), initial_message, &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    assert_eq!(bob_state.receiver_chain, alice_state.sender_chain);
+    alice_state = ChatState::load(&alice_user, &db, "1").unwrap();
+    assert_eq!(bob_state.receiver_chain, alice_state.sender_chain);
+    let second_message =
+        send("1".to_owned(), "test2".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let second_decrypted = receive_inner("1".to_owned(), second_message.clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(bob_state.receiver_chain, alice_state.sender_chain);
+    assert_eq!(second_decrypted.unwrap().content, "test2");
+}
+#[test]
+fn messages_should_carry_unique_id_and_timestamp() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let messages: Vec<Message> = (0..2).map(|_| {
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap()
+    }).collect();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &messages[0].header.rachet_key);
+    let first = receive_inner("1".to_owned(), messages[0].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    let second = receive_inner("1".to_owned(), messages[1].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    assert_ne!(first.id, second.id);
+    assert!(first.timestamp > 0);
+    assert!(second.timestamp >= first.timestamp);
+}
+#[test]
+fn message_id_should_be_received_once() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let user = User { user_id: Some("2".to_owned()) };
+    let id = [0x05u8; 16];
+    assert!(mark_received(&id, &message.header, &user, "1", &db).unwrap());
+    assert!(!mark_received(&id, &message.header, &user, "1", &db).unwrap());
+    assert!(mark_received(&id, &message.header, &user, "2", &db).unwrap());
+}
+#[test]
+fn resent_message_should_be_duplicate() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let messages: Vec<Message> = (0..3).map(|_| {
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap()
+    }).collect();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &messages[0].header.rachet_key);
+    for message in &messages {
+        receive_inner("1".to_owned(), message.clone(), &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    }
+    let receiver_chain = bob_state.receiver_chain;
+    let root_chain = bob_state.root_chain;
+    for message in &messages {
+        let resent = receive_inner("1".to_owned(), message.clone(), &mut bob_state, &bob_user, &db);
+        assert!(matches!(resent, Some(Received::Duplicate)));
+    }
+    assert_eq!(bob_state.receiver_chain, receiver_chain);
+    assert_eq!(bob_state.root_chain, root_chain);
+    let next = send("1".to_owned(), "test2".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let decrypted = receive_inner("1".to_owned(), next, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test2");
+}
+#[test]
+fn resent_skipped_message_should_be_duplicate() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let messages: Vec<Message> = (0..5).map(|_| {
+        send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap()
+    }).collect();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &messages[0].header.rachet_key);
+    receive_inner("1".to_owned(), messages[4].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    let receiver_chain = bob_state.receiver_chain;
+    let skipped = receive_inner("1".to_owned(), messages[2].clone(), &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(skipped.unwrap().content, "test");
+    assert_eq!(bob_state.receiver_chain, receiver_chain);
+    let resent = receive_inner("1".to_owned(), messages[2].clone(), &mut bob_state, &bob_user, &db);
+    assert!(matches!(resent, Some(Received::Duplicate)));
+    let resent = receive_inner("1".to_owned(), messages[4].clone(), &mut bob_state, &bob_user, &db);
+    assert!(matches!(resent, Some(Received::Duplicate)));
+    assert_eq!(bob_state.receiver_chain, receiver_chain);
+}
+#[test]
+fn sent_message_should_be_padded_with_account_padding() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    Padding::Buckets.store(&db, &alice_user).unwrap();
+    let short = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let longer = send("1".to_owned(), "a bit longer test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    assert_eq!(short.ciphertext.len(), longer.ciphertext.len());
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &short.header.rachet_key);
+    let decrypted = receive_inner("1".to_owned(), longer, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "a bit longer test");
+}
+#[test]
+fn x3dh_session_should_bind_identities() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let mut bob_state = ChatState::initial_receiver(bob_id.clone(), bob_prekey, message.header.initial.as_ref().unwrap(), &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap();
+    assert_eq!(alice_state.identities, bob_state.identities);
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test");
+    let reply = send("1".to_owned(), "reply".to_owned(), &mut bob_state, &bob_user, &db).unwrap();
+    alice_state = ChatState::load(&alice_user, &db, "1").unwrap();
+    assert_eq!(alice_state.identities, bob_state.identities);
+    let decrypted = receive_inner("1".to_owned(), reply, &mut alice_state, &alice_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "reply");
+}
+#[test]
+fn message_with_other_identities_should_fail() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let identities = SessionIdentities { initiator: IdentityKey::generate().get_public_key(), responder: bob.get_public_key() };
+    alice_state.identities = Some(identities);
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let (mut bob_state, bob_user) = mock_bob_state(bob.clone(), &message.header.rachet_key);
+    bob_state.identities = Some(SessionIdentities { initiator: IdentityKey::generate().get_public_key(), ..identities });
+    assert!(receive_inner("1".to_owned(), message.clone(), &mut bob_state, &bob_user, &db).is_none());
+    let db = prepare_database();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &message.header.rachet_key);
+    bob_state.identities = Some(identities);
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test");
+}
+#[test]
+fn pqxdh_session_should_be_established() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let mut bob_kem = KemPrekey::generate();
+    bob_kem.store(&db, &bob_user).unwrap();
+    let bundle = KemPrekeyBundle { key: bob_kem.get_public_key(), signature: bob_kem.signature(bob_id.get_keypair()).to_vec(), id: bob_kem.id };
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, Some(&bundle), None).unwrap();
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let initial = message.header.initial.as_ref().unwrap();
+    assert!(initial.kem_ciphertext.is_some());
+    assert_eq!(initial.kem_prekey_id, Some(bob_kem.id));
+    let mut bob_state = ChatState::initial_receiver(bob_id, bob_prekey, initial, &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap();
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test");
+}
+#[test]
+fn matching_chat_passphrase_should_establish_session() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let passphrase = stretch("correct horse", "1");
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, Some(&passphrase)).unwrap();
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    assert!(message.header.initial.as_ref().unwrap().passphrase_confirmation.is_some());
+    let mut bob_state = ChatState::initial_receiver(bob_id, bob_prekey, message.header.initial.as_ref().unwrap(), &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, Some(&passphrase)).unwrap();
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test");
+}
+#[test]
+fn different_chat_passphrase_should_be_reported() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let passphrase = stretch("correct horse", "1");
+    let cases = [
+        (Some(passphrase), Some(stretch("battery staple", "1"))),
+        (Some(passphrase), None),
+        (None, Some(passphrase)),
+    ];
+    for (chat_id, (alice_passphrase, bob_passphrase)) in cases.into_iter().enumerate() {
+        let bob_prekey = SignedKey::generate();
+        let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, alice_passphrase.as_ref()).unwrap();
+        let message = send(chat_id.to_string(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+        let bob_state = ChatState::initial_receiver(bob_id.clone(), bob_prekey, message.header.initial.as_ref().unwrap(), &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, bob_passphrase.as_ref());
+        assert!(matches!(bob_state, Err(CommandError::PassphraseMismatch(_))));
+    }
+}
+#[test]
+fn initial_data_without_kem_should_keep_associated_data() {
+    let bob_id = IdentityKey::generate();
+    let alice_state = ChatState::initial_sender(IdentityKey::generate(), IdentityKey::generate(), &bob_id.get_public_key(), &SignedKey::generate().get_public_key(), None, 1, None, None, None).unwrap();
+    let initial = alice_state.receiver_used_keys.clone().unwrap();
+    // ephemeral, prekey id, onetime None tag and the version
+    assert_eq!(bincode::serialize(&initial).unwrap().len(), 1 + 32 + 4 + 2);
+}
+#[test]
+fn failed_message_should_not_move_the_chain() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let first = send("1".to_owned(), "first".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let second = send("1".to_owned(), "second".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let (mut bob_state, bob_user) = mock_bob_state(bob, &first.header.rachet_key);
+    receive_inner("1".to_owned(), first, &mut bob_state, &bob_user, &db).unwrap();
+    let mut forged = second.clone();
+    forged.header.rachet_key = IdentityKey::generate().get_public_key();
+    forged.header.id += 5;
+    assert!(receive_inner("1".to_owned(), forged, &mut bob_state, &bob_user, &db).is_none());
+    assert_eq!(bob_state.get_last_received_id(), 1);
+    let decrypted = receive_inner("1".to_owned(), second, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "second");
+}
+#[test]
+fn repeated_failures_should_require_reset() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let (bob_state, bob_user) = mock_bob_state(bob, &message.header.rachet_key);
+    let mut bob_state = Some(bob_state);
+    let mut corrupted = message.clone();
+    corrupted.ciphertext[0] ^= 0xFF;
+    for failures in 1..=3 {
+        let mut corrupted = corrupted.clone();
+        corrupted.header.id = failures;
+        let received = receive("1".to_owned(), corrupted, &mut bob_state, &bob_user, &db);
+        assert_eq!(matches!(received, Some(Received::ResetRequired)), failures == 3);
+    }
+    let decrypted = receive("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test");
+    assert_eq!(bob_state.unwrap().decryption_failures, 0);
+}
+#[test]
+fn missing_session_should_require_reset() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    send("1".to_owned(), "first".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let message = send("1".to_owned(), "second".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let received = receive("1".to_owned(), message, &mut None, &bob_user, &db);
+    assert!(matches!(received, Some(Received::ResetRequired)));
+}
+#[test]
+fn reset_session_should_keep_old_session_readable() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_prekey = SignedKey::generate();
+    bob_prekey.store(&db, &bob_user).unwrap();
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    let first = send("1".to_owned(), "first".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let late = send("1".to_owned(), "late".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let mut bob_state = Some(ChatState::initial_receiver(bob_id.clone(), SignedKey::fetch(None, &db, &bob_user).unwrap(), first.header.initial.as_ref().unwrap(), &first.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap());
+    receive("1".to_owned(), first, &mut bob_state, &bob_user, &db).unwrap();
+
+    // Alice lost trust in the session and starts over
+    let end = end_session("1", &mut alice_state, &alice_user, &db).unwrap();
+    alice_state.archive(&alice_user, &db, "1").unwrap();
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    let restarted = send("1".to_owned(), "restarted".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+
+    let received = receive("1".to_owned(), end, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(received.unwrap().control, Some(ControlMessage::EndSession));
+    assert!(bob_state.is_none());
+    let received = receive("1".to_owned(), restarted.clone(), &mut bob_state, &bob_user, &db);
+    assert!(matches!(received, Some(Received::NewSession)));
+    bob_state = Some(ChatState::initial_receiver(bob_id, bob_prekey, restarted.header.initial.as_ref().unwrap(), &restarted.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap());
+    let decrypted = receive("1".to_owned(), restarted, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "restarted");
+    let active_session = bob_state.as_ref().unwrap().session_id;
+    let decrypted = receive("1".to_owned(), late, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "late");
+    // Ended session is only read
+    assert_eq!(bob_state.unwrap().session_id, active_session);
+}
+#[test]
+fn archived_session_used_by_peer_should_be_promoted() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let (mut alice_state, alice_user) = mock_alice_state(bob.get_public_key());
+    let first = send("1".to_owned(), "first".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let (bob_state, bob_user) = mock_bob_state(bob.clone(), &first.header.rachet_key);
+    let mut bob_state = Some(bob_state);
+    receive("1".to_owned(), first, &mut bob_state, &bob_user, &db).unwrap();
+    let old_session = bob_state.as_ref().unwrap().session_id;
+
+    // Bob replaced the session, but Alice never learned about it
+    bob_state.as_ref().unwrap().archive(&bob_user, &db, "1").unwrap();
+    let replacement = ChatState::new_sender(&IdentityKey::generate().get_public_key(), vec![0x03u8; 32], ProtocolVersion::CURRENT);
+    replacement.save(&bob_user, &db, "1").unwrap();
+    bob_state = Some(replacement);
+
+    let second = send("1".to_owned(), "second".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let decrypted = receive("1".to_owned(), second, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "second");
+    assert_eq!(bob_state.as_ref().unwrap().session_id, old_session);
+    assert_eq!(ChatState::load(&bob_user, &db, "1").unwrap().session_id, old_session);
+
+    let reply = send("1".to_owned(), "reply".to_owned(), bob_state.as_mut().unwrap(), &bob_user, &db).unwrap();
+    let decrypted = receive_inner("1".to_owned(), reply, &mut alice_state, &alice_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "reply");
+}
+#[test]
+fn simultaneous_initiation_should_settle_on_one_session() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let alice_prekey = SignedKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+
+    // Both fetch the other's bundle and send before anything arrives
+    let alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    let bob_state = ChatState::initial_sender(bob_id.clone(), IdentityKey::generate(), &alice_id.get_public_key(), &alice_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    alice_state.save(&alice_user, &db, "1").unwrap();
+    bob_state.save(&bob_user, &db, "1").unwrap();
+    let mut alice_state = Some(alice_state);
+    let mut bob_state = Some(bob_state);
+    let from_alice = send("1".to_owned(), "hello bob".to_owned(), alice_state.as_mut().unwrap(), &alice_user, &db).unwrap();
+    let from_bob = send("1".to_owned(), "hello alice".to_owned(), bob_state.as_mut().unwrap(), &bob_user, &db).unwrap();
+
+    assert!(matches!(receive("1".to_owned(), from_bob.clone(), &mut alice_state, &alice_user, &db), Some(Received::NewSession)));
+    assert!(matches!(receive("1".to_owned(), from_alice.clone(), &mut bob_state, &bob_user, &db), Some(Received::NewSession)));
+    let incoming = ChatState::initial_receiver(alice_id.clone(), alice_prekey, from_bob.header.initial.as_ref().unwrap(), &from_bob.header.rachet_key, &db, bob_id.get_public_key(), &alice_user, None).unwrap();
+    alice_state = Some(accept_session(incoming, &from_bob, &alice_id.get_public_key(), &alice_user, &db, "1").unwrap());
+    let incoming = ChatState::initial_receiver(bob_id.clone(), bob_prekey, from_alice.header.initial.as_ref().unwrap(), &from_alice.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap();
+    bob_state = Some(accept_session(incoming, &from_alice, &bob_id.get_public_key(), &bob_user, &db, "1").unwrap());
+
+    let decrypted = receive("1".to_owned(), from_bob, &mut alice_state, &alice_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "hello alice");
+    let decrypted = receive("1".to_owned(), from_alice, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "hello bob");
+    let winner = alice_state.as_ref().unwrap().identities;
+    assert_eq!(winner, bob_state.as_ref().unwrap().identities);
+    let lower = std::cmp::min(alice_id.get_public_key().to_bytes(), bob_id.get_public_key().to_bytes());
+    assert_eq!(winner.unwrap().initiator.to_bytes(), lower);
+
+    for round in 0..3 {
+        let message = send("1".to_owned(), format!("alice {}", round), alice_state.as_mut().unwrap(), &alice_user, &db).unwrap();
+        let decrypted = receive("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+        assert_eq!(decrypted.unwrap().content, format!("alice {}", round));
+        let message = send("1".to_owned(), format!("bob {}", round), bob_state.as_mut().unwrap(), &bob_user, &db).unwrap();
+        let decrypted = receive("1".to_owned(), message, &mut alice_state, &alice_user, &db).and_then(Received::message);
+        assert_eq!(decrypted.unwrap().content, format!("bob {}", round));
+    }
+    assert_eq!(alice_state.unwrap().identities, winner);
+    assert_eq!(bob_state.unwrap().identities, winner);
+}
+#[test]
+fn pending_initial_data_should_survive_restart() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    let lost = send("1".to_owned(), "lost".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let mut alice_state = ChatState::load(&alice_user, &db, "1").unwrap();
+    let message = send("1".to_owned(), "after restart".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let initial = message.header.initial.as_ref().unwrap();
+    assert_eq!(initial.ephemeral, lost.header.initial.as_ref().unwrap().ephemeral);
+    // Bob never got the first message, the second one is enough to set up the session
+    let mut bob_state = ChatState::initial_receiver(bob_id, bob_prekey, initial, &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap();
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "after restart");
+}
+#[test]
+fn initial_data_should_be_cleared_after_reply() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let mut alice_state = ChatState::initial_sender(alice_id.clone(), IdentityKey::generate(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None, 1, None, None, None).unwrap();
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let mut bob_state = ChatState::initial_receiver(bob_id, bob_prekey, message.header.initial.as_ref().unwrap(), &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap();
+    receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).unwrap();
+    let reply = send("1".to_owned(), "reply".to_owned(), &mut bob_state, &bob_user, &db).unwrap();
+    assert!(reply.header.initial.is_none());
+    receive_inner("1".to_owned(), reply, &mut alice_state, &alice_user, &db).unwrap();
+    assert!(alice_state.receiver_used_keys.is_none());
+    let mut alice_state = ChatState::load(&alice_user, &db, "1").unwrap();
+    assert!(alice_state.receiver_used_keys.is_none());
+    let message = send("1".to_owned(), "next".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    assert!(message.header.initial.is_none());
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "next");
+}
+#[test]
+fn identity_rachet_session_should_still_be_accepted() {
+    let db = prepare_database();
+    let alice_id = IdentityKey::generate();
+    let bob_id = IdentityKey::generate();
+    let bob_prekey = SignedKey::generate();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    // Session set up the way senders before V3 do it
+    let ephemeral = IdentityKey::generate();
+    let dh = ChatState::dh_sender(ephemeral.get_keypair(), alice_id.get_keypair(), &bob_id.get_public_key(), &bob_prekey.get_public_key(), None);
+    let mut alice_state = ChatState::new_sender(&bob_id.get_public_key(), dh, ProtocolVersion::V2);
+    alice_state.receiver_used_keys = Some(InitialData { onetime_key_id: None, ephemeral: ephemeral.get_public_key(), prekey_id: 1, version: Some(2), kem_ciphertext: None, kem_prekey_id: None, passphrase_confirmation: None });
+    alice_state.identities = Some(SessionIdentities { initiator: alice_id.get_public_key(), responder: bob_id.get_public_key() });
+    let message = send("1".to_owned(), "legacy".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let mut bob_state = ChatState::initial_receiver(bob_id, bob_prekey, message.header.initial.as_ref().unwrap(), &message.header.rachet_key, &db, alice_id.get_public_key(), &bob_user, None).unwrap();
+    assert_eq!(bob_state.version, ProtocolVersion::V2);
+    let decrypted = receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "legacy");
+    let reply = send("1".to_owned(), "reply".to_owned(), &mut bob_state, &bob_user, &db).unwrap();
+    let decrypted = receive_inner("1".to_owned(), reply, &mut alice_state, &alice_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "reply");
+}
+#[test]
+fn published_bundle_should_start_session() {
+    let db = prepare_database();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_id = IdentityKey::generate();
+    alice_id.store(&db, &alice_user).unwrap();
+    IdentityKey::generate().store(&db, &bob_user).unwrap();
+    let bundle = serde_json::to_string(&ReceiverBundle::publish(&db, &bob_user).unwrap()).unwrap();
+    let bundle: ReceiverBundle = serde_json::from_str(&bundle).unwrap();
+    assert_eq!(bundle.receiver_onetime_id, Some(1));
+    let mut alice_state = enter_session("1", None, None, Some(bundle), &alice_user, &db).unwrap().unwrap();
+    let message = send("1".to_owned(), "test".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let message: Message = serde_json::from_str(&serde_json::to_string(&message).unwrap()).unwrap();
+    let mut bob_state = enter_session("1", Some(alice_id.get_public_key()), Some(message.clone()), None, &bob_user, &db).unwrap();
+    let decrypted = receive("1".to_owned(), message, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "test");
+    assert_eq!(ReceiverBundle::publish(&db, &bob_user).unwrap().receiver_onetime_id, Some(2));
+    assert_eq!(ChatState::list_chats(&bob_user, &db).unwrap(), vec!["1".to_owned()]);
+}
+#[test]
+fn forged_initial_message_should_not_replace_session() {
+    let db = prepare_database();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let alice_id = IdentityKey::generate();
+    alice_id.store(&db, &alice_user).unwrap();
+    IdentityKey::generate().store(&db, &bob_user).unwrap();
+    let mut alice_state = enter_session("1", None, None, Some(ReceiverBundle::publish(&db, &bob_user).unwrap()), &alice_user, &db).unwrap().unwrap();
+    let message = send("1".to_owned(), "first".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let bob_state = enter_session("1", Some(alice_id.get_public_key()), Some(message), None, &bob_user, &db).unwrap().unwrap();
+
+    let mut restarted = enter_session("1", None, None, Some(ReceiverBundle::publish(&db, &bob_user).unwrap()), &alice_user, &db).unwrap().unwrap();
+    let genuine = send("1".to_owned(), "restarted".to_owned(), &mut restarted, &alice_user, &db).unwrap();
+    let mut forged = genuine.clone();
+    forged.ciphertext[0] ^= 1;
+    assert!(matches!(enter_session("1", Some(alice_id.get_public_key()), Some(forged), None, &bob_user, &db), Err(CommandError::UnauthenticatedSession)));
+    assert_eq!(ChatState::load(&bob_user, &db, "1").unwrap().session_id, bob_state.session_id);
+    assert!(ChatState::load_archived(&bob_user, &db, "1").unwrap().is_empty());
+
+    let mut bob_state = enter_session("1", Some(alice_id.get_public_key()), Some(genuine.clone()), None, &bob_user, &db).unwrap();
+    let decrypted = receive("1".to_owned(), genuine, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "restarted");
+}
+#[test]
+fn legacy_plaintext_message_should_decrypt() {
+    let db = prepare_database();
+    let bob = IdentityKey::generate();
+    let alice_user = User { user_id: Some("1".to_owned()) };
+    let bob_user = User { user_id: Some("2".to_owned()) };
+    let mut alice_state = ChatState::new_sender(&bob.get_public_key(), vec![0x02u8; 32], ProtocolVersion::V1);
+    // Sent the way it was before message ids and padding, the text is the whole plaintext
+    let (rachet_key, message_key, id) = alice_state.move_sender();
+    let header = MessageHeader { id, rachet_key, initial: None, previous_receiver_length: 0 };
+    let ciphertext = encrypt(&message_key, "from before".as_bytes(), &alice_state.associated_data(&header), ProtocolVersion::V1);
+    let message = Message { header, ciphertext };
+
+    let mut bob_state = ChatState::new_receiver(bob.get_keypair().clone(), &rachet_key, vec![0x02u8; 32], ProtocolVersion::V1);
+    let decrypted = receive_inner("1".to_owned(), message.clone(), &mut bob_state, &bob_user, &db).and_then(Received::message).unwrap();
+    assert_eq!(decrypted.content, "from before");
+    assert_eq!(decrypted.timestamp, 0);
+    assert!(matches!(receive_inner("1".to_owned(), message, &mut bob_state, &bob_user, &db), Some(Received::Duplicate)));
+    assert_eq!(bob_state.decryption_failures, 0);
+
+    let current = send("1".to_owned(), "current".to_owned(), &mut alice_state, &alice_user, &db).unwrap();
+    let decrypted = receive_inner("1".to_owned(), current, &mut bob_state, &bob_user, &db).and_then(Received::message);
+    assert_eq!(decrypted.unwrap().content, "current");
+}
@@ -0,0 +1,150 @@
+use rusqlite::{Connection, params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use crate::user::User;
+
+// Marks the end of the message, everything after it is zero filled
+const PADDING_MARKER: u8 = 0x80;
+const BUCKETS: [usize; 5] = [64, 256, 1024, 4096, 16384];
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum Padding {
+    // Only the marker is appended, message length is visible
+    None,
+    // Padmé, leaks at most O(log log n) bits of the length
+    Padme,
+    // Fixed sizes, larger messages are rounded up to the multiple of the biggest bucket
+    Buckets,
+}
+
+impl Default for Padding {
+    fn default() -> Self {
+        Padding::Padme
+    }
+}
+
+impl Padding {
+    fn from_id(id: u8) -> Self {
+        match id {
+            0 => Padding::None,
+            2 => Padding::Buckets,
+            _ => Padding::Padme,
+        }
+    }
+    fn id(&self) -> u8 {
+        match self {
+            Padding::None => 0,
+            Padding::Padme => 1,
+            Padding::Buckets => 2,
+        }
+    }
+    pub fn padded_length(&self, length: usize) -> usize {
+        match self {
+            Padding::None => length,
+            Padding::Padme => padme(length),
+            Padding::Buckets => {
+                let largest = BUCKETS[BUCKETS.len() - 1];
+                BUCKETS.iter().copied().find(|bucket| *bucket >= length)
+                    .unwrap_or_else(|| (length + largest - 1) / largest * largest)
+            }
+        }
+    }
+    pub fn pad(&self, message: &[u8]) -> Vec<u8> {
+        let length = self.padded_length(message.len() + 1);
+        let mut padded = Vec::with_capacity(length);
+        padded.extend_from_slice(message);
+        padded.push(PADDING_MARKER);
+        padded.resize(length, 0);
+        padded
+    }
+    pub fn fetch(connection: &Connection, user: &User) -> rusqlite::Result<Self> {
+        let id: Option<u8> = connection.query_row("SELECT padding FROM account_settings WHERE user_id = ? LIMIT 1", params![user.user_id], |row| row.get(0)).optional()?;
+        Ok(id.map(Self::from_id).unwrap_or_default())
+    }
+    pub fn store(&self, connection: &Connection, user: &User) -> rusqlite::Result<usize> {
+        connection.execute("INSERT INTO account_settings(user_id, padding) VALUES (?, ?)
+            ON CONFLICT(user_id) DO UPDATE SET padding = excluded.padding", params![user.user_id, self.id()])
+    }
+}
+
+// https://bford.info/pub/net/purb-abs/
+fn padme(length: usize) -> usize {
+    if length < 2 {
+        return length;
+    }
+    let exponent = usize::BITS - 1 - length.leading_zeros();
+    let size_bits = u32::BITS - exponent.leading_zeros();
+    let last_bits = exponent - size_bits;
+    let mask = (1usize << last_bits) - 1;
+    (length + mask) & !mask
+}
+
+// Returns None if the padding is malformed
+pub fn unpad(padded: &[u8]) -> Option<&[u8]> {
+    let marker = padded.iter().rposition(|byte| *byte != 0)?;
+    if padded[marker] != PADDING_MARKER {
+        return None;
+    }
+    Some(&padded[..marker])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{encryption::{encrypt, ProtocolVersion}, helpers::prepare_database, user::User};
+
+    use super::{Padding, unpad, BUCKETS};
+
+    // AES-GCM-SIV tag
+    const TAG_LENGTH: usize = 16;
+
+    #[test]
+    fn padding_should_be_removed() {
+        for padding in [Padding::None, Padding::Padme, Padding::Buckets] {
+            for length in [0, 1, 5, 63, 64, 1000, 20000] {
+                let message = vec![0u8; length];
+                let padded = padding.pad(&message);
+                assert_eq!(unpad(&padded).unwrap(), &message[..]);
+            }
+        }
+    }
+    #[test]
+    fn malformed_padding_should_fail() {
+        assert!(unpad(&[]).is_none());
+        assert!(unpad(&[0u8; 16]).is_none());
+        assert!(unpad(&[0x20u8, 0x00]).is_none());
+    }
+    #[test]
+    fn bucket_ciphertext_should_fall_into_buckets() {
+        let key = [0x20u8; 32];
+        for length in [0, 1, 62, 63, 64, 255, 300, 4000, 16383] {
+            let ciphertext = encrypt(&key, &Padding::Buckets.pad(&vec![0x41u8; length]), b"AD", ProtocolVersion::CURRENT);
+            assert!(BUCKETS.contains(&(ciphertext.len() - TAG_LENGTH)), "{} not in buckets", ciphertext.len());
+        }
+        let ciphertext = encrypt(&key, &Padding::Buckets.pad(&vec![0x41u8; 20000]), b"AD", ProtocolVersion::CURRENT);
+        assert_eq!(ciphertext.len() - TAG_LENGTH, 2 * BUCKETS[BUCKETS.len() - 1]);
+    }
+    #[test]
+    fn padme_ciphertext_should_hide_low_bits() {
+        let key = [0x20u8; 32];
+        let sizes: std::collections::HashSet<usize> = (1000..1100).map(|length| {
+            encrypt(&key, &Padding::Padme.pad(&vec![0x41u8; length]), b"AD", ProtocolVersion::CURRENT).len()
+        }).collect();
+        assert!(sizes.len() <= 4);
+        for size in sizes {
+            assert_eq!((size - TAG_LENGTH) % 32, 0);
+        }
+    }
+    #[test]
+    fn padding_should_be_stored_per_account() {
+        let connection = prepare_database();
+        let alice = User { user_id: Some("1".to_owned()) };
+        let bob = User { user_id: Some("2".to_owned()) };
+        assert_eq!(Padding::fetch(&connection, &alice).unwrap(), Padding::Padme);
+        Padding::Buckets.store(&connection, &alice).unwrap();
+        Padding::None.store(&connection, &bob).unwrap();
+        Padding::Buckets.store(&connection, &bob).unwrap();
+        Padding::None.store(&connection, &bob).unwrap();
+        assert_eq!(Padding::fetch(&connection, &alice).unwrap(), Padding::Buckets);
+        assert_eq!(Padding::fetch(&connection, &bob).unwrap(), Padding::None);
+    }
+}
@@ -0,0 +1,83 @@
+use argon2::Argon2;
+use rusqlite::{Connection, params, OptionalExtension};
+
+use crate::{encryption::{derive, RootKey}, user::User, errors::{CommandResult, CommandError}};
+
+const SALT_PREFIX: &[u8] = b"enchat chat passphrase ";
+const CONFIRMATION_INFO: &[u8] = b"enchat passphrase confirmation";
+
+// Passphrase shared in person, stretched so it can be stored and mixed into X3DH
+pub type PassphraseKey = RootKey;
+
+// Chat id is known to both sides, so it works as the salt
+pub fn stretch(passphrase: &str, chat_id: &str) -> PassphraseKey {
+    let mut salt = SALT_PREFIX.to_vec();
+    salt.extend_from_slice(chat_id.as_bytes());
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase.as_bytes(), &salt, &mut key).expect("Argon2 failed");
+    key
+}
+
+// Key confirmation over the whole X3DH output, so a wrong passphrase isn't mistaken for a corrupted message
+pub fn confirmation(secrets: &[u8]) -> [u8; 32] {
+    derive(None, secrets, CONFIRMATION_INFO).0
+}
+
+pub fn check(sender_confirmation: Option<&[u8; 32]>, our_confirmation: Option<[u8; 32]>) -> CommandResult<()> {
+    match (sender_confirmation, our_confirmation) {
+        (None, None) => Ok(()),
+        (Some(_), None) => Err(CommandError::PassphraseMismatch("the other party set a passphrase for this chat")),
+        (None, Some(_)) => Err(CommandError::PassphraseMismatch("the other party didn't set a passphrase for this chat")),
+        (Some(theirs), Some(ours)) if *theirs == ours => Ok(()),
+        (Some(_), Some(_)) => Err(CommandError::PassphraseMismatch("the passphrase differs from the other party's")),
+    }
+}
+
+pub fn fetch(connection: &Connection, user: &User, chat_id: &str) -> rusqlite::Result<Option<PassphraseKey>> {
+    let key: Option<Vec<u8>> = connection.query_row("SELECT key FROM chat_passphrase WHERE chat_id = ? AND user_id = ? LIMIT 1", params![chat_id, user.user_id], |row| row.get(0)).optional()?;
+    Ok(key.and_then(|key| key.try_into().ok()))
+}
+
+pub fn store(key: Option<&PassphraseKey>, connection: &Connection, user: &User, chat_id: &str) -> rusqlite::Result<usize> {
+    match key {
+        Some(key) => connection.execute("INSERT INTO chat_passphrase(chat_id, user_id, key) VALUES (?, ?, ?)
+            ON CONFLICT(chat_id, user_id) DO UPDATE SET key = excluded.key", params![chat_id, user.user_id, key]),
+        None => connection.execute("DELETE FROM chat_passphrase WHERE chat_id = ? AND user_id = ?", params![chat_id, user.user_id]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{helpers::prepare_database, user::User, errors::CommandError};
+
+    use super::{stretch, check, confirmation, fetch, store};
+
+    #[test]
+    fn stretched_passphrase_should_depend_on_chat() {
+        let key = stretch("correct horse", "chat-1");
+        assert_eq!(key, stretch("correct horse", "chat-1"));
+        assert_ne!(key, stretch("correct horse", "chat-2"));
+        assert_ne!(key, stretch("battery staple", "chat-1"));
+    }
+    #[test]
+    fn passphrase_should_be_stored_per_chat() {
+        let connection = prepare_database();
+        let user = User { user_id: Some("1".to_owned()) };
+        let key = [0x20u8; 32];
+        assert_eq!(fetch(&connection, &user, "1").unwrap(), None);
+        store(Some(&key), &connection, &user, "1").unwrap();
+        assert_eq!(fetch(&connection, &user, "1").unwrap(), Some(key));
+        assert_eq!(fetch(&connection, &user, "2").unwrap(), None);
+        store(None, &connection, &user, "1").unwrap();
+        assert_eq!(fetch(&connection, &user, "1").unwrap(), None);
+    }
+    #[test]
+    fn confirmation_mismatch_should_fail() {
+        let ours = confirmation(&[0x01u8; 32]);
+        assert!(check(None, None).is_ok());
+        assert!(check(Some(&ours), Some(ours)).is_ok());
+        assert!(matches!(check(Some(&confirmation(&[0x02u8; 32])), Some(ours)), Err(CommandError::PassphraseMismatch(_))));
+        assert!(matches!(check(Some(&ours), None), Err(CommandError::PassphraseMismatch(_))));
+        assert!(matches!(check(None, Some(ours)), Err(CommandError::PassphraseMismatch(_))));
+    }
+}
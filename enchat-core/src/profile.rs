@@ -0,0 +1,312 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+use rusqlite::{Connection, DatabaseName, params};
+use zeroize::Zeroizing;
+
+use crate::{errors::CommandResult, files::remove_decrypted_files, helpers::shred, store::{self, Database, DataDir, get_key_keyring, SHARED_DATABASE_ENTRY}, user::User};
+
+// Every table with account data, rows are kept apart by their user_id column
+const ACCOUNT_TABLES: &[&str] = &["identity", "signed", "onetime", "message_key", "rachet_state", "received_message", "account_settings", "kem_prekey", "chat_passphrase", "attachment_file", "app_lock"];
+
+// One account on this device, with a database file and keyring entry of its own
+#[derive(Clone, Debug, PartialEq)]
+pub struct Profile {
+    pub user_id: String,
+    data_dir: DataDir,
+}
+
+// Hex keeps any user id a valid file name, also on case-insensitive file systems
+fn encode_name(user_id: &str) -> String {
+    user_id.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_name(name: &str) -> Option<String> {
+    let bytes = (0..name.len()).step_by(2)
+        .map(|start| name.get(start..start + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+impl Profile {
+    pub fn new(data_dir: &DataDir, user_id: &str) -> Self {
+        Self { user_id: user_id.to_owned(), data_dir: data_dir.clone() }
+    }
+    pub fn user(&self) -> User {
+        User { user_id: Some(self.user_id.clone()) }
+    }
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.profiles_dir().join(format!("{}.db", encode_name(&self.user_id)))
+    }
+    pub fn keyring_entry(&self) -> String {
+        format!("database-{}", encode_name(&self.user_id))
+    }
+    pub fn database_key(&self) -> CommandResult<Zeroizing<String>> {
+        Ok(Database::get_database_key(&self.keyring_entry())?)
+    }
+    pub fn open(&self) -> CommandResult<Database> {
+        self.open_at(None, None)
+    }
+    // Path and key can be overridden for scripting, the profile's own are used otherwise
+    pub fn open_at(&self, path: Option<PathBuf>, key: Option<String>) -> CommandResult<Database> {
+        let own_path = path.is_none();
+        let path = path.unwrap_or_else(|| self.database_path());
+        let key = match key {
+            Some(key) => Zeroizing::new(key),
+            None => self.database_key()?,
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let created = !path.exists();
+        let database = Database::open(&path, &key)?;
+        if own_path && created {
+            self.import_shared(database.get_connection())?;
+        }
+        Ok(database)
+    }
+    // More connections to the profile's own database, opened after open() checked and migrated it
+    pub fn connect(&self, count: usize) -> CommandResult<Vec<Database>> {
+        let key = self.database_key()?;
+        (0..count).map(|_| Database::connect(&self.database_path(), &key)).collect()
+    }
+    fn import_shared(&self, connection: &Connection) -> CommandResult<()> {
+        let shared_path = self.data_dir.shared_database_path();
+        let Some(shared_key) = get_key_keyring(SHARED_DATABASE_ENTRY).filter(|_| shared_path.exists()).map(Zeroizing::new) else {
+            return Ok(());
+        };
+        info!("Moving the account out of the shared database");
+        if import_account(connection, &shared_path, &shared_key, &self.user())? {
+            shred(&shared_path)?;
+            store::get_keyring_entry(SHARED_DATABASE_ENTRY).and_then(|entry| entry.delete_password())?;
+        }
+        Ok(())
+    }
+    // Removes the account from this device: its decrypted attachments, the profile database and its key,
+    // and whatever is left of it in the shared database
+    pub fn delete(&self, database: Option<Database>) -> CommandResult<()> {
+        let user = self.user();
+        let path = self.database_path();
+        let database = match (database, get_key_keyring(&self.keyring_entry()).map(Zeroizing::new)) {
+            (Some(database), _) => Some(database),
+            (None, Some(key)) if path.exists() => Some(Database::open(&path, &key)?),
+            _ => None,
+        };
+        if let Some(database) = database {
+            remove_decrypted_files(&user, database.get_connection())?;
+        }
+        shred_database(&path)?;
+        let shared_path = self.data_dir.shared_database_path();
+        if let Some(shared_key) = get_key_keyring(SHARED_DATABASE_ENTRY).filter(|_| shared_path.exists()).map(Zeroizing::new) {
+            let shared = Database::open(&shared_path, &shared_key)?;
+            remove_decrypted_files(&user, shared.get_connection())?;
+            wipe_account(shared.get_connection(), &user)?;
+        }
+        match store::get_keyring_entry(&self.keyring_entry()).and_then(|entry| entry.delete_password()) {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(error) => Err(error.into()),
+        }
+    }
+    // Destroys the profile and opens an empty one under the same account, with a new database key
+    pub fn reset(&self, database: Database) -> CommandResult<Database> {
+        self.delete(Some(database))?;
+        self.open()
+    }
+    // Profiles are found by their database files, so no list is shared between accounts
+    pub fn list(data_dir: &DataDir) -> io::Result<Vec<Profile>> {
+        let dir = data_dir.profiles_dir();
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut profiles = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |extension| extension != "db") {
+                continue;
+            }
+            if let Some(user_id) = path.file_stem().and_then(|stem| stem.to_str()).and_then(decode_name) {
+                profiles.push(Profile::new(data_dir, &user_id));
+            }
+        }
+        profiles.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+        Ok(profiles)
+    }
+}
+
+// Database with the journals SQLite may leave next to it
+fn database_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![path.to_owned()];
+    for suffix in ["-journal", "-wal", "-shm"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        files.push(PathBuf::from(file));
+    }
+    files
+}
+
+// Snapshots taken before migrations go with it
+fn shred_database(path: &Path) -> io::Result<()> {
+    let snapshots = match path.parent() {
+        Some(parent) if parent.exists() => store::snapshots(path)?,
+        _ => Vec::new(),
+    };
+    for file in database_files(path).into_iter().chain(snapshots) {
+        if file.exists() {
+            shred(&file)?;
+        }
+    }
+    Ok(())
+}
+
+// secure_delete overwrites the freed pages, so the rows can't be read back from the file
+pub fn wipe_account(connection: &Connection, user: &User) -> rusqlite::Result<()> {
+    connection.pragma_update(None, "secure_delete", true)?;
+    let transaction = connection.unchecked_transaction()?;
+    for table in ACCOUNT_TABLES {
+        transaction.execute(&format!("DELETE FROM {} WHERE user_id = ?", table), params![user.user_id])?;
+    }
+    transaction.commit()
+}
+
+// Moves the user's rows out of the database all accounts used to share,
+// returns true once no account is left in it
+pub fn import_account(connection: &Connection, shared_path: &Path, shared_key: &str, user: &User) -> CommandResult<bool> {
+    // Brings the shared database to the same schema, so the columns line up
+    drop(Database::open(shared_path, shared_key)?);
+    connection.execute(&format!("ATTACH DATABASE ? AS shared KEY \"x'{}'\"", shared_key), params![shared_path.to_string_lossy()])?;
+    connection.pragma_update(Some(DatabaseName::Attached("shared")), "secure_delete", true)?;
+    let moved = move_rows(connection, user);
+    let remaining = moved.and_then(|_| ACCOUNT_TABLES.iter().try_fold(0, |count, table| {
+        connection.query_row(&format!("SELECT COUNT(*) FROM shared.{}", table), [], |row| row.get::<_, u32>(0)).map(|rows| count + rows)
+    }));
+    connection.execute("DETACH DATABASE shared", [])?;
+    Ok(remaining? == 0)
+}
+
+fn move_rows(connection: &Connection, user: &User) -> rusqlite::Result<()> {
+    let transaction = connection.unchecked_transaction()?;
+    for table in ACCOUNT_TABLES {
+        transaction.execute(&format!("INSERT INTO main.{0} SELECT * FROM shared.{0} WHERE user_id = ?", table), params![user.user_id])?;
+        transaction.execute(&format!("DELETE FROM shared.{} WHERE user_id = ?", table), params![user.user_id])?;
+    }
+    transaction.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use rusqlite::Connection;
+
+    use crate::{keybundle::{IdentityKey, ManagedKey, StoredKey}, store::{Database, DataDir}, user::User};
+
+    use crate::helpers::prepare_database;
+
+    use super::{Profile, database_files, decode_name, encode_name, import_account, shred_database, wipe_account};
+
+    const KEY: &str = "abababababababababababababababababababababababababababababababab";
+
+    fn temp_database(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("enchat-profile-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn profile_name_should_round_trip() {
+        for user_id in ["alice", "0f8fad5b-d9cb-469f-a165-70867728950e", "../Ünïcode"] {
+            let name = encode_name(user_id);
+            assert!(name.chars().all(|c| c.is_ascii_hexdigit()));
+            assert_eq!(decode_name(&name).as_deref(), Some(user_id));
+        }
+        assert_eq!(decode_name("abc"), None);
+        assert_eq!(decode_name("zz"), None);
+    }
+
+    #[test]
+    fn profiles_should_live_in_the_data_dir() {
+        let path = env::temp_dir().join(format!("enchat-data-dir-{}", std::process::id()));
+        let data_dir = DataDir::at(&path).unwrap();
+        assert!(Profile::list(&data_dir).unwrap().is_empty());
+        let alice = Profile::new(&data_dir, "alice");
+        let identity = IdentityKey::generate();
+        identity.store(alice.open_at(None, Some(KEY.to_owned())).unwrap().get_connection(), &alice.user()).unwrap();
+        assert!(alice.database_path().starts_with(&path));
+        assert_eq!(Profile::list(&data_dir).unwrap(), vec![alice.clone()]);
+        let reopened = alice.open_at(None, Some(KEY.to_owned())).unwrap();
+        assert_eq!(IdentityKey::fetch(None, reopened.get_connection(), &alice.user()).unwrap().get_public_key(), identity.get_public_key());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn account_should_move_out_of_shared_database() {
+        let shared_path = temp_database("shared");
+        let alice = User { user_id: Some("alice".to_owned()) };
+        let bob = User { user_id: Some("bob".to_owned()) };
+        let alice_identity = IdentityKey::generate();
+        {
+            let shared = Database::open(&shared_path, KEY).unwrap();
+            alice_identity.store(shared.get_connection(), &alice).unwrap();
+            IdentityKey::generate().store(shared.get_connection(), &bob).unwrap();
+        }
+        let alice_path = temp_database("alice");
+        let alice_profile = Database::open(&alice_path, &KEY.replace('a', "c")).unwrap();
+        assert!(!import_account(alice_profile.get_connection(), &shared_path, KEY, &alice).unwrap());
+        let imported = IdentityKey::fetch(None, alice_profile.get_connection(), &alice).unwrap();
+        assert_eq!(imported.get_public_key(), alice_identity.get_public_key());
+        assert!(IdentityKey::fetch(None, alice_profile.get_connection(), &bob).is_err());
+
+        let shared = Database::open(&shared_path, KEY).unwrap();
+        assert!(IdentityKey::fetch(None, shared.get_connection(), &alice).is_err());
+        drop(shared);
+        let bob_path = temp_database("bob");
+        let bob_profile = Database::open(&bob_path, KEY).unwrap();
+        assert!(import_account(bob_profile.get_connection(), &shared_path, KEY, &bob).unwrap());
+        for path in [shared_path, alice_path, bob_path] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn wipe_should_only_remove_the_account() {
+        let connection = prepare_database();
+        let alice = User { user_id: Some("alice".to_owned()) };
+        let bob = User { user_id: Some("bob".to_owned()) };
+        IdentityKey::generate().store(&connection, &alice).unwrap();
+        IdentityKey::generate().store(&connection, &bob).unwrap();
+        wipe_account(&connection, &alice).unwrap();
+        assert!(IdentityKey::fetch(None, &connection, &alice).is_err());
+        assert!(IdentityKey::fetch(None, &connection, &bob).is_ok());
+        let secure_delete: bool = connection.query_row("PRAGMA secure_delete", [], |row| row.get(0)).unwrap();
+        assert!(secure_delete);
+    }
+
+    #[test]
+    fn shredded_database_should_leave_no_key_material() {
+        let path = temp_database("shred");
+        let alice = User { user_id: Some("alice".to_owned()) };
+        let identity = IdentityKey::generate();
+        {
+            let database = Database::open(&path, KEY).unwrap();
+            identity.store(database.get_connection(), &alice).unwrap();
+        }
+        // A second link keeps the old blocks reachable, as a copy made before the wipe would
+        let link = temp_database("shred-link");
+        fs::hard_link(&path, &link).unwrap();
+        let length = fs::metadata(&link).unwrap().len();
+        assert!(length > 0);
+
+        let snapshot = path.with_file_name(format!("{}.schema-1.bak", path.file_name().unwrap().to_string_lossy()));
+        fs::copy(&path, &snapshot).unwrap();
+        shred_database(&path).unwrap();
+        assert!(database_files(&path).iter().all(|file| !file.exists()));
+        assert!(!snapshot.exists());
+        let left = fs::read(&link).unwrap();
+        assert_eq!(left.len() as u64, length);
+        assert!(left.iter().all(|byte| *byte == 0));
+        let connection = Connection::open(&link).unwrap();
+        connection.execute_batch(&format!("PRAGMA key = \"x'{}'\";", KEY)).unwrap();
+        assert!(connection.query_row("SELECT COUNT(*) FROM identity", [], |row| row.get::<_, u32>(0)).is_err());
+        drop(connection);
+        fs::remove_file(link).unwrap();
+    }
+}
@@ -0,0 +1,284 @@
+use std::{env, fmt::Display, fs, io, path::{Path, PathBuf}, time::Duration};
+
+use keyring::Entry;
+use rusqlite::{Connection, params};
+
+use crate::{encryption::{KdfOutput, get_rng, derive, DATABASE_INFO}, errors::{CommandError, CommandResult}, helpers::shred};
+use rand::{RngCore};
+use zeroize::Zeroizing;
+
+pub use self::migrations::{make_migrations, schema_version, SchemaReport};
+
+mod migrations;
+pub mod maintenance;
+
+// Where the profiles and the shared database are kept
+#[derive(Clone, Debug, PartialEq)]
+pub struct DataDir(PathBuf);
+
+pub const DATA_DIR_ENV: &str = "ENCHAT_DATA_DIR";
+// Placed next to the binary, keeps everything in a directory beside it, e.g. on a USB stick
+pub const PORTABLE_MARKER: &str = "enchat.portable";
+const PORTABLE_DIR: &str = "enchat-data";
+// The app keeps several connections to a profile, one writing makes the others wait instead of fail
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl DataDir {
+    // An explicit path wins, then the environment, then portable mode, then the user's local app data
+    pub fn resolve(explicit: Option<PathBuf>) -> CommandResult<Self> {
+        let from_env = env::var_os(DATA_DIR_ENV).filter(|path| !path.is_empty()).map(PathBuf::from);
+        let binary_dir = env::current_exe().ok().and_then(|binary| binary.parent().map(Path::to_owned));
+        Self::at(&Self::choose(explicit, from_env, binary_dir)?)
+    }
+    fn choose(explicit: Option<PathBuf>, from_env: Option<PathBuf>, binary_dir: Option<PathBuf>) -> CommandResult<PathBuf> {
+        if let Some(path) = explicit.or(from_env) {
+            return Ok(path);
+        }
+        if let Some(binary_dir) = binary_dir.filter(|dir| dir.join(PORTABLE_MARKER).is_file()) {
+            return Ok(binary_dir.join(PORTABLE_DIR));
+        }
+        dirs::data_local_dir().map(|dir| dir.join("enchat")).ok_or(CommandError::NoDataDir)
+    }
+    // Creates the directory if it's missing
+    pub fn at(path: &Path) -> CommandResult<Self> {
+        let created = fs::create_dir_all(path).and_then(|_| fs::metadata(path));
+        match created {
+            Ok(metadata) if metadata.is_dir() => Ok(Self(path.to_owned())),
+            Ok(_) => Err(CommandError::DataDir(path.to_owned(), io::Error::new(io::ErrorKind::Other, "not a directory"))),
+            Err(error) => Err(CommandError::DataDir(path.to_owned(), error)),
+        }
+    }
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+    // Database all accounts shared before profiles, only opened to move them out
+    pub fn shared_database_path(&self) -> PathBuf {
+        self.0.join("enchat_store.db")
+    }
+    pub fn profiles_dir(&self) -> PathBuf {
+        self.0.join("profiles")
+    }
+}
+
+pub const SHARED_DATABASE_ENTRY: &str = "database";
+
+
+impl Display for KdfOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // writes one hex string, stopping at the first formatter error
+        self.0.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+    }
+}
+pub fn get_keyring_entry(key_type: &str) -> Result<Entry, keyring::Error> {
+    Entry::new("enchat", key_type)
+}
+
+pub fn get_key_keyring(key_type: &str) -> Option<String> {
+    match get_keyring_entry(key_type) {
+        Ok(entry) => entry.get_password().ok(),
+        Err(_) => None,
+    }
+}
+
+pub fn store_key_keyring(key_type: &str, key: &str) -> Result<(), keyring::Error> {
+    get_keyring_entry(key_type)
+        .and_then(move |entry| entry.set_password(key))
+}
+
+fn snapshot_prefix(database: &Path) -> String {
+    format!("{}.schema-", database.file_name().map(|name| name.to_string_lossy()).unwrap_or_default())
+}
+
+// Copies of the database taken before migrations, newest last
+pub fn snapshots(database: &Path) -> io::Result<Vec<PathBuf>> {
+    let dir = match database.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = snapshot_prefix(database);
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        if let Some(version) = name.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(".bak")).and_then(|version| version.parse::<usize>().ok()) {
+            snapshots.push((version, path));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+}
+
+// The file is copied as it is, still encrypted with the same key. Only the latest snapshot is kept
+fn take_snapshot(database: &Path, version: usize) -> io::Result<PathBuf> {
+    let snapshot = database.with_file_name(format!("{}{}.bak", snapshot_prefix(database), version));
+    info!("Saving a snapshot of the schema {} database before migrating it", version);
+    fs::copy(database, &snapshot)?;
+    for old in snapshots(database)? {
+        if old != snapshot {
+            shred(&old)?;
+        }
+    }
+    Ok(snapshot)
+}
+
+pub struct Database {
+    connection: Connection
+}
+
+impl Database {
+    fn generate_database_key() -> KdfOutput {
+        let mut rng = get_rng();
+        let mut random_bytes = vec![0x0; 64];
+        rng.fill_bytes(&mut random_bytes);
+        derive(None, &random_bytes, DATABASE_INFO)
+    }
+    // Kept in the system keyring, generated when the entry is used for the first time
+    pub fn get_database_key(entry: &str) -> Result<Zeroizing<String>, keyring::Error> {
+        if let Some(key) = get_key_keyring(entry) {
+            Ok(Zeroizing::new(key))
+        } else {
+            let key = Zeroizing::new(Self::generate_database_key().to_string());
+            store_key_keyring(entry, &key)?;
+            Ok(key)
+        }
+    }
+    fn keyed_connection(path: &Path, key: &str) -> CommandResult<Connection> {
+        let conn = Connection::open(path)?;
+        conn.query_row(&Zeroizing::new(format!("PRAGMA key = \"x'{}'\"", &key)), params![], |_row| {
+            Ok(())
+        })?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        // Freed pages are overwritten, so deleted message keys and onetime keys don't stay in the file
+        conn.pragma_update(None, "secure_delete", true)?;
+        Ok(conn)
+    }
+    // Checked before migrating, so a damaged file or a wrong key ends up as an error rather than a failed migration
+    pub fn open(path: &Path, key: &str) -> CommandResult<Self> {
+        let mut conn = Self::keyed_connection(path, key)?;
+        let problems = maintenance::quick_check(&conn).map_err(|error| CommandError::DatabaseDamaged(error.to_string()))?;
+        if !problems.is_empty() {
+            return Err(CommandError::DatabaseDamaged(problems.join("; ")));
+        }
+        let schema = schema_version(&conn)?;
+        if schema.needs_migration() {
+            take_snapshot(path, schema.current)?;
+        }
+        make_migrations(&mut conn)?;
+        Ok(Self {
+            connection: conn
+        })
+    }
+    // Another connection to a database opened before, it was checked and migrated then
+    pub fn connect(path: &Path, key: &str) -> CommandResult<Self> {
+        Ok(Self {
+            connection: Self::keyed_connection(path, key)?
+        })
+    }
+    pub fn get_connection(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use crate::errors::CommandError;
+
+    use rusqlite::Connection;
+
+    use super::{Database, DataDir, PORTABLE_MARKER, schema_version, snapshots};
+
+    #[test]
+    fn data_dir_should_follow_precedence() {
+        let binary_dir = env::temp_dir().join(format!("enchat-binary-{}", std::process::id()));
+        fs::create_dir_all(&binary_dir).unwrap();
+        let explicit = PathBuf::from("/explicit");
+        let from_env = PathBuf::from("/from-env");
+        assert_eq!(DataDir::choose(Some(explicit.clone()), Some(from_env.clone()), Some(binary_dir.clone())).unwrap(), explicit);
+        assert_eq!(DataDir::choose(None, Some(from_env.clone()), Some(binary_dir.clone())).unwrap(), from_env);
+        assert_ne!(DataDir::choose(None, None, Some(binary_dir.clone())).ok(), Some(binary_dir.join("enchat-data")));
+        fs::write(binary_dir.join(PORTABLE_MARKER), "").unwrap();
+        assert_eq!(DataDir::choose(None, None, Some(binary_dir.clone())).unwrap(), binary_dir.join("enchat-data"));
+        fs::remove_dir_all(binary_dir).unwrap();
+    }
+
+    #[test]
+    fn unusable_data_dir_should_be_an_error() {
+        let file = env::temp_dir().join(format!("enchat-not-a-dir-{}", std::process::id()));
+        fs::write(&file, "").unwrap();
+        assert!(matches!(DataDir::at(&file), Err(CommandError::DataDir(..))));
+        assert!(matches!(DataDir::at(&file.join("below")), Err(CommandError::DataDir(..))));
+        fs::remove_file(&file).unwrap();
+        let dir = DataDir::at(&file.join("below")).unwrap();
+        assert_eq!(dir.profiles_dir(), file.join("below").join("profiles"));
+        fs::remove_dir_all(file).unwrap();
+    }
+
+    #[test]
+    fn wrong_key_should_be_an_error() {
+        let path = env::temp_dir().join(format!("enchat-wrong-key-{}.db", std::process::id()));
+        drop(Database::open(&path, &"ab".repeat(32)).unwrap());
+        assert!(matches!(Database::open(&path, &"cd".repeat(32)), Err(CommandError::DatabaseDamaged(_))));
+        fs::write(&path, vec![0x42; 8192]).unwrap();
+        assert!(matches!(Database::open(&path, &"ab".repeat(32)), Err(CommandError::DatabaseDamaged(_))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn second_connection_should_share_the_database() {
+        let path = env::temp_dir().join(format!("enchat-second-connection-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let database = Database::open(&path, &"ab".repeat(32)).unwrap();
+        let second = Database::connect(&path, &"ab".repeat(32)).unwrap();
+        database.get_connection().execute("INSERT INTO account_settings(user_id, padding) VALUES ('alice', 1)", []).unwrap();
+        let count: i64 = second.get_connection().query_row("SELECT COUNT(*) FROM account_settings", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+        let wrong_key = Database::connect(&path, &"cd".repeat(32)).unwrap();
+        assert!(wrong_key.get_connection().query_row("SELECT COUNT(*) FROM account_settings", [], |row| row.get::<_, i64>(0)).is_err());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migration_should_leave_an_encrypted_snapshot() {
+        let dir = env::temp_dir().join(format!("enchat-snapshot-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("profile.db");
+        let key = "ab".repeat(32);
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures/migrations/schema_22.sql");
+        {
+            let connection = Connection::open(&path).unwrap();
+            connection.execute_batch(&format!("PRAGMA key = \"x'{}'\";", key)).unwrap();
+            connection.execute_batch(&fs::read_to_string(fixture).unwrap()).unwrap();
+        }
+        let database = Database::open(&path, &key).unwrap();
+        let report = schema_version(database.get_connection()).unwrap();
+        assert_eq!(report.current, report.latest);
+        assert_eq!(snapshots(&path).unwrap(), vec![dir.join("profile.db.schema-22.bak")]);
+
+        // Same key as the database it was taken from, unreadable without it
+        let snapshot = Connection::open(dir.join("profile.db.schema-22.bak")).unwrap();
+        assert!(snapshot.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0)).is_err());
+        snapshot.execute_batch(&format!("PRAGMA key = \"x'{}'\";", key)).unwrap();
+        assert_eq!(snapshot.query_row("PRAGMA user_version", [], |row| row.get::<_, usize>(0)).unwrap(), 22);
+        drop(snapshot);
+
+        // Nothing to migrate, no new snapshot
+        drop(database);
+        drop(Database::open(&path, &key).unwrap());
+        assert_eq!(snapshots(&path).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn database_key_is_64_hex_string() {
+        let key = crate::store::Database::generate_database_key().to_string();
+        assert_eq!(key.len(), 64);
+        for x in key.chars() {
+            // should be able to parse into base 16 number if string is really hex
+            assert!(x.is_ascii_hexdigit())
+        }
+    }
+}
+
@@ -0,0 +1,150 @@
+use rusqlite::Connection;
+use serde::Serialize;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct IntegrityReport {
+    pub ok: bool,
+    // What integrity_check found wrong with the b-trees and indexes
+    pub problems: Vec<String>,
+    // Pages whose HMAC doesn't match, damaged on disk or tampered with
+    pub cipher_problems: Vec<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VacuumReport {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SecureDeleteReport {
+    pub enabled: bool,
+    // Pages freed before it was on, rewritten so their old content is gone
+    pub purged_pages: u64,
+}
+
+fn pragma_rows(connection: &Connection, pragma: &str) -> rusqlite::Result<Vec<String>> {
+    let mut statement = connection.prepare(&format!("PRAGMA {}", pragma))?;
+    let rows = statement.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+fn pragma_number(connection: &Connection, pragma: &str) -> rusqlite::Result<u64> {
+    connection.query_row(&format!("PRAGMA {}", pragma), [], |row| row.get(0))
+}
+
+fn database_size(connection: &Connection) -> rusqlite::Result<u64> {
+    Ok(pragma_number(connection, "page_count")? * pragma_number(connection, "page_size")?)
+}
+
+// integrity_check answers with a single "ok" row when nothing is wrong
+fn without_ok(rows: Vec<String>) -> Vec<String> {
+    rows.into_iter().filter(|row| row != "ok").collect()
+}
+
+// Reads every page, so it's meant to be run on request. A page that can't be decrypted
+// stops integrity_check with an error, which is reported like any other problem
+pub fn check_integrity(connection: &Connection) -> rusqlite::Result<IntegrityReport> {
+    let cipher_problems = pragma_rows(connection, "cipher_integrity_check")?;
+    let problems = match pragma_rows(connection, "integrity_check") {
+        Ok(rows) => without_ok(rows),
+        Err(error) => vec![error.to_string()],
+    };
+    Ok(IntegrityReport { ok: problems.is_empty() && cipher_problems.is_empty(), problems, cipher_problems })
+}
+
+// Cheaper check for every start, skips comparing indexes with their tables
+pub fn quick_check(connection: &Connection) -> rusqlite::Result<Vec<String>> {
+    Ok(without_ok(pragma_rows(connection, "quick_check")?))
+}
+
+pub fn vacuum(connection: &Connection) -> rusqlite::Result<VacuumReport> {
+    let bytes_before = database_size(connection)?;
+    connection.execute_batch("VACUUM")?;
+    Ok(VacuumReport { bytes_before, bytes_after: database_size(connection)? })
+}
+
+// The pragma only lasts for the connection, so it's also set whenever a database is opened
+pub fn enable_secure_delete(connection: &Connection) -> rusqlite::Result<SecureDeleteReport> {
+    connection.pragma_update(None, "secure_delete", true)?;
+    let purged_pages = pragma_number(connection, "freelist_count")?;
+    if purged_pages > 0 {
+        connection.execute_batch("VACUUM")?;
+    }
+    let enabled = connection.query_row("PRAGMA secure_delete", [], |row| row.get(0))?;
+    Ok(SecureDeleteReport { enabled, purged_pages })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use rusqlite::params;
+
+    use crate::store::Database;
+
+    use super::{check_integrity, enable_secure_delete, quick_check, vacuum};
+
+    const KEY: &str = "abababababababababababababababababababababababababababababababab";
+
+    fn temp_database(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("enchat-maintenance-{}-{}.db", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn healthy_database_should_pass_checks() {
+        let path = temp_database("healthy");
+        let database = Database::open(&path, KEY).unwrap();
+        let report = check_integrity(database.get_connection()).unwrap();
+        assert!(report.ok, "{:?}", report);
+        assert!(quick_check(database.get_connection()).unwrap().is_empty());
+        drop(database);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn vacuum_should_give_back_freed_pages() {
+        let path = temp_database("vacuum");
+        let database = Database::open(&path, KEY).unwrap();
+        let connection = database.get_connection();
+        for id in 0..200 {
+            connection.execute("INSERT INTO received_message(message_id, chat_id, user_id) VALUES (?, 'chat', 'alice')", params![vec![id as u8; 512]]).unwrap();
+        }
+        connection.execute("DELETE FROM received_message", []).unwrap();
+        let secure_delete = enable_secure_delete(connection).unwrap();
+        assert!(secure_delete.enabled);
+        assert!(secure_delete.purged_pages > 0);
+        assert_eq!(connection.query_row("PRAGMA freelist_count", [], |row| row.get::<_, u64>(0)).unwrap(), 0);
+
+        let report = vacuum(connection).unwrap();
+        assert_eq!(report.bytes_before, report.bytes_after);
+        drop(database);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn damaged_page_should_be_reported() {
+        let path = temp_database("damaged");
+        {
+            let database = Database::open(&path, KEY).unwrap();
+            let connection = database.get_connection();
+            for id in 0..50 {
+                connection.execute("INSERT INTO received_message(message_id, chat_id, user_id) VALUES (?, 'chat', 'alice')", params![vec![id as u8; 512]]).unwrap();
+            }
+        }
+        let mut bytes = fs::read(&path).unwrap();
+        let last_page = bytes.len() - 100;
+        bytes[last_page] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+
+        let connection = rusqlite::Connection::open(&path).unwrap();
+        connection.execute_batch(&format!("PRAGMA key = \"x'{}'\";", KEY)).unwrap();
+        let report = check_integrity(&connection).unwrap();
+        assert!(!report.ok);
+        assert!(!report.cipher_problems.is_empty());
+        drop(connection);
+        fs::remove_file(path).unwrap();
+    }
+}
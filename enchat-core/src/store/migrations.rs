@@ -0,0 +1,238 @@
+use rusqlite::Connection;
+use rusqlite_migration::{Migrations, M};
+use serde::Serialize;
+
+use crate::errors::{CommandError, CommandResult};
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct SchemaReport {
+    // Number of migrations applied to the database
+    pub current: usize,
+    // What this build migrates to
+    pub latest: usize,
+}
+
+impl SchemaReport {
+    // A new, empty database has nothing worth keeping a snapshot of
+    pub fn needs_migration(&self) -> bool {
+        self.current > 0 && self.current < self.latest
+    }
+}
+
+// rusqlite_migration keeps its version in user_version
+pub fn schema_version(connection: &Connection) -> rusqlite::Result<SchemaReport> {
+    let current: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    Ok(SchemaReport { current, latest: steps().len() })
+}
+
+// A database written by a newer build is left alone, its schema can't be known here
+pub fn make_migrations(connection: &mut Connection) -> CommandResult<()> {
+    let report = schema_version(connection)?;
+    if report.current > report.latest {
+        return Err(CommandError::DatabaseTooNew(report.current, report.latest));
+    }
+    Ok(Migrations::new(steps()).to_latest(connection)?)
+}
+
+fn steps() -> Vec<M<'static>> {
+    vec![
+        M::up("
+        CREATE TABLE identity(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );"),
+        M::up("
+        CREATE TABLE signed(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            key BLOB NOT NULL
+        );
+        "),
+        M::up("
+        CREATE TABLE onetime(
+            id INTEGER NOT NULL,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (id, user_id)
+        );
+        "),
+        M::up("
+        CREATE TABLE message_key(
+            id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+            chat_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            received BOOL DEFAULT FALSE NOT NULL
+        );
+        "),
+        // SQLite can't add a NOT NULL column without a default to a table with rows, so these got defaults
+        // for databases that still have to come through them
+        M::up("ALTER TABLE identity ADD COLUMN user_id TEXT NOT NULL DEFAULT ''; CREATE INDEX identity_user ON identity(user_id);"),
+        M::up("ALTER TABLE signed ADD COLUMN user_id TEXT NOT NULL DEFAULT ''; CREATE INDEX signed_user ON signed(user_id);"),
+        M::up("ALTER TABLE message_key ADD COLUMN user_id TEXT NOT NULL DEFAULT ''; CREATE INDEX message_key_user ON message_key(user_id);"),
+        M::up("ALTER TABLE message_key ADD COLUMN local_id INTEGER NOT NULL DEFAULT 0; CREATE INDEX message_key_local_id ON message_key(local_id);"),
+        M::up("CREATE INDEX message_key_chat_index ON message_key(chat_id)"),
+        M::up("CREATE TABLE rachet_state(
+            chat_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            diffie_public_bytes BLOB NOT NULL,
+            diffie_private_bytes BLOB NOT NULL,
+            root_input_bytes BLOB NOT NULL,
+            root_id INTEGER NOT NULL,
+            sender_input_bytes BLOB NOT NULL,
+            sender_id INTEGER NOT NULL,
+            receiver_input_bytes BLOB NOT NULL,
+            receiver_id INTEGER NOT NULL,
+            PRIMARY KEY (chat_id, user_id)
+        );"),
+        M::up("ALTER TABLE rachet_state ADD COLUMN last_previous_sender_id INTEGER NOT NULL DEFAULT 0;"),
+        M::up("CREATE TABLE received_message(
+            message_id BLOB NOT NULL,
+            chat_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY (message_id, chat_id, user_id)
+        );"),
+        M::up("ALTER TABLE received_message ADD COLUMN rachet_key BLOB;
+        ALTER TABLE received_message ADD COLUMN counter INTEGER;
+        CREATE INDEX received_message_header ON received_message(rachet_key, counter, chat_id, user_id);"),
+        M::up("CREATE TABLE account_settings(
+            user_id TEXT PRIMARY KEY NOT NULL,
+            padding INTEGER NOT NULL
+        );"),
+        // Sessions created before versioning use the original key derivation
+        M::up("ALTER TABLE rachet_state ADD COLUMN protocol_version INTEGER NOT NULL DEFAULT 1;"),
+        M::up("ALTER TABLE rachet_state ADD COLUMN initiator_identity BLOB;
+        ALTER TABLE rachet_state ADD COLUMN responder_identity BLOB;"),
+        M::up("CREATE TABLE kem_prekey(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            public BLOB NOT NULL
+        );
+        CREATE INDEX kem_prekey_user ON kem_prekey(user_id);"),
+        // Several sessions per chat, only the one that isn't archived is used for sending
+        M::up("CREATE TABLE rachet_session(
+            session_id INTEGER PRIMARY KEY NOT NULL,
+            chat_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            diffie_public_bytes BLOB NOT NULL,
+            diffie_private_bytes BLOB NOT NULL,
+            root_input_bytes BLOB NOT NULL,
+            root_id INTEGER NOT NULL,
+            sender_input_bytes BLOB NOT NULL,
+            sender_id INTEGER NOT NULL,
+            receiver_input_bytes BLOB NOT NULL,
+            receiver_id INTEGER NOT NULL,
+            last_previous_sender_id INTEGER NOT NULL,
+            protocol_version INTEGER NOT NULL DEFAULT 1,
+            initiator_identity BLOB,
+            responder_identity BLOB,
+            decryption_failures INTEGER NOT NULL DEFAULT 0,
+            archived_at INTEGER
+        );
+        INSERT INTO rachet_session(chat_id, user_id, diffie_public_bytes, diffie_private_bytes, root_input_bytes, root_id,
+            sender_input_bytes, sender_id, receiver_input_bytes, receiver_id, last_previous_sender_id, protocol_version, initiator_identity, responder_identity)
+        SELECT chat_id, user_id, diffie_public_bytes, diffie_private_bytes, root_input_bytes, root_id,
+            sender_input_bytes, sender_id, receiver_input_bytes, receiver_id, last_previous_sender_id, protocol_version, initiator_identity, responder_identity
+        FROM rachet_state;
+        DROP TABLE rachet_state;
+        ALTER TABLE rachet_session RENAME TO rachet_state;
+        CREATE UNIQUE INDEX rachet_state_active ON rachet_state(chat_id, user_id) WHERE archived_at IS NULL;
+        CREATE INDEX rachet_state_chat ON rachet_state(chat_id, user_id, archived_at);"),
+        M::up("ALTER TABLE rachet_state ADD COLUMN ended BOOL NOT NULL DEFAULT FALSE;"),
+        M::up("ALTER TABLE rachet_state ADD COLUMN pending_initial TEXT;"),
+        M::up("CREATE TABLE chat_passphrase(
+            chat_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (chat_id, user_id)
+        );"),
+        // Decrypted attachments, removed together with the account
+        M::up("CREATE TABLE attachment_file(
+            path TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            PRIMARY KEY (path, user_id)
+        );"),
+        M::up("CREATE TABLE app_lock(
+            user_id TEXT PRIMARY KEY NOT NULL,
+            pin_hash BLOB,
+            pin_salt BLOB,
+            idle_timeout INTEGER,
+            failed_attempts INTEGER NOT NULL DEFAULT 0,
+            retry_after INTEGER NOT NULL DEFAULT 0
+        );"),
+        M::up("ALTER TABLE app_lock ADD COLUMN duress_hash BLOB;
+        ALTER TABLE app_lock ADD COLUMN duress_salt BLOB;"),
+    ]
+}
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use rusqlite::Connection;
+
+    use crate::{errors::CommandError, store::maintenance::quick_check};
+
+    use super::{make_migrations, schema_version, steps};
+
+    // One per earlier migration step, a dump of its schema with two rows in every table
+    pub fn fixtures() -> Vec<(usize, PathBuf)> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fixtures").join("migrations");
+        let mut fixtures: Vec<(usize, PathBuf)> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path())
+            .filter_map(|path| {
+                let version = path.file_stem()?.to_str()?.strip_prefix("schema_")?.parse().ok()?;
+                Some((version, path))
+            })
+            .collect();
+        fixtures.sort();
+        fixtures
+    }
+
+    fn row_count(connection: &Connection, table: &str) -> usize {
+        connection.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| row.get(0)).unwrap()
+    }
+
+    fn tables(connection: &Connection) -> Vec<String> {
+        let mut statement = connection.prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name NOT LIKE 'sqlite_%'").unwrap();
+        let names = statement.query_map([], |row| row.get(0)).unwrap();
+        names.map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn every_earlier_schema_should_have_a_fixture() {
+        let versions: Vec<usize> = fixtures().into_iter().map(|(version, _)| version).collect();
+        assert_eq!(versions, (1..steps().len()).collect::<Vec<_>>(), "add a fixture for the schema before the new migration");
+    }
+
+    #[test]
+    fn fixtures_should_upgrade_without_losing_rows() {
+        for (version, path) in fixtures() {
+            let mut connection = Connection::open_in_memory().unwrap();
+            connection.execute_batch(&fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(schema_version(&connection).unwrap().current, version);
+            let before: Vec<(String, usize)> = tables(&connection).into_iter().map(|table| {
+                let rows = row_count(&connection, &table);
+                (table, rows)
+            }).collect();
+
+            make_migrations(&mut connection).unwrap_or_else(|error| panic!("schema {} didn't upgrade: {}", version, error));
+            let report = schema_version(&connection).unwrap();
+            assert_eq!(report.current, report.latest);
+            assert!(quick_check(&connection).unwrap().is_empty());
+            let after = tables(&connection);
+            for (table, rows) in before.into_iter().filter(|(table, _)| after.contains(table)) {
+                assert_eq!(row_count(&connection, &table), rows, "{} lost rows upgrading from schema {}", table, version);
+            }
+        }
+    }
+
+    #[test]
+    fn newer_database_should_be_refused() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        make_migrations(&mut connection).unwrap();
+        let latest = steps().len();
+        connection.pragma_update(None, "user_version", latest + 1).unwrap();
+        match make_migrations(&mut connection) {
+            Err(CommandError::DatabaseTooNew(found, known)) => assert_eq!((found, known), (latest + 1, latest)),
+            other => panic!("expected a too new error, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::{chat::{self, ChatState, ReceiverBundle}, encryption::PublicKey, errors::{CommandError, CommandResult}, keybundle::{generate_kem_prekey, generate_onetime_keys, generate_prekey, IdentityKey, KemPrekey, KemPrekeyBundle, ManagedKey, Onetime, SignedKemPrekey, SignedKey, StoredKey}, message::{self, Message, Received}, user::User};
+
+mod loopback;
+
+pub use self::loopback::LoopbackTransport;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error("No key bundle published for {0}")]
+    UnknownUser(String),
+    #[error("{0}")]
+    Other(#[from] anyhow::Error)
+}
+
+pub type TransportResult<T> = Result<T, TransportError>;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct OnetimeBundle {
+    pub id: u32,
+    pub key: PublicKey,
+}
+
+// Everything peers need to start sessions with us, each fetch hands out one of the onetime keys
+#[derive(Deserialize, Serialize, Clone)]
+pub struct KeyBundle {
+    pub identity: PublicKey,
+    pub prekey: PublicKey,
+    pub prekey_id: u32,
+    pub prekey_signature: Vec<u8>,
+    pub kem_prekey: Option<KemPrekeyBundle>,
+    pub onetime: Vec<OnetimeBundle>,
+}
+
+impl KeyBundle {
+    // Prekeys are generated only if there are none yet, onetime keys are always new
+    pub fn generate(onetime_keys: usize, conn: &Connection, user: &User) -> rusqlite::Result<Self> {
+        let identity = IdentityKey::fetch(None, conn, user)?;
+        let prekey = match SignedKey::fetch(None, conn, user) {
+            Err(rusqlite::Error::QueryReturnedNoRows) => generate_prekey(conn, user)?.0,
+            prekey => prekey?
+        };
+        let SignedKemPrekey(kem_prekey, kem_signature) = match KemPrekey::fetch(None, conn, user) {
+            Err(rusqlite::Error::QueryReturnedNoRows) => generate_kem_prekey(conn, user)?,
+            kem_prekey => {
+                let kem_prekey = kem_prekey?;
+                let signature = kem_prekey.signature(identity.get_keypair());
+                SignedKemPrekey(kem_prekey, signature)
+            }
+        };
+        let onetime = generate_onetime_keys(onetime_keys, Onetime::last_id(conn, user)?, conn, user)?;
+        Ok(Self {
+            identity: identity.get_public_key(),
+            prekey: prekey.get_public_key(),
+            prekey_id: 1,
+            prekey_signature: prekey.signature(identity.get_keypair()).to_vec(),
+            kem_prekey: Some(KemPrekeyBundle { key: kem_prekey.get_public_key(), signature: kem_signature.to_vec(), id: kem_prekey.id }),
+            onetime: onetime.iter().map(|key| OnetimeBundle { id: key.id as u32, key: key.get_public_key() }).collect(),
+        })
+    }
+    // Relay refuses bundles with prekeys not signed by the identity
+    pub fn verify(&self) -> bool {
+        let prekey_verified = KeyBundle { onetime: Vec::new(), kem_prekey: None, ..self.clone() }.take_receiver_bundle().verify_prekey();
+        prekey_verified && self.kem_prekey.as_ref().map_or(true, |kem| kem.verify(&self.identity))
+    }
+    // Once onetime keys run out, sessions are started without one
+    pub fn take_receiver_bundle(&mut self) -> ReceiverBundle {
+        let onetime = (!self.onetime.is_empty()).then(|| self.onetime.remove(0));
+        ReceiverBundle {
+            receiver_identity: self.identity,
+            receiver_prekey: self.prekey,
+            receiver_onetime: onetime.as_ref().map(|onetime| onetime.key),
+            receiver_onetime_id: onetime.map(|onetime| onetime.id),
+            receiver_prekey_id: self.prekey_id,
+            receiver_kem_prekey: self.kem_prekey.clone(),
+            receiver_prekey_signature: Some(self.prekey_signature.clone()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Envelope {
+    // Assigned by the transport, used to acknowledge the envelope
+    #[serde(default)]
+    pub id: u64,
+    pub chat_id: String,
+    pub sender: String,
+    pub recipient: String,
+    pub message: Message,
+}
+
+// Delivers key bundles and messages between users, envelopes stay in the mailbox until acknowledged
+pub trait Transport {
+    // Replaces the published keys, onetime keys are added to the ones not handed out yet
+    fn publish_bundle(&self, user_id: &str, bundle: KeyBundle) -> TransportResult<()>;
+    fn fetch_identity(&self, user_id: &str) -> TransportResult<PublicKey>;
+    fn fetch_bundle(&self, user_id: &str) -> TransportResult<ReceiverBundle>;
+    fn send(&self, envelope: Envelope) -> TransportResult<()>;
+    fn poll(&self, user_id: &str) -> TransportResult<Vec<Envelope>>;
+    fn acknowledge(&self, user_id: &str, envelope_ids: &[u64]) -> TransportResult<()>;
+}
+
+pub struct Incoming {
+    pub chat_id: String,
+    pub sender: String,
+    // None if no session could decrypt the message
+    pub received: Option<Received>,
+}
+
+// Runs the conversations of one user over a transport
+pub struct Client<'a, T: Transport + ?Sized> {
+    transport: &'a T,
+    conn: &'a Connection,
+    user: &'a User,
+}
+
+impl<'a, T: Transport + ?Sized> Client<'a, T> {
+    pub fn new(transport: &'a T, conn: &'a Connection, user: &'a User) -> Self {
+        Self { transport, conn, user }
+    }
+    fn user_id(&self) -> &str {
+        self.user.user_id.as_deref().unwrap_or_default()
+    }
+    pub fn publish_keys(&self, onetime_keys: usize) -> CommandResult<()> {
+        let bundle = KeyBundle::generate(onetime_keys, self.conn, self.user)?;
+        self.transport.publish_bundle(self.user_id(), bundle)?;
+        Ok(())
+    }
+    // Starts a session from the recipient's bundle if the chat has none
+    pub fn send(&self, chat_id: &str, recipient: &str, text: String) -> CommandResult<()> {
+        let mut chat = match ChatState::load(self.user, self.conn, chat_id) {
+            Ok(chat) => chat,
+            Err(_) => {
+                let bundle = self.transport.fetch_bundle(recipient)?;
+                chat::enter_session(chat_id, None, None, Some(bundle), self.user, self.conn)?.ok_or_else(|| anyhow::anyhow!("Session wasn't started"))?
+            }
+        };
+        let message = message::send(chat_id.to_owned(), text, &mut chat, self.user, self.conn).ok_or_else(|| anyhow::anyhow!("Encryption failed"))?;
+        self.transport.send(Envelope { id: 0, chat_id: chat_id.to_owned(), sender: self.user_id().to_owned(), recipient: recipient.to_owned(), message })?;
+        Ok(())
+    }
+    // Every polled envelope is acknowledged, unreadable ones too, so they don't come back.
+    // An envelope that fails is reported without a message instead of blocking the ones after it
+    pub fn receive(&self) -> CommandResult<Vec<Incoming>> {
+        let mut incoming = Vec::new();
+        for envelope in self.transport.poll(self.user_id())? {
+            let received = self.receive_envelope(&envelope).unwrap_or_else(|error| {
+                warn!("Dropping envelope {} from {}: {}", envelope.id, envelope.sender, error);
+                None
+            });
+            self.transport.acknowledge(self.user_id(), &[envelope.id])?;
+            incoming.push(Incoming { chat_id: envelope.chat_id, sender: envelope.sender, received });
+        }
+        Ok(incoming)
+    }
+    fn receive_envelope(&self, envelope: &Envelope) -> CommandResult<Option<Received>> {
+        let mut chat = ChatState::load(self.user, self.conn, &envelope.chat_id).ok();
+        let received = message::receive(envelope.chat_id.clone(), envelope.message.clone(), &mut chat, self.user, self.conn);
+        if !matches!(received, Some(Received::NewSession)) {
+            return Ok(received);
+        }
+        let sender_identity = self.transport.fetch_identity(&envelope.sender)?;
+        chat = match chat::enter_session(&envelope.chat_id, Some(sender_identity), Some(envelope.message.clone()), None, self.user, self.conn) {
+            Ok(chat) => chat,
+            // Forged or damaged, dropped like any other unreadable message
+            Err(CommandError::UnauthenticatedSession) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        Ok(message::receive(envelope.chat_id.clone(), envelope.message.clone(), &mut chat, self.user, self.conn))
+    }
+}
@@ -0,0 +1,184 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::{chat::ReceiverBundle, encryption::PublicKey};
+
+use super::{Envelope, KeyBundle, Transport, TransportError, TransportResult};
+
+#[derive(Default)]
+struct Mailboxes {
+    bundles: HashMap<String, KeyBundle>,
+    envelopes: HashMap<String, Vec<Envelope>>,
+    last_envelope_id: u64,
+}
+
+// In-process transport, all users share one instance. Meant for tests and local scripting
+#[derive(Default)]
+pub struct LoopbackTransport(Mutex<Mailboxes>);
+
+impl LoopbackTransport {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn publish_bundle(&self, user_id: &str, mut bundle: KeyBundle) -> TransportResult<()> {
+        let mut mailboxes = self.0.lock().unwrap();
+        if let Some(published) = mailboxes.bundles.remove(user_id) {
+            let mut onetime = published.onetime;
+            onetime.append(&mut bundle.onetime);
+            bundle.onetime = onetime;
+        }
+        mailboxes.bundles.insert(user_id.to_owned(), bundle);
+        Ok(())
+    }
+    fn fetch_identity(&self, user_id: &str) -> TransportResult<PublicKey> {
+        let mailboxes = self.0.lock().unwrap();
+        let bundle = mailboxes.bundles.get(user_id).ok_or_else(|| TransportError::UnknownUser(user_id.to_owned()))?;
+        Ok(bundle.identity)
+    }
+    fn fetch_bundle(&self, user_id: &str) -> TransportResult<ReceiverBundle> {
+        let mut mailboxes = self.0.lock().unwrap();
+        let bundle = mailboxes.bundles.get_mut(user_id).ok_or_else(|| TransportError::UnknownUser(user_id.to_owned()))?;
+        Ok(bundle.take_receiver_bundle())
+    }
+    fn send(&self, mut envelope: Envelope) -> TransportResult<()> {
+        let mut mailboxes = self.0.lock().unwrap();
+        mailboxes.last_envelope_id += 1;
+        envelope.id = mailboxes.last_envelope_id;
+        mailboxes.envelopes.entry(envelope.recipient.clone()).or_default().push(envelope);
+        Ok(())
+    }
+    fn poll(&self, user_id: &str) -> TransportResult<Vec<Envelope>> {
+        let mailboxes = self.0.lock().unwrap();
+        Ok(mailboxes.envelopes.get(user_id).cloned().unwrap_or_default())
+    }
+    fn acknowledge(&self, user_id: &str, envelope_ids: &[u64]) -> TransportResult<()> {
+        let mut mailboxes = self.0.lock().unwrap();
+        if let Some(envelopes) = mailboxes.envelopes.get_mut(user_id) {
+            envelopes.retain(|envelope| !envelope_ids.contains(&envelope.id));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{errors::CommandError, helpers::prepare_database, keybundle::{IdentityKey, ManagedKey, StoredKey}, message::Received, transport::{Client, Incoming, KeyBundle, Transport, TransportError}, user::User};
+
+    use super::LoopbackTransport;
+
+    fn texts(incoming: Vec<Incoming>) -> Vec<String> {
+        incoming.into_iter().filter_map(|incoming| incoming.received.and_then(Received::message)).map(|payload| payload.content).collect()
+    }
+
+    #[test]
+    fn users_should_talk_over_loopback() {
+        let transport = LoopbackTransport::new();
+        let alice_db = prepare_database();
+        let bob_db = prepare_database();
+        let alice_user = User { user_id: Some("alice".to_owned()) };
+        let bob_user = User { user_id: Some("bob".to_owned()) };
+        IdentityKey::generate().store(&alice_db, &alice_user).unwrap();
+        IdentityKey::generate().store(&bob_db, &bob_user).unwrap();
+        let alice = Client::new(&transport, &alice_db, &alice_user);
+        let bob = Client::new(&transport, &bob_db, &bob_user);
+        alice.publish_keys(2).unwrap();
+        bob.publish_keys(2).unwrap();
+
+        alice.send("chat", "bob", "hello".to_owned()).unwrap();
+        alice.send("chat", "bob", "are you there?".to_owned()).unwrap();
+        assert_eq!(texts(bob.receive().unwrap()), vec!["hello", "are you there?"]);
+        bob.send("chat", "alice", "hi".to_owned()).unwrap();
+        assert_eq!(texts(alice.receive().unwrap()), vec!["hi"]);
+        alice.send("chat", "bob", "bye".to_owned()).unwrap();
+        let incoming = bob.receive().unwrap();
+        assert_eq!(incoming[0].sender, "alice");
+        assert_eq!(texts(incoming), vec!["bye"]);
+        assert!(bob.receive().unwrap().is_empty());
+    }
+    #[test]
+    fn failed_envelope_should_not_block_the_mailbox() {
+        let transport = LoopbackTransport::new();
+        let alice_db = prepare_database();
+        let bob_db = prepare_database();
+        let stranger_db = prepare_database();
+        let alice_user = User { user_id: Some("alice".to_owned()) };
+        let bob_user = User { user_id: Some("bob".to_owned()) };
+        let stranger_user = User { user_id: Some("stranger".to_owned()) };
+        IdentityKey::generate().store(&alice_db, &alice_user).unwrap();
+        IdentityKey::generate().store(&bob_db, &bob_user).unwrap();
+        IdentityKey::generate().store(&stranger_db, &stranger_user).unwrap();
+        let bob = Client::new(&transport, &bob_db, &bob_user);
+        bob.publish_keys(2).unwrap();
+        Client::new(&transport, &alice_db, &alice_user).publish_keys(2).unwrap();
+
+        // The stranger never published keys, so their identity can't be fetched
+        Client::new(&transport, &stranger_db, &stranger_user).send("other", "bob", "hello".to_owned()).unwrap();
+        Client::new(&transport, &alice_db, &alice_user).send("chat", "bob", "hi".to_owned()).unwrap();
+        let incoming = bob.receive().unwrap();
+        assert_eq!(incoming.len(), 2);
+        assert!(incoming[0].received.is_none());
+        assert_eq!(texts(incoming), vec!["hi"]);
+        assert!(bob.receive().unwrap().is_empty());
+    }
+    #[test]
+    fn other_peer_should_not_take_over_a_chat() {
+        let transport = LoopbackTransport::new();
+        let alice_db = prepare_database();
+        let bob_db = prepare_database();
+        let mallory_db = prepare_database();
+        let alice_user = User { user_id: Some("alice".to_owned()) };
+        let bob_user = User { user_id: Some("bob".to_owned()) };
+        let mallory_user = User { user_id: Some("mallory".to_owned()) };
+        IdentityKey::generate().store(&alice_db, &alice_user).unwrap();
+        IdentityKey::generate().store(&bob_db, &bob_user).unwrap();
+        IdentityKey::generate().store(&mallory_db, &mallory_user).unwrap();
+        let alice = Client::new(&transport, &alice_db, &alice_user);
+        let bob = Client::new(&transport, &bob_db, &bob_user);
+        let mallory = Client::new(&transport, &mallory_db, &mallory_user);
+        alice.publish_keys(2).unwrap();
+        bob.publish_keys(2).unwrap();
+        mallory.publish_keys(2).unwrap();
+
+        alice.send("chat", "bob", "hello".to_owned()).unwrap();
+        assert_eq!(texts(bob.receive().unwrap()), vec!["hello"]);
+        // A valid session, but started by someone else under the id of the Alice and Bob chat
+        mallory.send("chat", "alice", "it's bob".to_owned()).unwrap();
+        let incoming = alice.receive().unwrap();
+        assert_eq!(incoming.len(), 1);
+        assert!(incoming[0].received.is_none());
+        alice.send("chat", "bob", "still you?".to_owned()).unwrap();
+        assert_eq!(texts(bob.receive().unwrap()), vec!["still you?"]);
+        assert!(mallory.receive().unwrap().is_empty());
+    }
+    #[test]
+    fn bundle_fetch_should_hand_out_onetime_keys_once() {
+        let transport = LoopbackTransport::new();
+        let db = prepare_database();
+        let user = User { user_id: Some("bob".to_owned()) };
+        IdentityKey::generate().store(&db, &user).unwrap();
+        let client = Client::new(&transport, &db, &user);
+        client.publish_keys(1).unwrap();
+        client.publish_keys(1).unwrap();
+        assert_eq!(transport.fetch_bundle("bob").unwrap().receiver_onetime_id, Some(1));
+        assert_eq!(transport.fetch_bundle("bob").unwrap().receiver_onetime_id, Some(2));
+        assert_eq!(transport.fetch_bundle("bob").unwrap().receiver_onetime_id, None);
+        assert!(matches!(transport.fetch_bundle("alice"), Err(TransportError::UnknownUser(_))));
+    }
+    #[test]
+    fn forged_prekey_should_be_rejected() {
+        let transport = LoopbackTransport::new();
+        let db = prepare_database();
+        let alice_user = User { user_id: Some("alice".to_owned()) };
+        let bob_user = User { user_id: Some("bob".to_owned()) };
+        IdentityKey::generate().store(&db, &alice_user).unwrap();
+        IdentityKey::generate().store(&db, &bob_user).unwrap();
+        Client::new(&transport, &db, &bob_user).publish_keys(1).unwrap();
+        let mut bundle = KeyBundle::generate(0, &db, &bob_user).unwrap();
+        bundle.prekey = IdentityKey::generate().get_public_key();
+        transport.publish_bundle("bob", bundle).unwrap();
+        let sent = Client::new(&transport, &db, &alice_user).send("chat", "bob", "hello".to_owned());
+        assert!(matches!(sent, Err(CommandError::Other(_))));
+    }
+}
@@ -0,0 +1,4 @@
+#[derive(Default, Clone)]
+pub struct User {
+    pub user_id: Option<String>
+}
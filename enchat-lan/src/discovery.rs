@@ -0,0 +1,46 @@
+use std::{io, net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket}, time::Duration};
+
+use enchat_core::encryption::PublicKey;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+// Organization-local scope, routers don't pass it outside the network
+pub const DEFAULT_DISCOVERY: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), 47100);
+
+// Anyone can announce anything, the identity is only trusted once a handshake proves it
+#[derive(Deserialize, Serialize)]
+pub struct Announcement {
+    pub user_id: String,
+    pub identity: PublicKey,
+    pub port: u16,
+}
+
+pub struct Discovery {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+}
+
+impl Discovery {
+    // All peers on a host bind the group port, so the address has to be reusable
+    pub fn join(group: SocketAddrV4, interface: Ipv4Addr, read_timeout: Duration) -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &interface)?;
+        socket.set_multicast_if_v4(&interface)?;
+        // Other peers may run on this host as well
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_read_timeout(Some(read_timeout))?;
+        Ok(Self { socket: socket.into(), group })
+    }
+    pub fn announce(&self, announcement: &Announcement) -> io::Result<()> {
+        self.socket.send_to(&serde_json::to_vec(announcement)?, self.group)?;
+        Ok(())
+    }
+    // None on timeout and for datagrams that aren't announcements
+    pub fn receive(&self) -> Option<(Announcement, IpAddr)> {
+        let mut buffer = [0u8; 2048];
+        let (length, source) = self.socket.recv_from(&mut buffer).ok()?;
+        Some((serde_json::from_slice(&buffer[..length]).ok()?, source.ip()))
+    }
+}
@@ -0,0 +1,10 @@
+// Peers on the same network find each other by multicast announcements and talk over direct TCP
+// connections, so no server is needed. Every connection starts with a handshake signed by the identity keys
+
+mod discovery;
+mod peer;
+mod wire;
+
+pub use self::{discovery::DEFAULT_DISCOVERY, peer::{LanConfig, LanTransport}};
+
+#[macro_use] extern crate log;
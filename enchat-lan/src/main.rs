@@ -0,0 +1,131 @@
+use std::{io::BufRead, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, path::PathBuf, sync::mpsc::{self, RecvTimeoutError}, thread, time::Duration};
+
+use anyhow::Context;
+use clap::Parser;
+use enchat_core::{keybundle::{generate_identity_key, IdentityKey, StoredKey}, message::Received, profile::Profile, store::DataDir, transport::Client};
+use enchat_lan::{LanConfig, LanTransport, DEFAULT_DISCOVERY};
+use serde::Serialize;
+
+#[macro_use] extern crate log;
+
+// Reads "<recipient> <chat id> <text>" lines from stdin and prints events as JSON lines
+#[derive(Parser)]
+#[command(name = "enchat-lan", about = "Chat with enchat peers on the local network, without a server")]
+struct Args {
+    /// Account the keys and sessions belong to
+    #[arg(long, env = "ENCHAT_USER")]
+    user: String,
+    /// Where profiles are kept, next to the binary in portable mode and in the local app data otherwise
+    #[arg(long, env = "ENCHAT_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Defaults to the account's profile database
+    #[arg(long, env = "ENCHAT_DATABASE")]
+    database: Option<PathBuf>,
+    /// Database key in hex, taken from the profile's keyring entry if not set
+    #[arg(long, env = "ENCHAT_DATABASE_KEY", hide_env_values = true)]
+    database_key: Option<String>,
+    /// Address to accept peers on
+    #[arg(long, default_value = "0.0.0.0:0")]
+    listen: SocketAddr,
+    /// Multicast group and port of the announcements
+    #[arg(long, default_value_t = DEFAULT_DISCOVERY)]
+    discovery: SocketAddrV4,
+    /// Interface to announce on, 127.0.0.1 keeps it on this host
+    #[arg(long, default_value_t = Ipv4Addr::UNSPECIFIED)]
+    interface: Ipv4Addr,
+    /// Peers to connect to directly, for networks without multicast
+    #[arg(long)]
+    peer: Vec<SocketAddr>,
+    #[arg(long, default_value_t = 20)]
+    onetime_keys: usize,
+    /// How long to wait for a recipient to show up
+    #[arg(long, default_value_t = 10)]
+    wait_seconds: u64,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Listening { address: SocketAddr },
+    Sent { recipient: String, chat_id: String },
+    Received { sender: String, chat_id: String, content: String },
+    Error { message: String },
+}
+
+fn print_event(event: &Event) {
+    println!("{}", serde_json::to_string(event).unwrap());
+}
+
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    let args = Args::parse();
+    let data_dir = DataDir::resolve(args.data_dir)?;
+    let profile = Profile::new(&data_dir, &args.user);
+    let database = profile.open_at(args.database, args.database_key)?;
+    let conn = database.get_connection();
+    let user = profile.user();
+    if IdentityKey::fetch(None, conn, &user).is_err() {
+        generate_identity_key(conn, &user)?;
+    }
+    let config = LanConfig { listen: args.listen, discovery: Some(args.discovery), interface: args.interface, ..LanConfig::default() };
+    let transport = LanTransport::start(conn, &user, config).context("Can't start the LAN transport")?;
+    let client = Client::new(&transport, conn, &user);
+    client.publish_keys(args.onetime_keys)?;
+    for peer in args.peer {
+        match transport.add_peer(peer) {
+            Ok(user_id) => info!("Connected to {} at {}", user_id, peer),
+            Err(error) => warn!("Can't connect to {}: {}", peer, error),
+        }
+    }
+    print_event(&Event::Listening { address: transport.local_addr() });
+
+    // Connection can't be shared with the reading thread, so lines are passed over
+    let (lines, input) = mpsc::channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if lines.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let mut input_closed = false;
+    loop {
+        if input_closed {
+            thread::sleep(Duration::from_millis(200));
+        } else {
+            match input.recv_timeout(Duration::from_millis(200)) {
+                Ok(line) => send_line(&line, &transport, &client, Duration::from_secs(args.wait_seconds)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => input_closed = true,
+            }
+        }
+        match client.receive() {
+            Ok(incoming) => for incoming in incoming {
+                match incoming.received {
+                    Some(Received::Message(payload)) if payload.control.is_none() => {
+                        print_event(&Event::Received { sender: incoming.sender, chat_id: incoming.chat_id, content: payload.content });
+                    }
+                    Some(_) => {}
+                    None => print_event(&Event::Error { message: format!("Can't decrypt a message from {}", incoming.sender) }),
+                }
+            },
+            Err(error) => print_event(&Event::Error { message: error.to_string() }),
+        }
+    }
+}
+
+fn send_line(line: &str, transport: &LanTransport, client: &Client<LanTransport>, wait: Duration) {
+    let mut parts = line.trim().splitn(3, ' ');
+    let (Some(recipient), Some(chat_id), Some(text)) = (parts.next(), parts.next(), parts.next()) else {
+        print_event(&Event::Error { message: "Expected \"<recipient> <chat id> <text>\"".to_owned() });
+        return;
+    };
+    if !transport.wait_for_peer(recipient, wait) {
+        print_event(&Event::Error { message: format!("{} isn't on the network", recipient) });
+        return;
+    }
+    match client.send(chat_id, recipient, text.to_owned()) {
+        Ok(()) => print_event(&Event::Sent { recipient: recipient.to_owned(), chat_id: chat_id.to_owned() }),
+        Err(error) => print_event(&Event::Error { message: error.to_string() }),
+    }
+}
@@ -0,0 +1,310 @@
+use std::{collections::HashMap, net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use anyhow::{anyhow, bail};
+use enchat_core::{chat::ReceiverBundle, encryption::PublicKey, keybundle::{to_base58, IdentityKey, ManagedKey, StoredKey}, transport::{Envelope, KeyBundle, Transport, TransportError, TransportResult}, user::User};
+use rusqlite::Connection;
+
+use crate::{discovery::{Announcement, Discovery, DEFAULT_DISCOVERY}, wire::{handshake, read_frame, write_frame, Hello, Request, Response, Role}};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct LanConfig {
+    // Any free port by default, peers learn it from the announcements
+    pub listen: SocketAddr,
+    // Multicast group of the announcements, None to only reach peers added by address
+    pub discovery: Option<SocketAddrV4>,
+    // Interface the group is joined on, loopback keeps the announcements on this host
+    pub interface: Ipv4Addr,
+    pub announce_interval: Duration,
+}
+
+impl Default for LanConfig {
+    fn default() -> Self {
+        Self {
+            listen: SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            discovery: Some(DEFAULT_DISCOVERY),
+            interface: Ipv4Addr::UNSPECIFIED,
+            announce_interval: Duration::from_secs(5),
+        }
+    }
+}
+
+struct Peer {
+    address: Option<SocketAddr>,
+    identity: PublicKey,
+    // Proven in a handshake, other identities for this user are refused from then on
+    verified: bool,
+}
+
+#[derive(Default)]
+struct Mailbox {
+    envelopes: Vec<Envelope>,
+    last_id: u64,
+}
+
+struct Shared {
+    user_id: String,
+    identity: IdentityKey,
+    port: u16,
+    bundle: Mutex<Option<KeyBundle>>,
+    mailbox: Mutex<Mailbox>,
+    peers: Mutex<HashMap<String, Peer>>,
+    stopped: AtomicBool,
+}
+
+impl Shared {
+    // Returns true if the peer is new or moved, so it can hear from us right away
+    fn announced(&self, announcement: Announcement, address: SocketAddr) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        match peers.get_mut(&announcement.user_id) {
+            Some(peer) if peer.verified && peer.identity != announcement.identity => {
+                warn!("Ignoring announcement of {} with another identity", announcement.user_id);
+                false
+            }
+            Some(peer) => {
+                let moved = peer.address != Some(address);
+                peer.address = Some(address);
+                peer.identity = announcement.identity;
+                moved
+            }
+            None => {
+                debug!("Discovered {} at {}", announcement.user_id, address);
+                peers.insert(announcement.user_id, Peer { address: Some(address), identity: announcement.identity, verified: false });
+                true
+            }
+        }
+    }
+    // First identity proven for a user is pinned
+    fn authenticated(&self, hello: &Hello, address: Option<SocketAddr>) -> anyhow::Result<()> {
+        if hello.user_id == self.user_id {
+            bail!("Peer uses our own user id");
+        }
+        let mut peers = self.peers.lock().unwrap();
+        let known = peers.get(&hello.user_id);
+        if let Some(peer) = known.filter(|peer| peer.verified && peer.identity != hello.identity) {
+            bail!("{} connected with another identity than {}", hello.user_id, to_base58(peer.identity.as_bytes()));
+        }
+        let address = address.or_else(|| known.and_then(|peer| peer.address));
+        peers.insert(hello.user_id.clone(), Peer { address, identity: hello.identity, verified: true });
+        Ok(())
+    }
+    fn verified_identity(&self, user_id: &str) -> Option<PublicKey> {
+        self.peers.lock().unwrap().get(user_id).filter(|peer| peer.verified).map(|peer| peer.identity)
+    }
+    fn connect(&self, address: SocketAddr, expected: Option<&str>) -> anyhow::Result<(TcpStream, Hello)> {
+        let mut stream = TcpStream::connect_timeout(&address, IO_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let theirs = handshake(&mut stream, Role::Initiator, &self.identity, &self.user_id, Some(self.port))?;
+        if let Some(expected) = expected.filter(|expected| *expected != theirs.user_id) {
+            bail!("Expected {} at {}, but {} answered", expected, address, theirs.user_id);
+        }
+        self.authenticated(&theirs, Some(address))?;
+        Ok((stream, theirs))
+    }
+    fn request(&self, user_id: &str, request: &Request) -> TransportResult<Response> {
+        let address = self.peers.lock().unwrap().get(user_id).and_then(|peer| peer.address)
+            .ok_or_else(|| TransportError::UnknownUser(user_id.to_owned()))?;
+        let (mut stream, _) = self.connect(address, Some(user_id))?;
+        write_frame(&mut stream, request)?;
+        Ok(read_frame(&mut stream)?)
+    }
+    fn serve(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        let theirs = handshake(&mut stream, Role::Responder, &self.identity, &self.user_id, Some(self.port))?;
+        let address = match theirs.port {
+            Some(port) => Some(SocketAddr::new(stream.peer_addr()?.ip(), port)),
+            None => None,
+        };
+        self.authenticated(&theirs, address)?;
+        let response = match read_frame(&mut stream)? {
+            Request::Ping => Response::Pong,
+            Request::Bundle => match self.bundle.lock().unwrap().as_mut() {
+                Some(bundle) => Response::Bundle(bundle.take_receiver_bundle()),
+                None => Response::NoBundle,
+            },
+            Request::Deliver(envelope) => self.deliver(&theirs.user_id, *envelope),
+        };
+        write_frame(&mut stream, &response)
+    }
+    // Sender is whoever passed the handshake, so envelopes can't be sent in someone else's name
+    fn deliver(&self, sender: &str, mut envelope: Envelope) -> Response {
+        if envelope.sender != sender || envelope.recipient != self.user_id {
+            return Response::Refused("Envelope has another sender or recipient".to_owned());
+        }
+        let mut mailbox = self.mailbox.lock().unwrap();
+        mailbox.last_id += 1;
+        envelope.id = mailbox.last_id;
+        mailbox.envelopes.push(envelope);
+        Response::Delivered
+    }
+}
+
+fn accept(shared: Arc<Shared>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        if shared.stopped.load(Ordering::Relaxed) {
+            break;
+        }
+        let Ok(stream) = stream else { continue };
+        let shared = shared.clone();
+        thread::spawn(move || {
+            if let Err(error) = shared.serve(stream) {
+                debug!("Incoming connection failed: {}", error);
+            }
+        });
+    }
+}
+
+fn discover(shared: Arc<Shared>, discovery: Discovery, interval: Duration) {
+    let announcement = Announcement { user_id: shared.user_id.clone(), identity: shared.identity.get_public_key(), port: shared.port };
+    let mut announced_at: Option<Instant> = None;
+    while !shared.stopped.load(Ordering::Relaxed) {
+        let due = match announced_at {
+            Some(at) => at.elapsed() >= interval,
+            None => true,
+        };
+        if due {
+            if let Err(error) = discovery.announce(&announcement) {
+                warn!("Can't send the announcement: {}", error);
+            }
+            announced_at = Some(Instant::now());
+        }
+        let Some((theirs, ip)) = discovery.receive() else { continue };
+        if theirs.user_id == shared.user_id {
+            continue;
+        }
+        let address = SocketAddr::new(ip, theirs.port);
+        if shared.announced(theirs, address) {
+            announced_at = None;
+        }
+    }
+}
+
+// Serves our bundle and mailbox to peers, both only live in memory. An envelope is acknowledged
+// to the sender once it's in the mailbox, so it's lost if we stop before receiving it
+pub struct LanTransport {
+    shared: Arc<Shared>,
+    address: SocketAddr,
+}
+
+impl LanTransport {
+    pub fn start(conn: &Connection, user: &User, config: LanConfig) -> anyhow::Result<Self> {
+        let user_id = user.user_id.clone().ok_or_else(|| anyhow!("Account has no user id"))?;
+        let identity = IdentityKey::fetch(None, conn, user)?;
+        let listener = TcpListener::bind(config.listen)?;
+        let address = listener.local_addr()?;
+        let discovery = config.discovery.map(|group| Discovery::join(group, config.interface, POLL_INTERVAL)).transpose()?;
+        let shared = Arc::new(Shared {
+            user_id,
+            identity,
+            port: address.port(),
+            bundle: Mutex::new(None),
+            mailbox: Mutex::new(Mailbox::default()),
+            peers: Mutex::new(HashMap::new()),
+            stopped: AtomicBool::new(false),
+        });
+        let accepting = shared.clone();
+        thread::spawn(move || accept(accepting, listener));
+        if let Some(discovery) = discovery {
+            let discovering = shared.clone();
+            thread::spawn(move || discover(discovering, discovery, config.announce_interval));
+        }
+        Ok(Self { shared, address })
+    }
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+    // For networks without multicast, returns who answered at the address
+    pub fn add_peer(&self, address: SocketAddr) -> TransportResult<String> {
+        let (mut stream, theirs) = self.shared.connect(address, None)?;
+        write_frame(&mut stream, &Request::Ping)?;
+        read_frame::<Response>(&mut stream)?;
+        Ok(theirs.user_id)
+    }
+    // Peers show up within an announcement interval once both sides run
+    pub fn wait_for_peer(&self, user_id: &str, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if self.shared.peers.lock().unwrap().get(user_id).is_some_and(|peer| peer.address.is_some()) {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+    pub fn peers(&self) -> Vec<String> {
+        self.shared.peers.lock().unwrap().keys().cloned().collect()
+    }
+    fn ensure_own(&self, user_id: &str) -> TransportResult<()> {
+        if user_id != self.shared.user_id {
+            return Err(TransportError::Other(anyhow!("Only {} is served by this transport", self.shared.user_id)));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LanTransport {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Relaxed);
+        // Wakes the accepting thread so it sees the flag
+        let wake = match self.address.ip().is_unspecified() {
+            true => SocketAddr::from((Ipv4Addr::LOCALHOST, self.address.port())),
+            false => self.address,
+        };
+        let _ = TcpStream::connect_timeout(&wake, IO_TIMEOUT);
+    }
+}
+
+fn unexpected(user_id: &str) -> TransportError {
+    TransportError::Other(anyhow!("Unexpected response from {}", user_id))
+}
+
+impl Transport for LanTransport {
+    // Peers fetch the bundle from us directly, so publishing only replaces it in memory
+    fn publish_bundle(&self, user_id: &str, mut bundle: KeyBundle) -> TransportResult<()> {
+        self.ensure_own(user_id)?;
+        let mut published = self.shared.bundle.lock().unwrap();
+        let mut onetime = published.take().map(|published| published.onetime).unwrap_or_default();
+        onetime.append(&mut bundle.onetime);
+        bundle.onetime = onetime;
+        *published = Some(bundle);
+        Ok(())
+    }
+    // Connecting is enough, the handshake proves the identity
+    fn fetch_identity(&self, user_id: &str) -> TransportResult<PublicKey> {
+        if let Some(identity) = self.shared.verified_identity(user_id) {
+            return Ok(identity);
+        }
+        self.shared.request(user_id, &Request::Ping)?;
+        self.shared.verified_identity(user_id).ok_or_else(|| TransportError::UnknownUser(user_id.to_owned()))
+    }
+    fn fetch_bundle(&self, user_id: &str) -> TransportResult<ReceiverBundle> {
+        match self.shared.request(user_id, &Request::Bundle)? {
+            Response::Bundle(bundle) if Some(bundle.receiver_identity) == self.shared.verified_identity(user_id) => Ok(bundle),
+            Response::Bundle(_) => Err(TransportError::Other(anyhow!("Bundle of {} has another identity", user_id))),
+            Response::NoBundle => Err(TransportError::UnknownUser(user_id.to_owned())),
+            _ => Err(unexpected(user_id)),
+        }
+    }
+    fn send(&self, envelope: Envelope) -> TransportResult<()> {
+        let recipient = envelope.recipient.clone();
+        match self.shared.request(&recipient, &Request::Deliver(Box::new(envelope)))? {
+            Response::Delivered => Ok(()),
+            Response::Refused(reason) => Err(TransportError::Other(anyhow!("{} refused the envelope: {}", recipient, reason))),
+            _ => Err(unexpected(&recipient)),
+        }
+    }
+    fn poll(&self, user_id: &str) -> TransportResult<Vec<Envelope>> {
+        self.ensure_own(user_id)?;
+        Ok(self.shared.mailbox.lock().unwrap().envelopes.clone())
+    }
+    fn acknowledge(&self, user_id: &str, envelope_ids: &[u64]) -> TransportResult<()> {
+        self.ensure_own(user_id)?;
+        self.shared.mailbox.lock().unwrap().envelopes.retain(|envelope| !envelope_ids.contains(&envelope.id));
+        Ok(())
+    }
+}
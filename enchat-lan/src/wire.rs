@@ -0,0 +1,183 @@
+use std::io::{Read, Write};
+
+use anyhow::{anyhow, bail};
+use enchat_core::{chat::ReceiverBundle, encryption::{get_rng, PublicKey}, keybundle::{calculate_signature, verify_signature, IdentityKey, ManagedKey}, transport::Envelope};
+use rand::RngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+// Envelopes carry one message, bundles a few hundred onetime keys at most
+const MAX_FRAME_LENGTH: u32 = 1024 * 1024;
+const HANDSHAKE_CONTEXT: &[u8] = b"enchat lan handshake";
+
+// Frames are a big endian length followed by JSON
+pub fn write_frame<T: Serialize>(stream: &mut impl Write, value: &T) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(value)?;
+    stream.write_all(&(body.len() as u32).to_be_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+pub fn read_frame<T: DeserializeOwned>(stream: &mut impl Read) -> anyhow::Result<T> {
+    let mut length = [0u8; 4];
+    stream.read_exact(&mut length)?;
+    let length = u32::from_be_bytes(length);
+    if length > MAX_FRAME_LENGTH {
+        bail!("Frame of {} bytes is too long", length);
+    }
+    let mut body = vec![0u8; length as usize];
+    stream.read_exact(&mut body)?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Hello {
+    pub user_id: String,
+    pub identity: PublicKey,
+    pub nonce: [u8; 32],
+    // Port we accept connections on, so the peer can reach us back without discovery
+    pub port: Option<u16>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Proof {
+    signature: Vec<u8>,
+}
+
+// One request per connection, sent after the handshake
+#[derive(Deserialize, Serialize)]
+pub enum Request {
+    Ping,
+    Bundle,
+    Deliver(Box<Envelope>),
+}
+
+#[derive(Deserialize, Serialize)]
+pub enum Response {
+    Pong,
+    Bundle(ReceiverBundle),
+    NoBundle,
+    Delivered,
+    Refused(String),
+}
+
+#[derive(Clone, Copy)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+impl Role {
+    fn peer(self) -> Self {
+        match self {
+            Role::Initiator => Role::Responder,
+            Role::Responder => Role::Initiator,
+        }
+    }
+}
+
+// Covers the peer's nonce so the proof can't be replayed, the role so it can't be reflected back,
+// and both sides so it can't be passed on to someone else
+fn transcript(role: Role, ours: &Hello, theirs: &Hello) -> Vec<u8> {
+    let mut transcript = HANDSHAKE_CONTEXT.to_vec();
+    transcript.push(role as u8);
+    transcript.extend_from_slice(&theirs.nonce);
+    transcript.extend_from_slice(&ours.nonce);
+    transcript.extend_from_slice(ours.identity.as_bytes());
+    transcript.extend_from_slice(theirs.identity.as_bytes());
+    for user_id in [&ours.user_id, &theirs.user_id] {
+        transcript.extend_from_slice(&(user_id.len() as u32).to_be_bytes());
+        transcript.extend_from_slice(user_id.as_bytes());
+    }
+    transcript
+}
+
+// Both sides sign the transcript with their identity key, the responder only after the initiator's proof checks out
+pub fn handshake<S: Read + Write>(stream: &mut S, role: Role, identity: &IdentityKey, user_id: &str, port: Option<u16>) -> anyhow::Result<Hello> {
+    let mut nonce = [0u8; 32];
+    get_rng().fill_bytes(&mut nonce);
+    let ours = Hello { user_id: user_id.to_owned(), identity: identity.get_public_key(), nonce, port };
+    let theirs: Hello = match role {
+        Role::Initiator => {
+            write_frame(stream, &ours)?;
+            read_frame(stream)?
+        }
+        Role::Responder => {
+            let theirs = read_frame(stream)?;
+            write_frame(stream, &ours)?;
+            theirs
+        }
+    };
+    let proof = Proof { signature: calculate_signature(identity.get_keypair(), &mut get_rng(), &transcript(role, &ours, &theirs)).to_vec() };
+    let verify = |proof: Proof| {
+        let signature = proof.signature.try_into().map_err(|_| anyhow!("Malformed handshake signature"))?;
+        if !verify_signature(theirs.identity.as_bytes(), &transcript(role.peer(), &theirs, &ours), &signature) {
+            bail!("{} failed the handshake", theirs.user_id);
+        }
+        Ok(())
+    };
+    match role {
+        Role::Initiator => {
+            write_frame(stream, &proof)?;
+            verify(read_frame(stream)?)?;
+        }
+        Role::Responder => {
+            verify(read_frame(stream)?)?;
+            write_frame(stream, &proof)?;
+        }
+    }
+    Ok(theirs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::{TcpListener, TcpStream}, thread};
+
+    use enchat_core::{encryption::get_rng, keybundle::{calculate_signature, IdentityKey, ManagedKey, StoredKey}};
+
+    use super::{handshake, read_frame, transcript, write_frame, Hello, Proof, Role, MAX_FRAME_LENGTH};
+
+    #[test]
+    fn handshake_should_authenticate_both_sides() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let bob = IdentityKey::generate();
+        let bob_public = bob.get_public_key();
+        let responder = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, Role::Responder, &bob, "bob", None).unwrap()
+        });
+        let alice = IdentityKey::generate();
+        let theirs = handshake(&mut TcpStream::connect(address).unwrap(), Role::Initiator, &alice, "alice", Some(1)).unwrap();
+        assert_eq!(theirs.user_id, "bob");
+        assert_eq!(theirs.identity, bob_public);
+        let ours = responder.join().unwrap();
+        assert_eq!(ours.identity, alice.get_public_key());
+        assert_eq!(ours.port, Some(1));
+    }
+
+    #[test]
+    fn claimed_identity_should_need_its_key() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let responder = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            handshake(&mut stream, Role::Responder, &IdentityKey::generate(), "bob", None).is_ok()
+        });
+        // Claims the identity of alice, but can only sign with its own key
+        let mut stream = TcpStream::connect(address).unwrap();
+        let hello = Hello { user_id: "alice".to_owned(), identity: IdentityKey::generate().get_public_key(), nonce: [0; 32], port: None };
+        write_frame(&mut stream, &hello).unwrap();
+        let theirs: Hello = read_frame(&mut stream).unwrap();
+        let signature = calculate_signature(IdentityKey::generate().get_keypair(), &mut get_rng(), &transcript(Role::Initiator, &hello, &theirs));
+        write_frame(&mut stream, &Proof { signature: signature.to_vec() }).unwrap();
+        assert!(!responder.join().unwrap());
+    }
+
+    #[test]
+    fn oversized_frame_should_be_refused() {
+        let mut frame = (MAX_FRAME_LENGTH + 1).to_be_bytes().to_vec();
+        frame.extend_from_slice(b"{}");
+        assert!(read_frame::<serde_json::Value>(&mut frame.as_slice()).is_err());
+    }
+}
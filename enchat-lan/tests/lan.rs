@@ -0,0 +1,155 @@
+use std::{env, fs, io::{BufRead, BufReader, Write}, net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket}, path::PathBuf, process::{Child, ChildStdin, Command, Stdio}, sync::mpsc::{self, Receiver}, thread, time::Duration};
+
+use enchat_core::{keybundle::{IdentityKey, ManagedKey, StoredKey}, message::Received, store::make_migrations, transport::{Client, Incoming, Transport, TransportError}, user::User};
+use enchat_lan::{LanConfig, LanTransport};
+use rusqlite::Connection;
+
+const WAIT: Duration = Duration::from_secs(15);
+
+fn account(user_id: &str) -> (Connection, User) {
+    let mut connection = Connection::open_in_memory().unwrap();
+    make_migrations(&mut connection).unwrap();
+    let user = User { user_id: Some(user_id.to_owned()) };
+    IdentityKey::generate().store(&connection, &user).unwrap();
+    (connection, user)
+}
+
+fn texts(incoming: Vec<Incoming>) -> Vec<String> {
+    incoming.into_iter().filter_map(|incoming| incoming.received.and_then(Received::message)).map(|payload| payload.content).collect()
+}
+
+// Tests run in parallel, each gets its own discovery port
+fn discovery_group() -> SocketAddrV4 {
+    let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    SocketAddrV4::new(Ipv4Addr::new(239, 255, 42, 99), port)
+}
+
+fn loopback(discovery: Option<SocketAddrV4>) -> LanConfig {
+    LanConfig {
+        listen: SocketAddr::from((Ipv4Addr::LOCALHOST, 0)),
+        discovery,
+        interface: Ipv4Addr::LOCALHOST,
+        announce_interval: Duration::from_millis(500),
+    }
+}
+
+#[test]
+fn peers_should_discover_each_other_and_talk() {
+    let group = discovery_group();
+    let (alice_db, alice_user) = account("alice");
+    let (bob_db, bob_user) = account("bob");
+    let alice_transport = LanTransport::start(&alice_db, &alice_user, loopback(Some(group))).unwrap();
+    let bob_transport = LanTransport::start(&bob_db, &bob_user, loopback(Some(group))).unwrap();
+    let alice = Client::new(&alice_transport, &alice_db, &alice_user);
+    let bob = Client::new(&bob_transport, &bob_db, &bob_user);
+    alice.publish_keys(5).unwrap();
+    bob.publish_keys(5).unwrap();
+    assert!(alice_transport.wait_for_peer("bob", WAIT));
+
+    alice.send("chat", "bob", "hello".to_owned()).unwrap();
+    alice.send("chat", "bob", "no server needed".to_owned()).unwrap();
+    assert_eq!(texts(bob.receive().unwrap()), vec!["hello", "no server needed"]);
+    assert!(bob.receive().unwrap().is_empty());
+    bob.send("chat", "alice", "hi".to_owned()).unwrap();
+    assert_eq!(texts(alice.receive().unwrap()), vec!["hi"]);
+}
+
+#[test]
+fn peers_should_connect_by_address_without_discovery() {
+    let (alice_db, alice_user) = account("alice");
+    let (bob_db, bob_user) = account("bob");
+    let alice_transport = LanTransport::start(&alice_db, &alice_user, loopback(None)).unwrap();
+    let bob_transport = LanTransport::start(&bob_db, &bob_user, loopback(None)).unwrap();
+    Client::new(&bob_transport, &bob_db, &bob_user).publish_keys(1).unwrap();
+    assert!(matches!(alice_transport.fetch_bundle("bob"), Err(TransportError::UnknownUser(_))));
+    assert_eq!(alice_transport.add_peer(bob_transport.local_addr()).unwrap(), "bob");
+    // Bob learned our address from the handshake
+    assert_eq!(bob_transport.peers(), vec!["alice"]);
+    assert_eq!(alice_transport.fetch_bundle("bob").unwrap().receiver_onetime_id, Some(1));
+    assert_eq!(alice_transport.fetch_bundle("bob").unwrap().receiver_onetime_id, None);
+}
+
+#[test]
+fn pinned_identity_should_not_be_replaced() {
+    let (alice_db, alice_user) = account("alice");
+    let (bob_db, bob_user) = account("bob");
+    let (mallory_db, mallory_user) = account("bob");
+    let alice = LanTransport::start(&alice_db, &alice_user, loopback(None)).unwrap();
+    let bob = LanTransport::start(&bob_db, &bob_user, loopback(None)).unwrap();
+    let mallory = LanTransport::start(&mallory_db, &mallory_user, loopback(None)).unwrap();
+    alice.add_peer(bob.local_addr()).unwrap();
+    assert!(alice.add_peer(mallory.local_addr()).is_err());
+    assert!(mallory.add_peer(alice.local_addr()).is_err());
+    assert_eq!(alice.fetch_identity("bob").unwrap(), IdentityKey::fetch(None, &bob_db, &bob_user).unwrap().get_public_key());
+}
+
+struct Peer {
+    process: Child,
+    input: ChildStdin,
+    events: Receiver<serde_json::Value>,
+    database: PathBuf,
+}
+
+impl Peer {
+    fn spawn(user_id: &str, group: SocketAddrV4) -> Self {
+        let database = env::temp_dir().join(format!("enchat-lan-{}-{}-{}.db", user_id, group.port(), std::process::id()));
+        let mut process = Command::new(env!("CARGO_BIN_EXE_enchat-lan"))
+            .args(["--user", user_id, "--listen", "127.0.0.1:0", "--interface", "127.0.0.1"])
+            .arg("--discovery").arg(group.to_string())
+            .arg("--data-dir").arg(env::temp_dir())
+            .arg("--database").arg(&database)
+            .args(["--database-key", &"ab".repeat(32)])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let input = process.stdin.take().unwrap();
+        let output = BufReader::new(process.stdout.take().unwrap());
+        let (sender, events) = mpsc::channel();
+        thread::spawn(move || {
+            for line in output.lines().map_while(Result::ok) {
+                if sender.send(serde_json::from_str(&line).unwrap()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { process, input, events, database }
+    }
+    fn expect(&self, event: &str) -> serde_json::Value {
+        loop {
+            let received = self.events.recv_timeout(WAIT).expect("Peer didn't answer in time");
+            assert_ne!(received["event"], "error", "{}", received);
+            if received["event"] == event {
+                return received;
+            }
+        }
+    }
+    fn send(&mut self, line: &str) {
+        writeln!(self.input, "{}", line).unwrap();
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = fs::remove_file(&self.database);
+    }
+}
+
+#[test]
+fn processes_should_talk_over_loopback() {
+    let group = discovery_group();
+    let mut alice = Peer::spawn("alice", group);
+    let mut bob = Peer::spawn("bob", group);
+    alice.expect("listening");
+    bob.expect("listening");
+
+    alice.send("bob chat hello from another process");
+    alice.expect("sent");
+    let received = bob.expect("received");
+    assert_eq!(received["sender"], "alice");
+    assert_eq!(received["content"], "hello from another process");
+    bob.send("alice chat hi");
+    assert_eq!(alice.expect("received")["content"], "hi");
+}
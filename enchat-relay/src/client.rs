@@ -0,0 +1,74 @@
+use enchat_core::{chat::ReceiverBundle, encryption::{derive, PublicKey}, keybundle::{to_base58, IdentityKey, ManagedKey, StoredKey}, transport::{Envelope, KeyBundle, Transport, TransportError, TransportResult}, user::User};
+use rusqlite::Connection;
+use serde::de::DeserializeOwned;
+
+const RELAY_TOKEN_INFO: &[u8] = b"enchat relay token";
+
+// Proves to the relay that we own the account, derived from the identity key so it doesn't have to be stored
+pub fn relay_token(identity: &IdentityKey) -> String {
+    to_base58(derive(None, &identity.get_keypair().to_bytes(), RELAY_TOKEN_INFO).0)
+}
+
+pub struct RelayTransport {
+    url: String,
+    token: String,
+    agent: ureq::Agent,
+}
+
+impl RelayTransport {
+    pub fn new(url: &str, token: String) -> Self {
+        Self { url: url.trim_end_matches('/').to_owned(), token, agent: ureq::Agent::new() }
+    }
+    pub fn for_user(url: &str, conn: &Connection, user: &User) -> rusqlite::Result<Self> {
+        Ok(Self::new(url, relay_token(&IdentityKey::fetch(None, conn, user)?)))
+    }
+    fn endpoint(&self, user_id: &str, path: &str) -> String {
+        format!("{}/users/{}/{}", self.url, user_id, path)
+    }
+    fn bearer(&self) -> String {
+        format!("Bearer {}", self.token)
+    }
+}
+
+fn map_error(user_id: &str, error: ureq::Error) -> TransportError {
+    match error {
+        ureq::Error::Status(404, _) => TransportError::UnknownUser(user_id.to_owned()),
+        ureq::Error::Status(status, response) => {
+            let body = response.into_string().unwrap_or_default();
+            TransportError::Other(anyhow::anyhow!("Relay responded with {}: {}", status, body))
+        }
+        error => TransportError::Other(error.into()),
+    }
+}
+
+fn parse<T: DeserializeOwned>(response: ureq::Response) -> TransportResult<T> {
+    response.into_json().map_err(|error| TransportError::Other(error.into()))
+}
+
+impl Transport for RelayTransport {
+    fn publish_bundle(&self, user_id: &str, bundle: KeyBundle) -> TransportResult<()> {
+        self.agent.put(&self.endpoint(user_id, "bundle")).set("Authorization", &self.bearer())
+            .send_json(&bundle).map_err(|error| map_error(user_id, error))?;
+        Ok(())
+    }
+    fn fetch_identity(&self, user_id: &str) -> TransportResult<PublicKey> {
+        parse(self.agent.get(&self.endpoint(user_id, "identity")).call().map_err(|error| map_error(user_id, error))?)
+    }
+    fn fetch_bundle(&self, user_id: &str) -> TransportResult<ReceiverBundle> {
+        parse(self.agent.post(&self.endpoint(user_id, "bundle/claim")).call().map_err(|error| map_error(user_id, error))?)
+    }
+    fn send(&self, envelope: Envelope) -> TransportResult<()> {
+        self.agent.post(&self.endpoint(&envelope.recipient, "messages")).set("Authorization", &self.bearer())
+            .send_json(&envelope).map_err(|error| map_error(&envelope.recipient, error))?;
+        Ok(())
+    }
+    fn poll(&self, user_id: &str) -> TransportResult<Vec<Envelope>> {
+        parse(self.agent.get(&self.endpoint(user_id, "messages")).set("Authorization", &self.bearer())
+            .call().map_err(|error| map_error(user_id, error))?)
+    }
+    fn acknowledge(&self, user_id: &str, envelope_ids: &[u64]) -> TransportResult<()> {
+        self.agent.post(&self.endpoint(user_id, "messages/ack")).set("Authorization", &self.bearer())
+            .send_json(envelope_ids).map_err(|error| map_error(user_id, error))?;
+        Ok(())
+    }
+}
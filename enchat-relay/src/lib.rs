@@ -0,0 +1,10 @@
+// Relay keeps published key bundles and queues envelopes until the recipient acknowledges them.
+// It only ever sees ciphertext, identity keys and who talks to whom
+
+mod client;
+mod server;
+mod store;
+
+pub use self::{client::{RelayTransport, relay_token}, server::Relay, store::RelayStore};
+
+#[macro_use] extern crate log;
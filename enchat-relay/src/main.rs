@@ -0,0 +1,28 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use enchat_relay::{Relay, RelayStore};
+
+#[macro_use] extern crate log;
+
+#[derive(Parser)]
+#[command(name = "enchat-relay", about = "Self-hosted relay for enchat key bundles and mailboxes")]
+struct Args {
+    /// Address to listen on, put it behind a TLS proxy when exposed to the internet
+    #[arg(long, env = "ENCHAT_RELAY_LISTEN", default_value = "127.0.0.1:8800")]
+    listen: String,
+    /// SQLite database of the relay
+    #[arg(long, env = "ENCHAT_RELAY_DATABASE", default_value = "enchat-relay.db")]
+    database: PathBuf,
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+}
+
+fn main() -> anyhow::Result<()> {
+    pretty_env_logger::init();
+    let args = Args::parse();
+    let relay = Relay::bind(&args.listen, RelayStore::open(&args.database)?)?;
+    info!("Relay listening on {}", args.listen);
+    relay.run(args.workers);
+    Ok(())
+}
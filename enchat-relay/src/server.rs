@@ -0,0 +1,156 @@
+use std::{io::Read, net::SocketAddr, sync::{Arc, Mutex}, thread};
+
+use enchat_core::transport::{Envelope, KeyBundle};
+use serde::Serialize;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::store::{Access, RelayStore};
+
+// Envelopes carry one message, bundles a few hundred onetime keys at most
+const MAX_BODY_LENGTH: u64 = 1024 * 1024;
+const MAX_ENVELOPE_LENGTH: u64 = 64 * 1024;
+// One sender can't fill a mailbox its owner doesn't empty
+const MAX_QUEUED_PER_SENDER: u64 = 1000;
+
+struct Reply(u16, String);
+
+impl Reply {
+    fn json<T: Serialize>(value: &T) -> Self {
+        Self(200, serde_json::to_string(value).unwrap())
+    }
+    fn empty() -> Self {
+        Self(204, String::new())
+    }
+    fn error(status: u16, message: &str) -> Self {
+        Self(status, serde_json::json!({ "error": message }).to_string())
+    }
+}
+
+// Names end up in URLs and SQL parameters only, but anything else is most likely a client bug
+fn valid_user_id(user_id: &str) -> bool {
+    !user_id.is_empty() && user_id.len() <= 128 && user_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    request.headers().iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "))
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(request: &mut Request, limit: u64) -> Result<T, Reply> {
+    let mut body = String::new();
+    request.as_reader().take(limit + 1).read_to_string(&mut body).map_err(|_| Reply::error(400, "Can't read the body"))?;
+    if body.len() as u64 > limit {
+        return Err(Reply::error(413, "Body too large"));
+    }
+    serde_json::from_str(&body).map_err(|_| Reply::error(400, "Malformed body"))
+}
+
+fn handle(request: &mut Request, store: &Mutex<RelayStore>) -> Result<Reply, Reply> {
+    let path = request.url().split('?').next().unwrap_or_default().to_owned();
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let ["users", user_id, rest @ ..] = segments.as_slice() else {
+        return Err(Reply::error(404, "Not found"));
+    };
+    if !valid_user_id(user_id) {
+        return Err(Reply::error(400, "Invalid user id"));
+    }
+    let internal = |_| Reply::error(500, "Storage error");
+    let token = bearer_token(request).map(str::to_owned);
+    let authorized = || match store.lock().unwrap().access(user_id, token.as_deref()).map_err(internal)? {
+        Access::Granted => Ok(()),
+        Access::Unclaimed => Err(Reply::error(404, "Unknown user")),
+        Access::Denied => Err(Reply::error(401, "Invalid token")),
+    };
+    match (request.method(), rest) {
+        (Method::Put, ["bundle"]) => {
+            let bundle: KeyBundle = read_json(request, MAX_BODY_LENGTH)?;
+            let token = token.as_deref().ok_or_else(|| Reply::error(401, "Token required"))?;
+            if !bundle.verify() {
+                return Err(Reply::error(400, "Prekey signature doesn't match the identity key"));
+            }
+            let store = store.lock().unwrap();
+            if let Access::Denied = store.access(user_id, Some(token)).map_err(internal)? {
+                return Err(Reply::error(401, "Invalid token"));
+            }
+            store.publish(user_id, token, &bundle).map_err(internal)?;
+            Ok(Reply::empty())
+        }
+        (Method::Get, ["identity"]) => {
+            let identity = store.lock().unwrap().identity(user_id).map_err(internal)?;
+            identity.map(|identity| Reply::json(&identity)).ok_or_else(|| Reply::error(404, "Unknown user"))
+        }
+        (Method::Post, ["bundle", "claim"]) => {
+            let bundle = store.lock().unwrap().claim_bundle(user_id).map_err(internal)?;
+            bundle.map(|bundle| Reply::json(&bundle)).ok_or_else(|| Reply::error(404, "Unknown user"))
+        }
+        (Method::Post, ["messages"]) => {
+            // Sent with the sender's token, so nobody can queue envelopes in someone else's name
+            let envelope: Envelope = read_json(request, MAX_ENVELOPE_LENGTH)?;
+            if envelope.recipient != *user_id {
+                return Err(Reply::error(400, "Envelope is for another user"));
+            }
+            let token = token.as_deref().ok_or_else(|| Reply::error(401, "Token required"))?;
+            let store = store.lock().unwrap();
+            if !valid_user_id(&envelope.sender) || !matches!(store.access(&envelope.sender, Some(token)).map_err(internal)?, Access::Granted) {
+                return Err(Reply::error(403, "Token doesn't belong to the sender"));
+            }
+            if store.identity(user_id).map_err(internal)?.is_none() {
+                return Err(Reply::error(404, "Unknown user"));
+            }
+            if store.queued(&envelope.sender, user_id).map_err(internal)? >= MAX_QUEUED_PER_SENDER {
+                return Err(Reply::error(429, "Too many envelopes waiting for the recipient"));
+            }
+            let id = store.enqueue(&envelope).map_err(internal)?;
+            Ok(Reply::json(&id))
+        }
+        (Method::Get, ["messages"]) => {
+            authorized()?;
+            Ok(Reply::json(&store.lock().unwrap().mailbox(user_id).map_err(internal)?))
+        }
+        (Method::Post, ["messages", "ack"]) => {
+            let envelope_ids: Vec<u64> = read_json(request, MAX_BODY_LENGTH)?;
+            authorized()?;
+            store.lock().unwrap().acknowledge(user_id, &envelope_ids).map_err(internal)?;
+            Ok(Reply::empty())
+        }
+        _ => Err(Reply::error(404, "Not found"))
+    }
+}
+
+pub struct Relay {
+    server: Arc<Server>,
+    store: Arc<Mutex<RelayStore>>,
+}
+
+impl Relay {
+    pub fn bind(address: &str, store: RelayStore) -> anyhow::Result<Self> {
+        let server = Server::http(address).map_err(|error| anyhow::anyhow!("Can't listen on {}: {}", address, error))?;
+        Ok(Self { server: Arc::new(server), store: Arc::new(Mutex::new(store)) })
+    }
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+    // Blocks, requests are handled by the worker threads
+    pub fn run(self, workers: usize) {
+        let handles: Vec<_> = (0..workers.max(1)).map(|_| {
+            let server = self.server.clone();
+            let store = self.store.clone();
+            thread::spawn(move || {
+                for mut request in server.incoming_requests() {
+                    let Reply(status, body) = handle(&mut request, &store).unwrap_or_else(|reply| reply);
+                    debug!("{} {} {}", request.method(), request.url(), status);
+                    let response = Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(Header::from_bytes("Content-Type", "application/json").unwrap());
+                    if let Err(error) = request.respond(response) {
+                        warn!("Failed to respond: {}", error);
+                    }
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
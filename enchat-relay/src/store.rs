@@ -0,0 +1,214 @@
+use std::path::Path;
+
+use anyhow::Context;
+use enchat_core::{chat::ReceiverBundle, encryption::PublicKey, transport::{Envelope, KeyBundle, OnetimeBundle}};
+use rusqlite::{Connection, OptionalExtension, params};
+use rusqlite_migration::{Migrations, M};
+use sha2::{Digest, Sha256};
+
+fn make_migrations(connection: &mut Connection) -> rusqlite_migration::Result<()> {
+    let migration = Migrations::new(vec![
+        M::up("CREATE TABLE account(
+            user_id TEXT PRIMARY KEY NOT NULL,
+            token_hash BLOB NOT NULL,
+            identity BLOB NOT NULL,
+            prekey BLOB NOT NULL,
+            prekey_id INTEGER NOT NULL,
+            prekey_signature BLOB NOT NULL,
+            kem_prekey TEXT
+        );
+        CREATE TABLE onetime_key(
+            user_id TEXT NOT NULL,
+            id INTEGER NOT NULL,
+            key BLOB NOT NULL,
+            PRIMARY KEY (user_id, id)
+        );
+        CREATE TABLE envelope(
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            recipient TEXT NOT NULL,
+            content TEXT NOT NULL
+        );
+        CREATE INDEX envelope_recipient ON envelope(recipient, id);"),
+        // Envelopes queued before senders were checked count for nobody
+        M::up("ALTER TABLE envelope ADD COLUMN sender TEXT NOT NULL DEFAULT '';
+        CREATE INDEX envelope_sender ON envelope(recipient, sender);"),
+    ]);
+    migration.to_latest(connection)
+}
+
+fn to_public_key(bytes: Vec<u8>) -> rusqlite::Result<PublicKey> {
+    let bytes: [u8; 32] = bytes.try_into().map_err(|_| rusqlite::Error::InvalidQuery)?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+pub enum Access {
+    // No account yet, whoever publishes first owns the name
+    Unclaimed,
+    Granted,
+    Denied,
+}
+
+pub struct RelayStore {
+    connection: Connection
+}
+
+impl RelayStore {
+    // A database left by a newer relay, or one that can't be migrated, stops the server before it listens
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(path).with_context(|| format!("Can't open the relay database {}", path.display()))?;
+        make_migrations(&mut connection).with_context(|| format!("Can't migrate the relay database {}", path.display()))?;
+        Ok(Self { connection })
+    }
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        let mut connection = Connection::open_in_memory()?;
+        make_migrations(&mut connection)?;
+        Ok(Self { connection })
+    }
+    pub fn access(&self, user_id: &str, token: Option<&str>) -> rusqlite::Result<Access> {
+        let token_hash: Option<Vec<u8>> = self.connection.query_row("SELECT token_hash FROM account WHERE user_id = ?", params![user_id], |row| row.get(0)).optional()?;
+        Ok(match (token_hash, token) {
+            (None, _) => Access::Unclaimed,
+            (Some(stored), Some(token)) if stored == hash_token(token) => Access::Granted,
+            (Some(_), _) => Access::Denied,
+        })
+    }
+    // Keys replace the published ones, onetime keys are added to those not handed out yet
+    pub fn publish(&self, user_id: &str, token: &str, bundle: &KeyBundle) -> rusqlite::Result<()> {
+        let kem_prekey = bundle.kem_prekey.as_ref().map(|kem| serde_json::to_string(kem).unwrap());
+        self.connection.execute("INSERT INTO account(user_id, token_hash, identity, prekey, prekey_id, prekey_signature, kem_prekey) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+            ON CONFLICT(user_id) DO UPDATE SET identity = excluded.identity, prekey = excluded.prekey, prekey_id = excluded.prekey_id,
+            prekey_signature = excluded.prekey_signature, kem_prekey = excluded.kem_prekey",
+            params![user_id, hash_token(token), bundle.identity.as_bytes(), bundle.prekey.as_bytes(), bundle.prekey_id, bundle.prekey_signature, kem_prekey])?;
+        for onetime in &bundle.onetime {
+            self.connection.execute("INSERT OR REPLACE INTO onetime_key(user_id, id, key) VALUES (?, ?, ?)", params![user_id, onetime.id, onetime.key.as_bytes()])?;
+        }
+        Ok(())
+    }
+    pub fn identity(&self, user_id: &str) -> rusqlite::Result<Option<PublicKey>> {
+        let identity: Option<Vec<u8>> = self.connection.query_row("SELECT identity FROM account WHERE user_id = ?", params![user_id], |row| row.get(0)).optional()?;
+        identity.map(to_public_key).transpose()
+    }
+    // Each onetime key is handed out once, the oldest first
+    pub fn claim_bundle(&mut self, user_id: &str) -> rusqlite::Result<Option<ReceiverBundle>> {
+        let transaction = self.connection.transaction()?;
+        let bundle = transaction.query_row("SELECT identity, prekey, prekey_id, prekey_signature, kem_prekey FROM account WHERE user_id = ?", params![user_id], |row| {
+            let kem_prekey: Option<String> = row.get(4)?;
+            Ok(KeyBundle {
+                identity: to_public_key(row.get(0)?)?,
+                prekey: to_public_key(row.get(1)?)?,
+                prekey_id: row.get(2)?,
+                prekey_signature: row.get(3)?,
+                kem_prekey: kem_prekey.and_then(|kem| serde_json::from_str(&kem).ok()),
+                onetime: Vec::new(),
+            })
+        }).optional()?;
+        let Some(mut bundle) = bundle else {
+            return Ok(None);
+        };
+        let onetime = transaction.query_row("SELECT id, key FROM onetime_key WHERE user_id = ? ORDER BY id LIMIT 1", params![user_id], |row| {
+            Ok(OnetimeBundle { id: row.get(0)?, key: to_public_key(row.get(1)?)? })
+        }).optional()?;
+        if let Some(onetime) = onetime {
+            transaction.execute("DELETE FROM onetime_key WHERE user_id = ? AND id = ?", params![user_id, onetime.id])?;
+            bundle.onetime.push(onetime);
+        }
+        transaction.commit()?;
+        Ok(Some(bundle.take_receiver_bundle()))
+    }
+    pub fn enqueue(&self, envelope: &Envelope) -> rusqlite::Result<u64> {
+        self.connection.execute("INSERT INTO envelope(recipient, sender, content) VALUES (?, ?, ?)", params![envelope.recipient, envelope.sender, serde_json::to_string(envelope).unwrap()])?;
+        Ok(self.connection.last_insert_rowid() as u64)
+    }
+    // Envelopes of the sender still waiting in the recipient's mailbox
+    pub fn queued(&self, sender: &str, recipient: &str) -> rusqlite::Result<u64> {
+        self.connection.query_row("SELECT COUNT(*) FROM envelope WHERE recipient = ? AND sender = ?", params![recipient, sender], |row| row.get(0))
+    }
+    pub fn mailbox(&self, user_id: &str) -> rusqlite::Result<Vec<Envelope>> {
+        let mut statement = self.connection.prepare("SELECT id, content FROM envelope WHERE recipient = ? ORDER BY id")?;
+        let envelopes = statement.query_map(params![user_id], |row| {
+            let content: String = row.get(1)?;
+            let mut envelope: Envelope = serde_json::from_str(&content).map_err(|_| rusqlite::Error::InvalidQuery)?;
+            envelope.id = row.get(0)?;
+            Ok(envelope)
+        })?;
+        envelopes.collect()
+    }
+    pub fn acknowledge(&self, user_id: &str, envelope_ids: &[u64]) -> rusqlite::Result<usize> {
+        let mut removed = 0;
+        for id in envelope_ids {
+            removed += self.connection.execute("DELETE FROM envelope WHERE recipient = ? AND id = ?", params![user_id, id])?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use enchat_core::{keybundle::{IdentityKey, ManagedKey, StoredKey}, store::make_migrations, transport::KeyBundle, user::User};
+    use std::{env, fs};
+
+    use rusqlite::Connection;
+
+    use super::{RelayStore, Access};
+
+    fn bundle(onetime_keys: usize) -> KeyBundle {
+        let mut connection = Connection::open_in_memory().unwrap();
+        make_migrations(&mut connection).unwrap();
+        let user = User { user_id: Some("bob".to_owned()) };
+        IdentityKey::generate().store(&connection, &user).unwrap();
+        KeyBundle::generate(onetime_keys, &connection, &user).unwrap()
+    }
+
+    #[test]
+    fn onetime_keys_should_be_claimed_once() {
+        let mut store = RelayStore::open_in_memory().unwrap();
+        let bundle = bundle(2);
+        store.publish("bob", "token", &bundle).unwrap();
+        assert_eq!(store.claim_bundle("bob").unwrap().unwrap().receiver_onetime_id, Some(1));
+        assert_eq!(store.claim_bundle("bob").unwrap().unwrap().receiver_onetime_id, Some(2));
+        let last = store.claim_bundle("bob").unwrap().unwrap();
+        assert_eq!(last.receiver_onetime_id, None);
+        assert_eq!(last.receiver_identity, bundle.identity);
+        assert!(store.claim_bundle("alice").unwrap().is_none());
+    }
+    #[test]
+    fn account_should_need_its_token() {
+        let store = RelayStore::open_in_memory().unwrap();
+        assert!(matches!(store.access("bob", None).unwrap(), Access::Unclaimed));
+        store.publish("bob", "token", &bundle(0)).unwrap();
+        assert!(matches!(store.access("bob", Some("token")).unwrap(), Access::Granted));
+        assert!(matches!(store.access("bob", Some("other")).unwrap(), Access::Denied));
+        assert!(matches!(store.access("bob", None).unwrap(), Access::Denied));
+    }
+    #[test]
+    fn envelopes_should_stay_until_acknowledged() {
+        let store = RelayStore::open_in_memory().unwrap();
+        let bob = IdentityKey::generate().get_public_key();
+        let message = serde_json::from_value(serde_json::json!({
+            "header": { "id": 0, "rachet_key": bob, "initial": null, "previous_receiver_length": 0 },
+            "ciphertext": [1, 2, 3]
+        })).unwrap();
+        let envelope = enchat_core::transport::Envelope { id: 0, chat_id: "chat".to_owned(), sender: "alice".to_owned(), recipient: "bob".to_owned(), message };
+        let first = store.enqueue(&envelope).unwrap();
+        let second = store.enqueue(&envelope).unwrap();
+        assert_eq!(store.queued("alice", "bob").unwrap(), 2);
+        assert_eq!(store.queued("carol", "bob").unwrap(), 0);
+        assert_eq!(store.mailbox("bob").unwrap().iter().map(|envelope| envelope.id).collect::<Vec<_>>(), vec![first, second]);
+        assert_eq!(store.acknowledge("alice", &[first]).unwrap(), 0);
+        assert_eq!(store.acknowledge("bob", &[first]).unwrap(), 1);
+        assert_eq!(store.mailbox("bob").unwrap().iter().map(|envelope| envelope.id).collect::<Vec<_>>(), vec![second]);
+    }
+
+    #[test]
+    fn newer_database_should_fail_to_open() {
+        let path = env::temp_dir().join(format!("enchat-relay-newer-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Connection::open(&path).unwrap().pragma_update(None, "user_version", 99).unwrap();
+        assert!(RelayStore::open(&path).is_err());
+        fs::remove_file(path).unwrap();
+    }
+}
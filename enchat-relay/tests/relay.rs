@@ -0,0 +1,127 @@
+use std::thread;
+
+use enchat_core::{errors::CommandError, keybundle::{IdentityKey, ManagedKey, StoredKey}, message::{Message, Received}, store::make_migrations, transport::{Client, Envelope, Incoming, KeyBundle, Transport, TransportError}, user::User};
+use enchat_relay::{Relay, RelayStore, RelayTransport};
+use rusqlite::Connection;
+
+fn start_relay() -> String {
+    let relay = Relay::bind("127.0.0.1:0", RelayStore::open_in_memory().unwrap()).unwrap();
+    let url = format!("http://{}", relay.local_addr().unwrap());
+    thread::spawn(move || relay.run(2));
+    url
+}
+
+fn account(user_id: &str) -> (Connection, User) {
+    let mut connection = Connection::open_in_memory().unwrap();
+    make_migrations(&mut connection).unwrap();
+    let user = User { user_id: Some(user_id.to_owned()) };
+    IdentityKey::generate().store(&connection, &user).unwrap();
+    (connection, user)
+}
+
+fn envelope(sender: &str, recipient: &str) -> Envelope {
+    let message: Message = serde_json::from_value(serde_json::json!({
+        "header": { "id": 0, "rachet_key": IdentityKey::generate().get_public_key(), "initial": null, "previous_receiver_length": 0 },
+        "ciphertext": [1, 2, 3]
+    })).unwrap();
+    Envelope { id: 0, chat_id: "chat".to_owned(), sender: sender.to_owned(), recipient: recipient.to_owned(), message }
+}
+
+fn texts(incoming: Vec<Incoming>) -> Vec<String> {
+    incoming.into_iter().filter_map(|incoming| incoming.received.and_then(Received::message)).map(|payload| payload.content).collect()
+}
+
+#[test]
+fn users_should_talk_through_relay() {
+    let url = start_relay();
+    let (alice_db, alice_user) = account("alice");
+    let (bob_db, bob_user) = account("bob");
+    let alice_transport = RelayTransport::for_user(&url, &alice_db, &alice_user).unwrap();
+    let bob_transport = RelayTransport::for_user(&url, &bob_db, &bob_user).unwrap();
+    let alice = Client::new(&alice_transport, &alice_db, &alice_user);
+    let bob = Client::new(&bob_transport, &bob_db, &bob_user);
+    alice.publish_keys(5).unwrap();
+    bob.publish_keys(5).unwrap();
+
+    alice.send("chat", "bob", "hello".to_owned()).unwrap();
+    alice.send("chat", "bob", "over the relay".to_owned()).unwrap();
+    assert_eq!(texts(bob.receive().unwrap()), vec!["hello", "over the relay"]);
+    assert!(bob.receive().unwrap().is_empty());
+    bob.send("chat", "alice", "hi".to_owned()).unwrap();
+    assert_eq!(texts(alice.receive().unwrap()), vec!["hi"]);
+}
+
+#[test]
+fn bundle_fetch_should_hand_out_one_onetime_key() {
+    let url = start_relay();
+    let (bob_db, bob_user) = account("bob");
+    let transport = RelayTransport::for_user(&url, &bob_db, &bob_user).unwrap();
+    Client::new(&transport, &bob_db, &bob_user).publish_keys(2).unwrap();
+    assert_eq!(transport.fetch_bundle("bob").unwrap().receiver_onetime_id, Some(1));
+    assert_eq!(transport.fetch_bundle("bob").unwrap().receiver_onetime_id, Some(2));
+    assert_eq!(transport.fetch_bundle("bob").unwrap().receiver_onetime_id, None);
+    assert!(matches!(transport.fetch_bundle("carol"), Err(TransportError::UnknownUser(_))));
+}
+
+#[test]
+fn mailbox_and_bundle_should_need_the_owner_token() {
+    let url = start_relay();
+    let (bob_db, bob_user) = account("bob");
+    let bob = RelayTransport::for_user(&url, &bob_db, &bob_user).unwrap();
+    Client::new(&bob, &bob_db, &bob_user).publish_keys(1).unwrap();
+    // Someone else taking over the name
+    let (mallory_db, mallory_user) = account("bob");
+    let mallory = RelayTransport::for_user(&url, &mallory_db, &mallory_user).unwrap();
+    let published = Client::new(&mallory, &mallory_db, &mallory_user).publish_keys(1);
+    assert!(matches!(published, Err(CommandError::TransportError(TransportError::Other(_)))));
+    assert!(mallory.poll("bob").is_err());
+    assert!(mallory.acknowledge("bob", &[1]).is_err());
+    assert!(bob.poll("bob").unwrap().is_empty());
+}
+
+#[test]
+fn forged_bundle_should_be_refused() {
+    let url = start_relay();
+    let (bob_db, bob_user) = account("bob");
+    let transport = RelayTransport::for_user(&url, &bob_db, &bob_user).unwrap();
+    let mut bundle = KeyBundle::generate(1, &bob_db, &bob_user).unwrap();
+    bundle.prekey = bundle.onetime[0].key;
+    assert!(transport.publish_bundle("bob", bundle).is_err());
+    assert!(matches!(transport.fetch_identity("bob"), Err(TransportError::UnknownUser(_))));
+}
+
+#[test]
+fn envelope_should_need_the_sender_token() {
+    let url = start_relay();
+    let (alice_db, alice_user) = account("alice");
+    let (bob_db, bob_user) = account("bob");
+    let (mallory_db, mallory_user) = account("mallory");
+    let alice = RelayTransport::for_user(&url, &alice_db, &alice_user).unwrap();
+    let bob = RelayTransport::for_user(&url, &bob_db, &bob_user).unwrap();
+    let mallory = RelayTransport::for_user(&url, &mallory_db, &mallory_user).unwrap();
+    Client::new(&alice, &alice_db, &alice_user).publish_keys(1).unwrap();
+    Client::new(&bob, &bob_db, &bob_user).publish_keys(1).unwrap();
+    Client::new(&mallory, &mallory_db, &mallory_user).publish_keys(1).unwrap();
+
+    // Mallory's own token doesn't let them send as Alice, or as someone without an account
+    assert!(mallory.send(envelope("alice", "bob")).is_err());
+    assert!(mallory.send(envelope("carol", "bob")).is_err());
+    assert!(bob.poll("bob").unwrap().is_empty());
+    mallory.send(envelope("mallory", "bob")).unwrap();
+    assert_eq!(bob.poll("bob").unwrap()[0].sender, "mallory");
+}
+
+#[test]
+fn envelope_without_token_should_be_refused() {
+    let url = start_relay();
+    let (alice_db, alice_user) = account("alice");
+    let (bob_db, bob_user) = account("bob");
+    let alice = RelayTransport::for_user(&url, &alice_db, &alice_user).unwrap();
+    let bob = RelayTransport::for_user(&url, &bob_db, &bob_user).unwrap();
+    Client::new(&alice, &alice_db, &alice_user).publish_keys(1).unwrap();
+    Client::new(&bob, &bob_db, &bob_user).publish_keys(1).unwrap();
+
+    let posted = ureq::post(&format!("{}/users/bob/messages", url)).send_json(envelope("alice", "bob"));
+    assert!(matches!(posted, Err(ureq::Error::Status(401, _))));
+    assert!(bob.poll("bob").unwrap().is_empty());
+}
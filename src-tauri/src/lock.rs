@@ -0,0 +1,186 @@
+use std::{sync::Mutex, thread, time::{Duration, Instant}};
+
+use enchat_core::{errors::{CommandError, CommandResult}, helpers::unix_millis, lock::{self, IdleTimer, Unlock}, profile::Profile, store::Database, user::User};
+use serde::Serialize;
+use tauri::{AppHandle, Manager, State};
+
+use crate::{chat::WrappedChatState, store::{DatabasePool, DatabaseState, ProfileDatabase}, worker::{blocking, States}};
+
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct LockState(pub Mutex<IdleTimer>);
+
+#[derive(Serialize)]
+pub struct LockSettings {
+    has_pin: bool,
+    idle_timeout_seconds: Option<u64>,
+}
+
+// Sessions are dropped and the connections closed once running commands are done, the user is kept so unlocking knows which profile to open.
+// Only an open profile is locked, the idle check keeps calling this while the timer stays expired
+fn lock_profile(db_state: &DatabaseState, chat_state: &WrappedChatState) {
+    let mut database = db_state.0.lock().unwrap();
+    if !matches!(*database, ProfileDatabase::Open(_)) {
+        return;
+    }
+    let mut chat = chat_state.0.lock().unwrap();
+    if let Some(open) = database.take() {
+        *chat = None;
+        drop(open);
+        *database = ProfileDatabase::Locked;
+        info!("App locked");
+    }
+}
+
+// Runs for the lifetime of the app, started from setup
+pub fn watch_idle(app: AppHandle) {
+    thread::spawn(move || loop {
+        thread::sleep(IDLE_CHECK_INTERVAL);
+        let expired = app.state::<LockState>().0.lock().unwrap().expired(Instant::now());
+        if expired {
+            lock_profile(&app.state::<DatabaseState>(), &app.state::<WrappedChatState>());
+        }
+    });
+}
+
+#[tauri::command]
+pub async fn lock_app(app: AppHandle) -> CommandResult<()> {
+    blocking(app, |states| {
+        let (user, database) = states.connect()?;
+        if !lock::has_pin(database.get_connection(), &user)? {
+            return Err(CommandError::NoPin);
+        }
+        // Locking waits for every connection, this one too
+        drop(database);
+        lock_profile(&states.db_state, &states.chat_state);
+        Ok(())
+    }).await
+}
+
+// The profile database is opened again to check the PIN, and closed right away if it's wrong.
+// The duress passphrase unlocks like any other, into an empty profile that takes it as the PIN
+#[tauri::command]
+pub async fn unlock_app(pin: String, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |States { data_dir, user_state, db_state, lock_state, .. }| {
+        let user = user_state.0.lock().unwrap();
+        let mut database = db_state.0.lock().unwrap();
+        if !matches!(*database, ProfileDatabase::Locked) {
+            return Ok(());
+        }
+        let profile = Profile::new(&data_dir.0, user.user_id.as_deref().ok_or(CommandError::NoProfile)?);
+        let reopened = profile.open()?;
+        let unlocked = match lock::verify_pin(&pin, unix_millis(), reopened.get_connection(), &user)? {
+            Unlock::Normal => reopened,
+            Unlock::Duress => {
+                let idle_timeout = lock::idle_timeout(reopened.get_connection(), &user)?;
+                let fresh = profile.reset(reopened)?;
+                lock::set_pin(Some(&pin), fresh.get_connection(), &user)?;
+                lock::set_idle_timeout(idle_timeout, fresh.get_connection(), &user)?;
+                fresh
+            }
+        };
+        *database = ProfileDatabase::Open(DatabasePool::open(unlocked, &profile)?);
+        lock_state.0.lock().unwrap().activity();
+        Ok(())
+    }).await
+}
+
+// Polled by the lock screen, waits for the database lock like the other commands
+#[tauri::command]
+pub async fn is_locked(app: AppHandle) -> CommandResult<bool> {
+    blocking(app, |States { db_state, .. }| Ok(matches!(*db_state.0.lock().unwrap(), ProfileDatabase::Locked))).await
+}
+
+// Called by the frontend on user input, background polling doesn't keep the app unlocked
+#[tauri::command]
+pub fn report_activity(lock_state: State<LockState>) {
+    lock_state.0.lock().unwrap().activity();
+}
+
+#[tauri::command]
+pub async fn get_lock_settings(app: AppHandle) -> CommandResult<LockSettings> {
+    blocking(app, |states| {
+        let (user, database) = states.connect()?;
+        let conn = database.get_connection();
+        Ok(LockSettings {
+            has_pin: lock::has_pin(conn, &user)?,
+            idle_timeout_seconds: lock::idle_timeout(conn, &user)?.map(|timeout| timeout.as_secs()),
+        })
+    }).await
+}
+
+// Only the PIN itself passes, the duress passphrase doesn't open the settings
+fn check_current_pin(current_pin: Option<&str>, database: &Database, user: &User) -> CommandResult<()> {
+    match lock::verify_pin(current_pin.unwrap_or_default(), unix_millis(), database.get_connection(), user)? {
+        Unlock::Normal => Ok(()),
+        Unlock::Duress => Err(CommandError::WrongPin),
+    }
+}
+
+// Changing or removing a PIN needs the current one, wrong guesses count towards the back-off
+#[tauri::command]
+pub async fn set_app_pin(pin: Option<String>, current_pin: Option<String>, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |states| {
+        let (user, database) = states.connect()?;
+        let conn = database.get_connection();
+        if lock::has_pin(conn, &user)? {
+            check_current_pin(current_pin.as_deref(), &database, &user)?;
+        }
+        lock::set_pin(pin.as_deref(), conn, &user)?;
+        if pin.is_none() {
+            states.lock_state.0.lock().unwrap().set_timeout(None);
+        }
+        Ok(())
+    }).await
+}
+
+// Whether one is set isn't shown anywhere, the old one isn't asked for to replace or remove it
+#[tauri::command]
+pub async fn set_duress_pin(duress: Option<String>, current_pin: Option<String>, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |states| {
+        let (user, database) = states.connect()?;
+        check_current_pin(current_pin.as_deref(), &database, &user)?;
+        lock::set_duress_pin(duress.as_deref(), database.get_connection(), &user)
+    }).await
+}
+
+#[tauri::command]
+pub async fn set_idle_timeout(seconds: Option<u64>, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |states| {
+        let (user, database) = states.connect()?;
+        let conn = database.get_connection();
+        let timeout = seconds.map(Duration::from_secs);
+        if timeout.is_some() && !lock::has_pin(conn, &user)? {
+            return Err(CommandError::NoPin);
+        }
+        lock::set_idle_timeout(timeout, conn, &user)?;
+        states.lock_state.0.lock().unwrap().set_timeout(timeout);
+        Ok(())
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, sync::Mutex};
+
+    use enchat_core::store::Database;
+
+    use crate::{chat::WrappedChatState, store::{DatabasePool, DatabaseState, ProfileDatabase}};
+
+    use super::lock_profile;
+
+    #[test]
+    fn locking_twice_should_stay_locked() {
+        let path = env::temp_dir().join(format!("enchat-lock-twice-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let database = Database::open(&path, "abababababababababababababababababababababababababababababababab").unwrap();
+        let db_state = DatabaseState(Mutex::new(ProfileDatabase::Open(DatabasePool::new(vec![database]))));
+        let chat_state = WrappedChatState(Mutex::new(None));
+        lock_profile(&db_state, &chat_state);
+        lock_profile(&db_state, &chat_state);
+        assert!(matches!(*db_state.0.lock().unwrap(), ProfileDatabase::Locked));
+        assert!(db_state.0.lock().unwrap().take().is_none());
+        assert!(matches!(*db_state.0.lock().unwrap(), ProfileDatabase::Locked));
+        fs::remove_file(path).unwrap();
+    }
+}
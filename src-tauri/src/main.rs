@@ -4,16 +4,21 @@
 )]
 
 
-use crate::{keybundle::{request_onetime_keys, request_prekey, request_identity_key}, chat::{enter_chat, reenter_chat}, message::{send, receive, try_decrypt}, user::login, files::{decrypt_and_open, encrypt_file}};
+use std::{env, path::PathBuf, process};
+
+use crate::{keybundle::{request_onetime_keys, request_prekey, request_identity_key, request_kem_prekey}, chat::{enter_chat, reenter_chat, reset_session}, message::{send, receive, try_decrypt}, user::{switch_profile, list_profiles, logout, delete_profile}, files::{decrypt_and_open, encrypt_file}, padding::{get_padding, set_padding}, passphrase::set_chat_passphrase, lock::{lock_app, unlock_app, is_locked, report_activity, get_lock_settings, set_app_pin, set_duress_pin, set_idle_timeout}, maintenance::{check_database, vacuum_database, enable_secure_delete, get_schema_version}};
 
-mod encryption;
 mod keybundle;
 mod chat;
 mod message;
 mod store;
 mod user;
 mod files;
-mod errors;
+mod padding;
+mod passphrase;
+mod lock;
+mod maintenance;
+mod worker;
 #[macro_use]
 mod helpers;
 
@@ -22,18 +27,47 @@ extern crate pretty_env_logger;
 
 
 use chat::WrappedChatState;
-use dotenv;
-use store::DatabaseState;
+use lock::LockState;
+use enchat_core::store::DataDir;
+use store::{DatabaseState, DataDirState};
 use user::UserState;
 
+// Only --data-dir is read here, the rest is left to Tauri
+fn data_dir_argument() -> Option<PathBuf> {
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--data-dir" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--data-dir=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
 fn main() {
     pretty_env_logger::init();
     dotenv::dotenv().ok();
+    let data_dir = match DataDir::resolve(data_dir_argument()) {
+        Ok(data_dir) => data_dir,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    };
+    info!("Keeping data in {}", data_dir.path().display());
     tauri::Builder::default()
+        .manage(DataDirState(data_dir))
         .manage(WrappedChatState(Default::default()))
         .manage(DatabaseState(Default::default()))
         .manage(UserState(Default::default()))
-        .invoke_handler(tauri::generate_handler![request_onetime_keys, request_identity_key, request_prekey, enter_chat, reenter_chat, receive, send, login, try_decrypt, decrypt_and_open, encrypt_file])
+        .manage(LockState(Default::default()))
+        .setup(|app| {
+            lock::watch_idle(app.handle());
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![request_onetime_keys, request_identity_key, request_prekey, request_kem_prekey, enter_chat, reenter_chat, reset_session, receive, send, switch_profile, list_profiles, logout, delete_profile, try_decrypt, decrypt_and_open, encrypt_file, get_padding, set_padding, set_chat_passphrase, lock_app, unlock_app, is_locked, report_activity, get_lock_settings, set_app_pin, set_duress_pin, set_idle_timeout, check_database, vacuum_database, enable_secure_delete, get_schema_version])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,36 @@
+use enchat_core::{errors::CommandResult, store::{maintenance::{self, IntegrityReport, SecureDeleteReport, VacuumReport}, schema_version, SchemaReport}};
+use tauri::AppHandle;
+
+use crate::worker::blocking;
+
+#[tauri::command]
+pub async fn check_database(app: AppHandle) -> CommandResult<IntegrityReport> {
+    blocking(app, |states| {
+        let (_, database) = states.connect()?;
+        Ok(maintenance::check_integrity(database.get_connection())?)
+    }).await
+}
+
+#[tauri::command]
+pub async fn vacuum_database(app: AppHandle) -> CommandResult<VacuumReport> {
+    blocking(app, |states| {
+        let (_, database) = states.connect()?;
+        Ok(maintenance::vacuum(database.get_connection())?)
+    }).await
+}
+
+#[tauri::command]
+pub async fn enable_secure_delete(app: AppHandle) -> CommandResult<SecureDeleteReport> {
+    blocking(app, |states| {
+        let (_, database) = states.connect()?;
+        Ok(maintenance::enable_secure_delete(database.get_connection())?)
+    }).await
+}
+
+#[tauri::command]
+pub async fn get_schema_version(app: AppHandle) -> CommandResult<SchemaReport> {
+    blocking(app, |states| {
+        let (_, database) = states.connect()?;
+        Ok(schema_version(database.get_connection())?)
+    }).await
+}
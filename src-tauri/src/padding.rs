@@ -0,0 +1,23 @@
+use enchat_core::{padding::Padding, errors::CommandResult};
+use tauri::AppHandle;
+
+use crate::worker::blocking;
+
+#[tauri::command]
+pub async fn get_padding(app: AppHandle) -> CommandResult<Padding> {
+    blocking(app, |states| {
+        let (user, database) = states.connect()?;
+        let conn = database.get_connection();
+        Ok(Padding::fetch(conn, &user)?)
+    }).await
+}
+
+#[tauri::command]
+pub async fn set_padding(padding: Padding, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |states| {
+        let (user, database) = states.connect()?;
+        let conn = database.get_connection();
+        padding.store(conn, &user)?;
+        Ok(())
+    }).await
+}
@@ -0,0 +1,17 @@
+use enchat_core::{passphrase::{stretch, store}, errors::CommandResult};
+use tauri::AppHandle;
+
+use crate::worker::blocking;
+
+// Applies to sessions started from now on, both parties have to set the same passphrase before the first message.
+// Stretching happens before the locks are taken
+#[tauri::command]
+pub async fn set_chat_passphrase(chat_id: String, passphrase: Option<String>, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |states| {
+        let key = passphrase.filter(|passphrase| !passphrase.is_empty()).map(|passphrase| stretch(&passphrase, &chat_id));
+        let (user, database) = states.connect()?;
+        let conn = database.get_connection();
+        store(key.as_ref(), conn, &user, &chat_id)?;
+        Ok(())
+    }).await
+}
@@ -1,113 +1,136 @@
-use std::{fmt::Display, sync::Mutex, fs::create_dir, path::PathBuf};
+use std::{ops::Deref, sync::{Arc, Condvar, Mutex}};
 
-use keyring::Entry;
-use rusqlite::{Connection, params};
+use enchat_core::{errors::{CommandError, CommandResult}, profile::Profile, store::{Database, DataDir}};
 
-use crate::{encryption::{KdfOutput, get_rng, kdf}};
-use rand::{RngCore};
+// A receive, a VACUUM or a file lookup each take one, the rest stay free for the other commands
+const POOL_SIZE: usize = 4;
 
-pub use self::migrations::make_migrations;
-
-mod migrations;
+// Connections to the profile database, a command checks one out for as long as it runs.
+// SQLite still lets one of them write at a time, the others wait for it instead of failing
+pub struct DatabasePool {
+    idle: Mutex<Vec<Database>>,
+    returned: Condvar,
+    size: usize,
+}
 
-fn ensure_appdata_dir_exists() -> PathBuf {
-    let mut path = dirs::data_local_dir().unwrap();
-    path.push("enchat");
-    if !path.exists() {
-        create_dir(&path).expect("Can't get create an app data directory");
+impl DatabasePool {
+    pub fn new(connections: Vec<Database>) -> Arc<Self> {
+        Arc::new(Self { size: connections.len(), idle: Mutex::new(connections), returned: Condvar::new() })
+    }
+    // The database was checked and migrated when the profile opened it, the other connections just join it
+    pub fn open(database: Database, profile: &Profile) -> CommandResult<Arc<Self>> {
+        let mut connections = profile.connect(POOL_SIZE - 1)?;
+        connections.insert(0, database);
+        Ok(Self::new(connections))
+    }
+    // Waits while every connection is in use
+    fn get(self: &Arc<Self>) -> PooledDatabase {
+        let mut idle = self.returned.wait_while(self.idle.lock().unwrap(), |idle| idle.is_empty()).unwrap();
+        PooledDatabase { database: idle.pop(), pool: self.clone() }
+    }
+    // Waits for the commands still using a connection, then closes all but the one handed back
+    fn close(&self) -> Option<Database> {
+        let mut idle = self.returned.wait_while(self.idle.lock().unwrap(), |idle| idle.len() < self.size).unwrap();
+        let database = idle.pop();
+        idle.clear();
+        database
     }
-    path
 }
 
-#[inline]
-fn database_path() -> PathBuf {
-    let mut dir = ensure_appdata_dir_exists();
-    dir.push("enchat_store.db");
-    dir
+// Goes back to the pool when the command is done with it
+pub struct PooledDatabase {
+    database: Option<Database>,
+    pool: Arc<DatabasePool>,
 }
 
-
-impl Display for KdfOutput {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // folds into one hex string, with formatter result accumulated
-        self.0.iter().fold(Ok(()), |result, byte| {
-            result.and_then(|_| write!(f, "{:02x}", byte))
-        })
+impl Deref for PooledDatabase {
+    type Target = Database;
+    fn deref(&self) -> &Database {
+        self.database.as_ref().unwrap()
     }
 }
-pub fn get_keyring_entry(key_type: &str) -> Result<Entry, keyring::Error> {
-    Entry::new("enchat", key_type)
-}
 
-pub fn get_key_keyring(key_type: &str) -> Option<String> {
-    match get_keyring_entry(key_type) {
-        Ok(entry) => entry.get_password().ok(),
-        Err(_) => None,
+impl Drop for PooledDatabase {
+    fn drop(&mut self) {
+        if let Some(database) = self.database.take() {
+            self.pool.idle.lock().unwrap().push(database);
+            self.pool.returned.notify_all();
+        }
     }
 }
 
-pub fn store_key_keyring(key_type: &str, key: &str) -> Result<(), keyring::Error> {
-    get_keyring_entry(key_type)
-        .and_then(move |entry| entry.set_password(key))
-}
-
-pub struct Database {
-    connection: Connection
+#[derive(Default)]
+pub enum ProfileDatabase {
+    #[default]
+    LoggedOut,
+    // Connections are closed until the user unlocks the app
+    Locked,
+    Open(Arc<DatabasePool>),
 }
 
-impl Database {
-    fn generate_database_key() -> KdfOutput {
-        let mut rng = get_rng();
-        let mut random_bytes = vec![0x0; 64];
-        rng.fill_bytes(&mut random_bytes);
-        kdf(random_bytes)
-    }
-    fn get_database_key() -> String {
-        if let Some(key) = get_key_keyring("database") {
-            key
-        } else {
-            let key = Self::generate_database_key().to_string();
-            store_key_keyring("database", &key).unwrap();
-            key
+impl ProfileDatabase {
+    // Only an open database is handed out, Locked and LoggedOut are left as they are.
+    // Commands still running finish first, the caller gets one connection and the others are closed
+    pub fn take(&mut self) -> Option<Database> {
+        if !matches!(self, ProfileDatabase::Open(_)) {
+            return None;
+        }
+        match std::mem::take(self) {
+            ProfileDatabase::Open(pool) => pool.close(),
+            _ => None,
         }
-    }
-    fn new() -> rusqlite::Result<Self> {
-        let path = database_path();
-        let key = Self::get_database_key();
-        let mut conn = Connection::open(path)?;
-        println!("{}", format!("PRAGMA key = \"x'{}'\"", &key));
-        conn.query_row(&format!("PRAGMA key = \"x'{}'\"", &key), params![], |_row| {
-            Ok(())
-        })?;
-        make_migrations(&mut conn);
-        Ok(Self {
-            connection: conn
-        })
-    }
-    pub fn get_connection(&self) -> &Connection {
-        &self.connection
     }
 }
 
-impl Default for Database {
-    fn default() -> Self {
-        Self::new().expect("Database not found!")
+// Database of the profile that's logged in
+pub struct DatabaseState(pub Mutex<ProfileDatabase>);
+
+// Resolved once at startup
+pub struct DataDirState(pub DataDir);
+
+// Every command goes through here, so none of them runs while the app is locked
+pub fn opened(database: &ProfileDatabase) -> CommandResult<PooledDatabase> {
+    match database {
+        ProfileDatabase::Open(pool) => Ok(pool.get()),
+        ProfileDatabase::Locked => Err(CommandError::Locked),
+        ProfileDatabase::LoggedOut => Err(CommandError::NoProfile),
     }
 }
 
-pub struct DatabaseState(pub Mutex<Database>);
-
+// For commands that work without a profile but mustn't get around the lock
+pub fn unlocked(database: &ProfileDatabase) -> CommandResult<()> {
+    match database {
+        ProfileDatabase::Locked => Err(CommandError::Locked),
+        _ => Ok(()),
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use std::{env, fs, sync::mpsc, thread, time::Duration};
+
+    use enchat_core::store::Database;
+
+    use super::{opened, DatabasePool, ProfileDatabase};
+
     #[test]
-    fn database_key_is_64_hex_string() {
-        let key = crate::store::Database::generate_database_key().to_string();
-        assert_eq!(key.len(), 64);
-        for x in key.chars() {
-            // should be able to parse into base 16 number if string is really hex
-            assert!(x.to_digit(16).is_some())
-        }
+    fn connections_should_be_used_side_by_side() {
+        let path = env::temp_dir().join(format!("enchat-pool-{}.db", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let key = "ab".repeat(32);
+        let first = Database::open(&path, &key).unwrap();
+        let second = Database::connect(&path, &key).unwrap();
+        let mut database = ProfileDatabase::Open(DatabasePool::new(vec![first, second]));
+        let busy = opened(&database).unwrap();
+        let other = opened(&database).unwrap();
+        other.get_connection().execute("INSERT INTO account_settings(user_id, padding) VALUES ('alice', 1)", []).unwrap();
+        drop(other);
+        // Closing waits for the connection still checked out
+        let (done, closed) = mpsc::channel();
+        thread::spawn(move || done.send(database.take().is_some()).unwrap());
+        assert!(closed.recv_timeout(Duration::from_millis(200)).is_err());
+        drop(busy);
+        assert!(closed.recv().unwrap());
+        fs::remove_file(path).unwrap();
     }
 }
-
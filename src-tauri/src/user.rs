@@ -1,20 +1,75 @@
 use std::sync::Mutex;
 
-use tauri::State;
+use enchat_core::{chat::ChatState, errors::CommandResult, lock, profile::Profile, store::Database, user::User};
+use tauri::{AppHandle, State};
 
-use crate::chat::WrappedChatState;
+use crate::{store::{DatabasePool, DataDirState, ProfileDatabase, unlocked}, worker::{blocking, States}};
 
-#[derive(Default)]
-pub struct User {
-    pub user_id: Option<String>
+pub struct UserState(pub Mutex<User>);
+
+// Sessions are dropped and the database is handed back to be closed once running commands are done, so no key stays in memory
+fn close(user: &mut User, database: &mut ProfileDatabase, chat: &mut Option<ChatState>) -> Option<Database> {
+    *chat = None;
+    *user = Default::default();
+    database.take()
 }
 
-pub struct UserState(pub Mutex<User>);
+// Closes the previous profile's database before opening the account's own.
+// A profile with a PIN starts out locked
+#[tauri::command]
+pub async fn switch_profile(user_id: String, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |States { data_dir, user_state, db_state, chat_state, lock_state, .. }| {
+        let mut user = user_state.0.lock().unwrap();
+        let mut database = db_state.0.lock().unwrap();
+        let mut chat = chat_state.0.lock().unwrap();
+        unlocked(&database)?;
+        drop(close(&mut user, &mut database, &mut chat));
+        let profile = Profile::new(&data_dir.0, &user_id);
+        let opened = profile.open()?;
+        let idle_timeout = lock::idle_timeout(opened.get_connection(), &profile.user())?;
+        lock_state.0.lock().unwrap().set_timeout(idle_timeout);
+        *database = match lock::has_pin(opened.get_connection(), &profile.user())? {
+            true => ProfileDatabase::Locked,
+            false => ProfileDatabase::Open(DatabasePool::open(opened, &profile)?),
+        };
+        *user = profile.user();
+        Ok(())
+    }).await
+}
+
+#[tauri::command]
+pub fn list_profiles(data_dir: State<DataDirState>) -> CommandResult<Vec<String>> {
+    Ok(Profile::list(&data_dir.0)?.into_iter().map(|profile| profile.user_id).collect())
+}
+
+#[tauri::command]
+pub async fn logout(app: AppHandle) -> CommandResult<()> {
+    blocking(app, |States { user_state, db_state, chat_state, lock_state, .. }| {
+        let mut user = user_state.0.lock().unwrap();
+        let mut database = db_state.0.lock().unwrap();
+        let mut chat = chat_state.0.lock().unwrap();
+        unlocked(&database)?;
+        drop(close(&mut user, &mut database, &mut chat));
+        lock_state.0.lock().unwrap().set_timeout(None);
+        Ok(())
+    }).await
+}
 
+// Deleting the account that's logged in logs it out first
 #[tauri::command]
-pub fn login(user_id: String, user_state: State<UserState>, chat_state: State<WrappedChatState>) {
-    let mut chat = chat_state.0.lock().unwrap();
-    let mut user = user_state.0.lock().unwrap();
-    *chat = Default::default();
-    (*user).user_id = Some(user_id);
-}
\ No newline at end of file
+pub async fn delete_profile(user_id: String, app: AppHandle) -> CommandResult<()> {
+    blocking(app, move |States { data_dir, user_state, db_state, chat_state, lock_state, .. }| {
+        let mut user = user_state.0.lock().unwrap();
+        let mut database = db_state.0.lock().unwrap();
+        let mut chat = chat_state.0.lock().unwrap();
+        unlocked(&database)?;
+        let open = match user.user_id.as_deref() == Some(user_id.as_str()) {
+            true => {
+                lock_state.0.lock().unwrap().set_timeout(None);
+                close(&mut user, &mut database, &mut chat)
+            }
+            false => None,
+        };
+        Profile::new(&data_dir.0, &user_id).delete(open)
+    }).await
+}
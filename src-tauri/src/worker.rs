@@ -0,0 +1,52 @@
+use std::sync::MutexGuard;
+
+use anyhow::anyhow;
+use enchat_core::{chat::ChatState, errors::CommandResult, user::User};
+use tauri::{AppHandle, Manager, State};
+
+use crate::{chat::WrappedChatState, lock::LockState, store::{opened, DatabaseState, DataDirState, PooledDatabase}, user::UserState};
+
+// Named like the command arguments they replace, so commands destructure only what they use
+pub struct States<'a> {
+    pub user_state: State<'a, UserState>,
+    pub db_state: State<'a, DatabaseState>,
+    pub chat_state: State<'a, WrappedChatState>,
+    pub lock_state: State<'a, LockState>,
+    pub data_dir: State<'a, DataDirState>,
+}
+
+impl<'a> States<'a> {
+    // Checks out a connection of the open profile with a copy of its user. Both locks are released again,
+    // so other commands get their own connection while this one runs
+    pub fn connect(&self) -> CommandResult<(User, PooledDatabase)> {
+        let user = self.user_state.0.lock().unwrap();
+        let database = self.db_state.0.lock().unwrap();
+        Ok((user.clone(), opened(&database)?))
+    }
+    // The chat is locked before the connection is checked out. Logout and locking wait for every
+    // connection while they hold the chat, so a command with a connection mustn't wait for it
+    pub fn connect_chat(&self) -> CommandResult<(User, PooledDatabase, MutexGuard<'a, Option<ChatState>>)> {
+        let user = self.user_state.0.lock().unwrap();
+        let database = self.db_state.0.lock().unwrap();
+        let chat = self.chat_state.inner().0.lock().unwrap();
+        Ok((user.clone(), opened(&database)?, chat))
+    }
+}
+
+// Commands that touch the database run on Tauri's blocking pool instead of the main thread.
+// Each takes a connection from the profile's pool, so SQLCipher or file crypto of one doesn't hold up the others
+pub async fn blocking<F, R>(app: AppHandle, work: F) -> CommandResult<R>
+where
+    F: FnOnce(States) -> CommandResult<R> + Send + 'static,
+    R: Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(move || work(States {
+        user_state: app.state(),
+        db_state: app.state(),
+        chat_state: app.state(),
+        lock_state: app.state(),
+        data_dir: app.state(),
+    }))
+    .await
+    .map_err(|error| anyhow!("Background task failed: {:?}", error))?
+}
@@ -86,6 +86,50 @@ export class Prekey extends Key {
     }
 }
 
+interface KemPrekeySerialized {
+    prekey: string;
+    signature: string;
+    id: number;
+}
+
+/**
+ * Post-quantum (ML-KEM) prekey, optional - users without it get plain X3DH sessions
+ */
+export class KemPrekey extends Key {
+    private _signature: Array<number> | null = null;
+    private _keyId: number = 0;
+    public get signature() { return this._signature }
+    public get id(): number {
+        return this._keyId
+    }
+    async shouldGenerate(userId: string): Promise<boolean> {
+        const {error} = await supabaseClient.from('kem-prekey').select('id').eq('id', userId).single()
+        return !!error
+    }
+    async generate(userId: string): Promise<string> {
+        console.debug('Generating new post-quantum prekey')
+        const {prekey, signature, id} = await invoke<KemPrekeySerialized>('request_kem_prekey')
+        await supabaseClient.from('kem-prekey').insert({
+            id: userId,
+            key: prekey,
+            signature,
+            local_id: id
+        })
+        this._signature = decode_base58(signature)
+        this._keyId = id
+        console.debug('Done generating post-quantum prekey')
+        return prekey
+    }
+    async fetch(userId: string, callerId: string): Promise<string | null> {
+        console.debug('Getting post-quantum prekey')
+        const {data, error} = await supabaseClient.from('kem-prekey').select('key, signature, local_id').eq('id', userId).single()
+        if (error || !data) return null
+        this._signature = decode_base58(data.signature)
+        this._keyId = data.local_id
+        return data.key
+    }
+}
+
 interface OnetimeKeys {
     id: number;
     key: string 
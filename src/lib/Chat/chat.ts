@@ -1,5 +1,5 @@
 import { invoke } from "@tauri-apps/api"
-import { IdentityKey, OnetimeKey, populateKey, Prekey } from "src/Keys"
+import { IdentityKey, KemPrekey, OnetimeKey, populateKey, Prekey } from "src/Keys"
 import { supabaseClient } from "src/supabase"
 import { showError } from "src/toasts";
 import { AttachementUpload, AttachmentStatus, type ChangeStatusFunction } from "./Attachment/attachements";
@@ -7,6 +7,7 @@ export interface MessageEntry {
     id: number;
     content: string,
     sender_id: string;
+    created_at?: string;
 }
 
 export interface DecryptedMessage {
@@ -14,6 +15,35 @@ export interface DecryptedMessage {
     id: number,
     received?: boolean,
     localId: number
+    // Sender's timestamp, adjusted for clock skew against the server time
+    sentAt?: number
+    messageId?: Array<number>
+}
+
+interface MessagePayload {
+    id: Array<number>;
+    timestamp: number;
+    content: string;
+    control: "EndSession" | null;
+}
+
+// Result of the receive command, duplicates were already processed and should be ignored
+type Received = { Message: MessagePayload } | "Duplicate" | "NewSession" | "ResetRequired"
+
+// How far behind the server time we still trust the sender's clock
+const MAX_CLOCK_SKEW = 5 * 60 * 1000
+
+// Message can't be sent after the server received it, and a clock too far behind is skewed as well
+export const adjustForSkew = (timestamp: number, serverTime?: string) => {
+    if (!serverTime) return timestamp
+    const received = Date.parse(serverTime)
+    if (isNaN(received)) return timestamp
+    return Math.min(Math.max(timestamp, received - MAX_CLOCK_SKEW), received)
+}
+
+export const compareMessages = (a: DecryptedMessage, b: DecryptedMessage) => {
+    if (a.sentAt !== undefined && b.sentAt !== undefined && a.sentAt != b.sentAt) return a.sentAt - b.sentAt
+    return a.id - b.id
 }
 
 const statusCache: Record<string, boolean> = {}
@@ -39,7 +69,12 @@ const withCache = async (chatId: string, callback: () => Promise<boolean>): Prom
 
 export const changeCache = (chatId: string, value: boolean) => statusCache[chatId] = value
 
-export const initialSender = async (chatId: string, userId: string) => {
+// Chats where the other party ended the session, we start a new one with the next message
+const endedSessions: Record<string, boolean> = {}
+
+export const isSessionEnded = (chatId: string) => !!endedSessions[chatId]
+
+const fetchReceiverBundle = async (chatId: string, userId: string) => {
     const {data, error} = await supabaseClient.from('chat-party')
         .select('user')
         .eq('chat', chatId)
@@ -49,26 +84,63 @@ export const initialSender = async (chatId: string, userId: string) => {
     const receiverIdentity = await populateKey(data.user, IdentityKey);
     const receiverPrekey = await populateKey(data.user, Prekey)
     const receiverOnetime = await populateKey(data.user, OnetimeKey)
+    const receiverKemPrekey = await populateKey(data.user, KemPrekey)
+    return {
+        receiver_prekey: receiverPrekey.key,
+        receiver_identity: receiverIdentity.key,
+        receiver_onetime: receiverOnetime.key,
+        receiver_onetime_id: receiverOnetime.id,
+        receiver_prekey_id: 1,
+        receiver_kem_prekey: receiverKemPrekey.key ? {
+            key: receiverKemPrekey.key,
+            signature: receiverKemPrekey.signature,
+            id: receiverKemPrekey.id
+        } : null
+    }
+}
+
+export const initialSender = async (chatId: string, userId: string) => {
     await invoke('enter_chat', {
         chatId,
-        receiverKeys: {
-            receiver_prekey: receiverPrekey.key,
-            receiver_identity: receiverIdentity.key,
-            receiver_onetime: receiverOnetime.key,
-            receiver_onetime_id: receiverOnetime.id,
-            receiver_prekey_id: 1
-        }
+        receiverKeys: await fetchReceiverBundle(chatId, userId)
     })
+    endedSessions[chatId] = false
     changeCache(chatId, false)
 }
 
-export const initialReceiver = async (chatId: string, userId: string) => {
+// Starts over when our session state is lost or out of sync, the other party is told to archive the old one
+export const resetSession = async (chatId: string, userId: string) => {
+    console.warn(`Resetting the session of chat ${chatId}`)
+    const endMessage = await invoke<Message | null>('reset_session', {
+        chatId,
+        receiverKeys: await fetchReceiverBundle(chatId, userId)
+    })
+    endedSessions[chatId] = false
+    if (!endMessage) return
+    const res = await supabaseClient.from('chat-message').insert({
+        chat_id: chatId,
+        sender_id: userId,
+        content: endMessage
+    }).select('id')
+    if (res.data?.[0]?.id) await supabaseClient.from('chat-message').update({ready: true}).eq('id', res.data[0].id)
+}
+
+// Both parties have to set the same passphrase before the session starts, empty removes it
+export const setChatPassphrase = async (chatId: string, passphrase: string) => {
+    await invoke('set_chat_passphrase', { chatId, passphrase: passphrase || null })
+}
+
+// Without a message, the session is entered from the first message of the chat
+export const initialReceiver = async (chatId: string, userId: string, message?: Message) => {
     console.debug('Initial receiver')
-    const firstMessage = await supabaseClient.from('chat-message').select('*').eq('chat_id', chatId).order('created_at', { ascending: true }).limit(1).single()
-    if (!firstMessage.data || !firstMessage.data.content) {
-        throw new Error("Messages could not be retrieved")
+    if (!message) {
+        const firstMessage = await supabaseClient.from('chat-message').select('*').eq('chat_id', chatId).order('created_at', { ascending: true }).limit(1).single()
+        if (!firstMessage.data || !firstMessage.data.content) {
+            throw new Error("Messages could not be retrieved")
+        }
+        console.log(firstMessage)
+        message = firstMessage.data.content
     }
-    console.log(firstMessage)
     const {data} = await supabaseClient.from('chat-party')
         .select('user')
         .eq('chat', chatId)
@@ -84,12 +156,12 @@ export const initialReceiver = async (chatId: string, userId: string) => {
     await invoke('enter_chat', {
         chatId,
         senderIdentity: senderIdentity.key,
-        receivedMessage: firstMessage.data.content
+        receivedMessage: message
     })
     changeCache(chatId, false)
 }
 
-export const decryptMessage = async (chatId: string, message: MessageEntry, userId: string): Promise<DecryptedMessage> => {
+export const decryptMessage = async (chatId: string, message: MessageEntry, userId: string): Promise<DecryptedMessage | null> => {
     try {
         console.log(message)
         if (await isInitialReceiver(chatId, userId)) {
@@ -97,23 +169,42 @@ export const decryptMessage = async (chatId: string, message: MessageEntry, user
         }
         const parsed = message.content
         const received = message.sender_id != userId
-        let decryptedBytes = await invoke<Array<number>>('try_decrypt', {
+        let payload = await invoke<MessagePayload>('try_decrypt', {
             chatId,
             received,
             message: parsed,
         })
-        if (!decryptedBytes && message.sender_id != userId)
-            decryptedBytes = await invoke<Array<number>>('receive', {
+        if (!payload && message.sender_id != userId) {
+            let result = await invoke<Received | null>('receive', {
                 chatId,
                 message: parsed,
             })
-        if (!decryptedBytes) throw new Error("The message could not be decrypted. Either the key is wrong or the message got corrupted.")
-        const text = new TextDecoder().decode(Uint8Array.from(decryptedBytes))
+            if (result === "NewSession") {
+                // The other party started over, their message carries the new session
+                await initialReceiver(chatId, userId, parsed)
+                result = await invoke<Received | null>('receive', {
+                    chatId,
+                    message: parsed,
+                })
+            }
+            if (result === "ResetRequired") {
+                await resetSession(chatId, userId)
+                throw new Error("The secure session was out of sync and has been reset. Messages sent before may not be readable.")
+            }
+            if (result === "Duplicate" || result === "NewSession") return null
+            payload = result?.Message
+            if (payload?.control === "EndSession") endedSessions[chatId] = true
+        }
+        if (!payload) throw new Error("The message could not be decrypted. Either the key is wrong or the message got corrupted.")
+        // Session management messages aren't shown
+        if (payload.control) return null
         return {
-            text,
+            text: payload.content,
             id: message.id,
             received,
-            localId: parsed.header.id
+            localId: parsed.header.id,
+            sentAt: adjustForSkew(payload.timestamp, message.created_at),
+            messageId: payload.id
         }
     } catch (err) {
         showError(err.message)
@@ -127,14 +218,15 @@ export const decryptMessage = async (chatId: string, message: MessageEntry, user
 }
 export async function* getMessages (chatId: string, userId: string, skip: number = 0, limit: number = 15) {
     const messages =  await supabaseClient.from('chat-message')
-        .select('sender_id, content, id')
+        .select('sender_id, content, id, created_at')
         .eq('chat_id', chatId)
         .eq('ready', true)
         .order("created_at", {ascending: false})
         .range(skip, skip + limit)
     console.log(messages)
     for (const message of messages.data ?? []) {
-        yield await decryptMessage(chatId, message, userId)
+        const decrypted = await decryptMessage(chatId, message, userId)
+        if (decrypted) yield decrypted
     }
 }
 
@@ -145,6 +237,9 @@ interface Message {
             ephemeral: Array<number>;
             onetime_key_id: number;
             prekey_id: number;
+            version?: number;
+            kem_ciphertext?: Array<number>;
+            kem_prekey_id?: number;
         };
         id: number;
         rachet_key: Array<number>;
@@ -135,6 +135,29 @@ export interface Database {
           user?: string
         }
       }
+      "kem-prekey": {
+        Row: {
+          created_at: string | null
+          id: string
+          key: string
+          local_id: number
+          signature: string
+        }
+        Insert: {
+          created_at?: string | null
+          id: string
+          key: string
+          local_id: number
+          signature: string
+        }
+        Update: {
+          created_at?: string | null
+          id?: string
+          key?: string
+          local_id?: number
+          signature?: string
+        }
+      }
       prekey: {
         Row: {
           created_at: string | null